@@ -5,41 +5,76 @@
 //! Bot functionality, command handling, etc.
 
 use matrix_sdk::{
+    deserialized_responses::SyncResponse,
     room::{Joined, Room},
+    ruma::api::client::r0::{
+        alias::get_alias, filter::RoomEventFilter, membership::unban_user,
+        message::get_message_events::Request as MessagesRequest, room::get_room_event,
+    },
     ruma::events::{
         room::{
             member::{MemberEventContent, MembershipState},
             message::{
                 InReplyTo, MessageEventContent, MessageType, Relation, TextMessageEventContent,
             },
+            power_levels::PowerLevelsEventContent,
+            server_acl::ServerAclEventContent,
         },
-        AnyMessageEventContent, StrippedStateEvent, SyncMessageEvent, SyncStateEvent,
+        AnyMessageEvent, AnyMessageEventContent, AnyRoomEvent, AnyStateEventContent, EventType,
+        StrippedStateEvent, SyncMessageEvent, SyncStateEvent,
     },
-    ruma::EventId,
-    Client,
+    ruma::{assign, EventId, Int, RoomAliasId, RoomId, RoomIdOrAliasId, UInt, UserId},
+    Client, LoopCtrl,
 };
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::TryFrom,
+    time::Duration,
+};
 use tracing::instrument;
 
 use tokio::time::sleep;
 #[allow(unused_imports)]
 use tracing::{debug, error, info, warn};
 
-use crate::config::Config;
+use crate::{
+    config::Config,
+    glob::Glob,
+    parser::{self, Arguments, ParseError, UserTarget},
+    policy::{self, AclUpdate, Action, Rule, RuleChange, RuleIndex},
+    protection_commands::{self, LockdownOutcome},
+    protections,
+    state::{
+        AccountData, DomainLists, LinkFilter, Lockdowns, Mutes, PolicyLists, ProtectedRooms,
+        Protections, RuleAclEntry, RuleBan, RuleEnforcements, RuleKey, WatchedRooms,
+    },
+    timers::{self, Timer, TimerKind, Timers},
+};
+
+/// Event types rules can be published as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RuleFormat {
+    /// The standard `m.policy.rule.*` event types, with the action given as a `recommendation`.
+    Standard,
+    /// The `sh.nao.list.*` event types used by earlier versions of clobber, with the action given
+    /// as an `action`.
+    Legacy,
+}
 
-/// Enum of available actions to apply to entity that matches rules.
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub enum Action {
-    /// Ban the entity from the room.
-    Ban,
+impl RuleFormat {
+    /// All formats, in order of preference.
+    pub const ALL: [Self; 2] = [Self::Standard, Self::Legacy];
 }
 
 /// Enum of available rule list event types.
-#[derive(Clone, Debug, Deserialize, Serialize)]
+///
+/// Rules are published as state events keyed by entity, and deserialize directly from them. Both
+/// the standard `m.policy.rule.*` event types and the legacy `sh.nao.list.*` event types are
+/// understood, and rules serialize as standard events.
+#[derive(Clone, Debug)]
 pub enum List {
     /// List of rules containing User IDs or globs.
-    #[serde(rename = "sh.nao.list.user")]
     User {
         /// The user(s) the rule applies to.
         entity: String,
@@ -49,7 +84,6 @@ pub enum List {
         reason: String,
     },
     /// List of rules containing server names or globs.
-    #[serde(rename = "sh.nao.list.server")]
     Server {
         /// The server(s) the rule applies to.
         entity: String,
@@ -58,6 +92,195 @@ pub enum List {
         /// User-supplied reason for creating the rule.
         reason: String,
     },
+    /// List of rules containing room IDs, aliases or globs.
+    Room {
+        /// The room(s) the rule applies to.
+        entity: String,
+        /// The action to take on successful match.
+        action: Action,
+        /// User-supplied reason for creating the rule.
+        reason: String,
+    },
+}
+
+impl List {
+    /// Kinds of rules, as shown to and entered by users.
+    pub const KINDS: [&'static str; 3] = ["user", "server", "room"];
+
+    /// Create a rule of the given kind, `user`, `server` or `room`.
+    pub fn new(kind: &str, entity: String, action: Action, reason: String) -> Option<Self> {
+        match kind {
+            "user" => Some(Self::User {
+                entity,
+                action,
+                reason,
+            }),
+            "server" => Some(Self::Server {
+                entity,
+                action,
+                reason,
+            }),
+            "room" => Some(Self::Room {
+                entity,
+                action,
+                reason,
+            }),
+            _ => None,
+        }
+    }
+
+    /// State event type rules of the given kind are published as in `format`.
+    #[must_use]
+    pub fn event_type_of(kind: &str, format: RuleFormat) -> Option<&'static str> {
+        Some(match (kind, format) {
+            ("user", RuleFormat::Standard) => "m.policy.rule.user",
+            ("server", RuleFormat::Standard) => "m.policy.rule.server",
+            ("room", RuleFormat::Standard) => "m.policy.rule.room",
+            ("user", RuleFormat::Legacy) => "sh.nao.list.user",
+            ("server", RuleFormat::Legacy) => "sh.nao.list.server",
+            ("room", RuleFormat::Legacy) => "sh.nao.list.room",
+            _ => return None,
+        })
+    }
+
+    /// Kind of the rules published as `event_type`, if it is a rule event type.
+    #[must_use]
+    pub fn kind_of(event_type: &str) -> Option<&'static str> {
+        match event_type {
+            // Lists published by older versions of Mjolnir still use the unstable names
+            "m.policy.rule.user" | "sh.nao.list.user" | "org.matrix.mjolnir.rule.user" => {
+                Some("user")
+            }
+            "m.policy.rule.server" | "sh.nao.list.server" | "org.matrix.mjolnir.rule.server" => {
+                Some("server")
+            }
+            "m.policy.rule.room" | "sh.nao.list.room" | "org.matrix.mjolnir.rule.room" => {
+                Some("room")
+            }
+            _ => None,
+        }
+    }
+
+    /// State event type the rule is published as in `format`.
+    #[must_use]
+    pub fn event_type(&self, format: RuleFormat) -> &'static str {
+        match (self, format) {
+            (Self::User { .. }, RuleFormat::Standard) => "m.policy.rule.user",
+            (Self::Server { .. }, RuleFormat::Standard) => "m.policy.rule.server",
+            (Self::Room { .. }, RuleFormat::Standard) => "m.policy.rule.room",
+            (Self::User { .. }, RuleFormat::Legacy) => "sh.nao.list.user",
+            (Self::Server { .. }, RuleFormat::Legacy) => "sh.nao.list.server",
+            (Self::Room { .. }, RuleFormat::Legacy) => "sh.nao.list.room",
+        }
+    }
+
+    /// Content of the state event the rule is published as in `format`.
+    #[must_use]
+    pub fn content(&self, format: RuleFormat) -> serde_json::Value {
+        match format {
+            RuleFormat::Standard => serde_json::json!({
+                "entity": self.entity(),
+                "recommendation": self.action().recommendation(),
+                "reason": self.reason(),
+            }),
+            RuleFormat::Legacy => serde_json::json!({
+                "entity": self.entity(),
+                "action": self.action(),
+                "reason": self.reason(),
+            }),
+        }
+    }
+
+    /// The action to take on successful match.
+    #[must_use]
+    pub const fn action(&self) -> &Action {
+        match self {
+            Self::User { action, .. } | Self::Server { action, .. } | Self::Room { action, .. } => {
+                action
+            }
+        }
+    }
+
+    /// The user(s), server(s) or room(s) the rule applies to.
+    #[must_use]
+    pub fn entity(&self) -> &str {
+        match self {
+            Self::User { entity, .. } | Self::Server { entity, .. } | Self::Room { entity, .. } => {
+                entity
+            }
+        }
+    }
+
+    /// User-supplied reason for creating the rule.
+    #[must_use]
+    pub fn reason(&self) -> &str {
+        match self {
+            Self::User { reason, .. } | Self::Server { reason, .. } | Self::Room { reason, .. } => {
+                reason
+            }
+        }
+    }
+
+    /// Kind of entity the rule applies to, as shown to users.
+    #[must_use]
+    pub const fn kind(&self) -> &'static str {
+        match self {
+            Self::User { .. } => "user",
+            Self::Server { .. } => "server",
+            Self::Room { .. } => "room",
+        }
+    }
+}
+
+impl Serialize for List {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serde_json::json!({
+            "type": self.event_type(RuleFormat::Standard),
+            "content": self.content(RuleFormat::Standard),
+        })
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for List {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        /// Parts of a rule state event needed to build a rule, in either format.
+        #[derive(Deserialize)]
+        struct RuleEvent {
+            #[serde(rename = "type")]
+            event_type: String,
+            content: RuleContent,
+        }
+
+        /// Content of a rule state event, in either format.
+        #[derive(Deserialize)]
+        struct RuleContent {
+            entity: String,
+            action: Option<Action>,
+            recommendation: Option<String>,
+            #[serde(default)]
+            reason: String,
+        }
+
+        let RuleEvent {
+            event_type,
+            content,
+        } = RuleEvent::deserialize(deserializer)?;
+        let kind = Self::kind_of(&event_type)
+            .ok_or_else(|| D::Error::custom(format!("unknown rule event type {}", event_type)))?;
+        let action = match (content.action, content.recommendation) {
+            (Some(action), _) => action,
+            (None, Some(recommendation)) => Action::from_recommendation(&recommendation)
+                .ok_or_else(|| {
+                    D::Error::custom(format!("unsupported recommendation {}", recommendation))
+                })?,
+            (None, None) => return Err(D::Error::missing_field("recommendation")),
+        };
+        Self::new(kind, content.entity, action, content.reason)
+            .ok_or_else(|| D::Error::custom(format!("unknown rule kind {}", kind)))
+    }
 }
 
 #[instrument]
@@ -68,36 +291,329 @@ pub async fn on_room_message(
     config: Config,
 ) {
     if let Room::Joined(room) = room {
+        if let Err(e) = check_protections(&event, &room, &client, &config).await {
+            error!("Failed to check protections in {}: {:?}", room.room_id(), e);
+        }
         // Match on m.text messages and get the message body
-        let msg_body = if let SyncMessageEvent {
+        let (msg_body, formatted) = if let SyncMessageEvent {
             content:
                 MessageEventContent {
-                    msgtype: MessageType::Text(TextMessageEventContent { body: msg_body, .. }),
+                    msgtype:
+                        MessageType::Text(TextMessageEventContent {
+                            body: msg_body,
+                            formatted,
+                            ..
+                        }),
                     ..
                 },
             ..
         } = &event
         {
             info!("Matching on received message");
-            msg_body
+            (msg_body, formatted)
         } else {
             info!("Not matching on received message");
             return;
         };
-        if msg_body
-            .trim_start()
-            .starts_with(&config.bot.command_prefix.to_string())
+        let reply_to = match &event.content.relates_to {
+            Some(Relation::Reply { in_reply_to }) => Some(&in_reply_to.event_id),
+            _ => None,
+        };
+        let (msg_body, formatted_body) = if reply_to.is_some() {
+            (
+                parser::strip_reply_fallback(msg_body),
+                formatted
+                    .as_ref()
+                    .map(|formatted| parser::strip_reply_fallback_html(&formatted.body)),
+            )
+        } else {
+            (
+                msg_body.as_str(),
+                formatted.as_ref().map(|formatted| formatted.body.as_str()),
+            )
+        };
+        // Commands are addressed to the bot either by prefix or by mention, replies included, so
+        // regular conversation is never mistaken for a command
+        let command_line = match parser::strip_prefix(msg_body, &config.bot.command_prefix)
+            .or_else(|| parser::strip_mention(msg_body, formatted_body, room.own_user_id()))
         {
-            let mut words: Vec<&str> = msg_body.split(' ').collect();
-            // Split prefix into separate word if 1 char long. don't judge ;-;
-            if config.bot.command_prefix.chars().count() == 1 {
-                words[0] = &words[0][1..];
-                words.insert(0, &config.bot.command_prefix);
+            Some(command_line) => command_line,
+            None => return,
+        };
+        let result = match parser::tokenize(command_line) {
+            Ok(tokens) => {
+                info!("Running command: {:?}", tokens);
+                handle_command(&event, &room, tokens, reply_to, &client, &config).await
             }
-            info!("Running command: {:?}", words);
-            handle_command(&event, &room, words, &config).await.unwrap();
+            Err(e) => {
+                let message = format!("Could not parse command: {}", e);
+                send_reply(
+                    &message,
+                    &escape_html(&message),
+                    &room,
+                    event.event_id.clone(),
+                )
+                .await
+            }
+        };
+        if let Err(e) = result {
+            error!("Failed to run command: {:?}", e);
+        }
+    }
+}
+
+/// Check a message sent in a protected room against the automatic protections of the room.
+async fn check_protections(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    if &event.sender == room.own_user_id()
+        || !ProtectedRooms::cached(client)
+            .await?
+            .rooms
+            .contains(room.room_id())
+    {
+        return Ok(());
+    }
+    let protections = Protections::cached(client).await?;
+    if let Some(limit) = protections.get(room.room_id(), |settings| &settings.flood) {
+        if let Some(count) = protections::record_message(room.room_id(), &event.sender, limit) {
+            let reason = format!(
+                "Flooding: {} messages within {}",
+                count,
+                format_duration(Duration::from_secs(limit.seconds))
+            );
+            return trigger_protection(
+                client,
+                config,
+                room,
+                &event.sender,
+                None,
+                &limit.action,
+                &reason,
+            )
+            .await;
+        }
+    }
+    if let (Some(limit), Some((body, formatted_body))) = (
+        protections.get(room.room_id(), |settings| &settings.mentions),
+        protections::message_text(&event.content),
+    ) {
+        if !protections.mention_allowed(room.room_id(), &event.sender) {
+            let members = room.joined_members_no_sync().await?;
+            let mentioned = protections::mentioned_users(
+                &event.sender,
+                body,
+                formatted_body,
+                members
+                    .iter()
+                    .map(|member| (member.user_id(), member.display_name())),
+            );
+            if mentioned.len() > limit.mentions {
+                let reason = format!("Mass mention: {} users in one message", mentioned.len());
+                return trigger_protection(
+                    client,
+                    config,
+                    room,
+                    &event.sender,
+                    None,
+                    &limit.action,
+                    &reason,
+                )
+                .await;
+            }
+        }
+    }
+    if let (Some(filter), Some((body, formatted_body))) = (
+        protections.get(room.room_id(), |settings| &settings.links),
+        protections::message_text(&event.content),
+    ) {
+        if let Some(reason) =
+            link_violation(client, room, &event.sender, filter, body, formatted_body).await?
+        {
+            return trigger_protection(
+                client,
+                config,
+                room,
+                &event.sender,
+                Some(&event.event_id),
+                &filter.action,
+                &reason,
+            )
+            .await;
+        }
+    }
+    // Only the plain text body is filtered, as patterns would also match the markup of the HTML
+    if let Some((body, _)) = protections::message_text(&event.content) {
+        let mut rules = protections.content_rules(room.room_id()).peekable();
+        if rules.peek().is_none() {
+            return Ok(());
+        }
+        let filter = protections::content_filter(room.room_id(), rules)?;
+        if let Some(rule) = filter.matching(body) {
+            info!(
+                "Message {} in {} matches {} {}",
+                event.event_id,
+                room.room_id(),
+                rule.kind,
+                rule.pattern
+            );
+            let reason = if rule.reason.is_empty() {
+                String::from("Message matches the content filter")
+            } else {
+                rule.reason.clone()
+            };
+            return trigger_protection(
+                client,
+                config,
+                room,
+                &event.sender,
+                Some(&event.event_id),
+                &rule.action,
+                &reason,
+            )
+            .await;
         }
     }
+    Ok(())
+}
+
+/// Why a message with the given bodies sent by `user` violates the link filter, if it does.
+async fn link_violation(
+    client: &Client,
+    room: &Joined,
+    user: &UserId,
+    filter: &LinkFilter,
+    body: &str,
+    formatted_body: Option<&str>,
+) -> Result<Option<String>, anyhow::Error> {
+    let hosts = protections::link_hosts(body, formatted_body);
+    if hosts.is_empty() {
+        return Ok(None);
+    }
+    let domains = protections::domain_filter(&DomainLists::cached(client).await?);
+    let denied = domains.denied(&hosts);
+    if !denied.is_empty() {
+        return Ok(Some(format!("Link to denied domain {}", denied.join(", "))));
+    }
+    let seconds = match filter.new_member_seconds {
+        Some(seconds) => seconds,
+        None => return Ok(None),
+    };
+    let allowed = domains.allowed(&hosts);
+    let unknown: Vec<&str> = hosts
+        .iter()
+        .map(String::as_str)
+        .filter(|host| !allowed.contains(host))
+        .collect();
+    if unknown.is_empty() || !is_new_member(room, user, Duration::from_secs(seconds)).await? {
+        return Ok(None);
+    }
+    Ok(Some(format!(
+        "Link from a new member to domain {}, which is not allowed",
+        unknown.join(", ")
+    )))
+}
+
+/// Whether `user` joined `room` less than `within` ago.
+///
+/// Joins the bot saw happen are remembered. Otherwise the user's membership event is used, but
+/// only if it is their join rather than a later change to their profile. Users whose join can't
+/// be found are not treated as new.
+async fn is_new_member(
+    room: &Joined,
+    user: &UserId,
+    within: Duration,
+) -> Result<bool, anyhow::Error> {
+    if let Some(joined) = protections::member_joined(room.room_id(), user) {
+        return Ok(joined.elapsed() < within);
+    }
+    let member = match room
+        .get_state_event(EventType::RoomMember, user.as_str())
+        .await?
+    {
+        Some(raw) => raw.deserialize_as::<SyncStateEvent<MemberEventContent>>()?,
+        None => return Ok(false),
+    };
+    let is_join = member.content.membership == MembershipState::Join
+        && member
+            .prev_content
+            .as_ref()
+            .is_none_or(|prev| prev.membership != MembershipState::Join);
+    if !is_join {
+        return Ok(false);
+    }
+    let since = timers::now().saturating_sub(u64::from(member.origin_server_ts.as_secs()));
+    Ok(since < within.as_secs())
+}
+
+/// Apply `action` to `user` in `room` on behalf of an automatic protection, and report it to the
+/// management room. The offending message is redacted first if `redact` is given. Moderators and
+/// users with a moderator's power level in the room are exempt from all protections.
+async fn trigger_protection(
+    client: &Client,
+    config: &Config,
+    room: &Joined,
+    user: &UserId,
+    redact: Option<&EventId>,
+    action: &Action,
+    reason: &str,
+) -> Result<(), anyhow::Error> {
+    if moderators(client, config).await?.contains(user)
+        || has_moderator_level(&power_levels(room).await?, user)
+    {
+        return Ok(());
+    }
+    info!(
+        "{} triggered a protection in {}: {}",
+        user,
+        room.room_id(),
+        reason
+    );
+    let redaction = match redact {
+        None => None,
+        Some(_) if config.bot.dry_run => Some(String::from("Would redact the message")),
+        Some(event_id) => Some(match room.redact(event_id, Some(reason), None).await {
+            Ok(_) => String::from("Redacted the message"),
+            Err(e) => {
+                warn!("Failed to redact {} in {}: {}", event_id, room.room_id(), e);
+                format!("Could not redact the message: {}", e)
+            }
+        }),
+    };
+    let results = if config.bot.dry_run {
+        vec![RoomResult {
+            room_id: room.room_id().clone(),
+            verb: action.conditional(),
+            succeeded: vec![user.clone()],
+            failed: Vec::new(),
+            error: None,
+        }]
+    } else {
+        apply_actions(
+            client,
+            config,
+            std::slice::from_ref(room),
+            std::slice::from_ref(user),
+            action,
+            Some(reason),
+        )
+        .await
+    };
+    let (mut plain, mut html) = format_results(&results);
+    if let Some(redaction) = redaction {
+        plain = format!("{}\n{}", redaction, plain);
+        html = format!("<p>{}</p>{}", escape_html(&redaction), html);
+    }
+    notify_management(
+        client,
+        config,
+        &format!("{}\n{}", reason, plain),
+        &format!("<p>{}</p>{}", escape_html(reason), html),
+    )
+    .await
 }
 
 #[instrument]
@@ -108,98 +624,4543 @@ pub async fn on_stripped_state_member(
     config: Config,
 ) {
     // If `m.member` event is an invite and the bot is the invitee
-    if event.content.membership == MembershipState::Invite
-        && event.state_key == client.user_id().await.unwrap()
+    if event.content.membership != MembershipState::Invite
+        || client.user_id().await.as_ref().map(UserId::as_str) != Some(event.state_key.as_str())
     {
-        accept_invite(&event, &room, &client, &config)
-            .await
-            .unwrap();
+        return;
+    }
+    if let Err(e) = accept_invite(&event, &room, &client, &config).await {
+        error!("Failed to handle invite to {}: {:?}", room.room_id(), e);
     }
 }
 
 #[instrument]
-pub async fn on_room_member(event: SyncStateEvent<MemberEventContent>, room: Room, client: Client) {
-    if let Room::Joined(_room) = room {
-        info!("Event handler firing");
+pub async fn on_room_member(
+    event: SyncStateEvent<MemberEventContent>,
+    room: Room,
+    client: Client,
+    config: Config,
+) {
+    if let Room::Joined(room) = room {
+        if let Err(e) = handle_member(&event, &room, &client, &config).await {
+            error!(
+                "Failed to handle membership change in {}: {:?}",
+                room.room_id(),
+                e
+            );
+        }
     };
 }
 
-/// Handles incoming commands and dispatches relevant functions.
-async fn handle_command(
-    event: &SyncMessageEvent<MessageEventContent>,
+/// Check users joining or being invited to rooms the bot looks after against policy list rules,
+/// acting on matches in protected rooms and reporting them in watched rooms. Bans that are lifted
+/// are no longer tracked as applied because of a rule.
+async fn handle_member(
+    event: &SyncStateEvent<MemberEventContent>,
     room: &Joined,
-    commands: Vec<&str>,
+    client: &Client,
     config: &Config,
 ) -> Result<(), anyhow::Error> {
-    if commands.len() < 2 {
-        command_help(event, room).await?;
+    let membership = &event.content.membership;
+    let previous = event.prev_content.as_ref().map(|prev| &prev.membership);
+    if previous == Some(&MembershipState::Ban) && membership != &MembershipState::Ban {
+        // However the ban was lifted, there is nothing left to lift when its rule is revoked
+        let room_id = room.room_id();
+        let user = UserId::try_from(event.state_key.as_str())?;
+        RuleEnforcements::update(client, |enforcements| {
+            enforcements.forget_bans(room_id, &[user])
+        })
+        .await?;
+    }
+    if !matches!(membership, MembershipState::Join | MembershipState::Invite)
+        || previous == Some(membership)
+    {
+        // Profile changes of existing members are not interesting
         return Ok(());
     }
-    let base_command = commands[1];
-    let _arguments = &commands[2..];
-    match base_command {
-        "help" => command_help(event, room).await?,
-        _ => command_unknown(event, room, config).await?,
+    let user = UserId::try_from(event.state_key.as_str())?;
+    if ProtectedRooms::cached(client)
+        .await?
+        .rooms
+        .contains(room.room_id())
+    {
+        if membership == &MembershipState::Join {
+            // Joins are remembered for as long as the link filter treats members as new
+            if let Some(seconds) = Protections::cached(client)
+                .await?
+                .get(room.room_id(), |settings| &settings.links)
+                .and_then(|filter| filter.new_member_seconds)
+            {
+                protections::record_member_join(
+                    room.room_id(),
+                    &user,
+                    Duration::from_secs(seconds),
+                );
+            }
+        }
+        let rules = policy::rule_index(client).await?;
+        // Most users match no rules, so the moderators are only looked up for those who do
+        if !rules.matching(&user).is_empty() {
+            let moderators = moderators(client, config).await?;
+            let results = apply_rules(client, config, room, &[user], &rules, &moderators).await?;
+            if !results.is_empty() {
+                let (plain, html) = format_results(&results);
+                notify_management(client, config, &plain, &html).await?;
+            }
+        }
+        if membership == &MembershipState::Join {
+            check_join_flood(client, config, room).await?;
+        }
+    } else if WatchedRooms::cached(client)
+        .await?
+        .rooms
+        .contains(room.room_id())
+    {
+        let rules = policy::rule_index(client).await?;
+        report_matches(client, config, room, &[user], &rules).await?;
     }
     Ok(())
 }
 
-/// Fallback when an unrecognized command is invoked.
-async fn command_unknown(
-    event: &SyncMessageEvent<MessageEventContent>,
-    room: &Joined,
+/// Lock down a protected room if more users joined it in a short time than its join limit allows.
+async fn check_join_flood(
+    client: &Client,
     config: &Config,
+    room: &Joined,
 ) -> Result<(), anyhow::Error> {
-    send_reply(
-        &format!("Unrecognized command, please try again or see {} help for available commands.", &config.bot.command_prefix),
-        &format!("Unrecognized command, please try again or see <code>{} help</code> for available commands.", &config.bot.command_prefix),
-        room,
-        event.event_id.clone(),
-    ).await?;
-    Ok(())
+    let protections = Protections::cached(client).await?;
+    let limit = match protections.get(room.room_id(), |settings| &settings.joins) {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+    let count = match protections::record_join(room.room_id(), limit) {
+        Some(count) => count,
+        None => return Ok(()),
+    };
+    if Lockdowns::load(client)
+        .await?
+        .rooms
+        .contains_key(room.room_id())
+    {
+        return Ok(());
+    }
+    let reason = format!(
+        "{} users joined within {}",
+        count,
+        format_duration(Duration::from_secs(limit.seconds))
+    );
+    info!("Join flood in {}: {}", room.room_id(), reason);
+    let name = room.display_name().await?;
+    let (plain, html) = if config.bot.dry_run {
+        (
+            format!("Would lock down {} ({}), {}", room.room_id(), name, reason),
+            format!(
+                "Would lock down <code>{}</code> ({}), {}",
+                room.room_id(),
+                escape_html(&name),
+                reason
+            ),
+        )
+    } else {
+        let duration = limit.lockdown.cooldown.map(Duration::from_secs);
+        match protection_commands::lock_down(client, room, limit.lockdown, duration).await {
+            // Another join handler got there first
+            Ok(LockdownOutcome::AlreadyLocked) => return Ok(()),
+            Ok(LockdownOutcome::Unchanged) => {
+                let message = format!(
+                    "Join flood in {} ({}), {}, but it already is as restricted as a lockdown would make it",
+                    room.room_id(),
+                    name,
+                    reason
+                );
+                (message.clone(), escape_html(&message))
+            }
+            Ok(LockdownOutcome::Locked) => (
+                format!(
+                    "Locked down {} ({}) {}, {}. Lift it with lockdown off.",
+                    room.room_id(),
+                    name,
+                    protection_commands::describe_lockdown_duration(duration),
+                    reason
+                ),
+                format!(
+                    "Locked down <code>{}</code> ({}) {}, {}. Lift it with <code>lockdown off</code>.",
+                    room.room_id(),
+                    escape_html(&name),
+                    protection_commands::describe_lockdown_duration(duration),
+                    reason
+                ),
+            ),
+            Err(e) => {
+                warn!("Failed to lock down {}: {}", room.room_id(), e);
+                let message = format!(
+                    "Could not lock down {} ({}) after {}: {}",
+                    room.room_id(),
+                    name,
+                    reason,
+                    e
+                );
+                (message.clone(), escape_html(&message))
+            }
+        }
+    };
+    notify_management(client, config, &plain, &html).await
 }
 
-/// Send help information.
-#[instrument]
-async fn command_help(
-    event: &SyncMessageEvent<MessageEventContent>,
+/// Report users in a watched room that match policy list rules to the management room.
+async fn report_matches(
+    client: &Client,
+    config: &Config,
     room: &Joined,
+    users: &[UserId],
+    rules: &RuleIndex,
 ) -> Result<(), anyhow::Error> {
-    let message = "There's nothing here yet";
-    send_reply(message, message, room, event.event_id.clone()).await?;
-    Ok(())
+    let mut plain = String::new();
+    let mut html = String::from("<ul>");
+    for user in users {
+        for rule in rules.matching(user) {
+            info!(
+                "{} in watched room {} matches {} rule {}",
+                user,
+                room.room_id(),
+                rule.rule.kind(),
+                rule.rule.entity()
+            );
+            plain.push_str(&format!(
+                "\n{} matches {} rule {} from {}: {}",
+                user,
+                rule.rule.kind(),
+                rule.rule.entity(),
+                rule.list,
+                rule.rule.reason()
+            ));
+            html.push_str(&format!(
+                "<li>{} matches {} rule <code>{}</code> from <code>{}</code>: {}</li>",
+                escape_html(user.as_str()),
+                rule.rule.kind(),
+                escape_html(rule.rule.entity()),
+                rule.list,
+                escape_html(rule.rule.reason())
+            ));
+        }
+    }
+    if plain.is_empty() {
+        return Ok(());
+    }
+    html.push_str("</ul>");
+    let name = room.display_name().await?;
+    notify_management(
+        client,
+        config,
+        &format!("Watched room {} ({}):{}", room.room_id(), name, plain),
+        &format!(
+            "<p>Watched room <code>{}</code> ({}):</p>{}",
+            room.room_id(),
+            escape_html(&name),
+            html
+        ),
+    )
+    .await
 }
 
-/// Send `m.notice` reply to user.
-async fn send_reply(
-    plain: &str,
-    html: &str,
-    room: &Joined,
-    event_id: EventId,
+/// Handles each sync response, applying changes to the rules of followed policy lists.
+///
+/// Rule changes are handled per sync rather than per event, so that the whole state of a newly
+/// joined policy list is applied in one go.
+pub async fn on_sync(response: SyncResponse, client: Client, config: Config) -> LoopCtrl {
+    if let Err(e) = handle_rule_changes(&response, &client, &config).await {
+        error!("Failed to handle policy list changes: {:?}", e);
+    }
+    LoopCtrl::Continue
+}
+
+/// Apply rules added to followed policy lists in a sync response, and report all changes to the
+/// management room.
+///
+/// This includes the lists published by the bot, so rules added with `list add` are applied the
+/// same way as rules from subscribed lists.
+async fn handle_rule_changes(
+    response: &SyncResponse,
+    client: &Client,
+    config: &Config,
 ) -> Result<(), anyhow::Error> {
-    let mut notice = MessageEventContent::notice_html(plain, html);
-    notice.relates_to = Some(Relation::Reply {
-        in_reply_to: InReplyTo::new(event_id),
+    let mut lists = PolicyLists::cached(client).await?;
+    let mut refreshed = false;
+    // Only the last change to each rule in the response counts
+    let mut changes = BTreeMap::new();
+    for (room_id, joined) in &response.rooms.join {
+        let state = joined.state.events.iter().map(|raw| raw.json());
+        let timeline = joined
+            .timeline
+            .events
+            .iter()
+            .map(|event| event.event.json());
+        let room_changes: Vec<RuleChange> = state
+            .chain(timeline)
+            .filter_map(|event| policy::rule_change(room_id, event))
+            .collect();
+        if room_changes.is_empty() {
+            continue;
+        }
+        // The state of a list subscribed to just now can arrive before the subscription shows up
+        // in the cached state, so check the stored state before ignoring its rules
+        if !lists.rooms().any(|list| list == room_id) && !refreshed {
+            lists = PolicyLists::load(client).await?;
+            refreshed = true;
+        }
+        if !lists.rooms().any(|list| list == room_id) {
+            continue;
+        }
+        for change in room_changes {
+            changes.insert(change.key(), change);
+        }
+    }
+    if changes.is_empty() {
+        return Ok(());
+    }
+    policy::update_index(|index| {
+        for change in changes.values() {
+            index.apply(change);
+        }
     });
-    let content = AnyMessageEventContent::RoomMessage(notice);
-    room.send(content, None).await?;
-    Ok(())
+
+    let mut added = Vec::new();
+    let mut revoked = Vec::new();
+    let mut plain = String::from("Policy list changes:");
+    let mut html = String::from("<p>Policy list changes:</p><ul>");
+    for (key, change) in changes {
+        let (verb, kind, entity) = match &change {
+            RuleChange::Added(rule) => ("added", rule.rule.kind(), rule.rule.entity()),
+            RuleChange::Revoked { kind, entity, .. } => ("revoked", *kind, entity.as_str()),
+        };
+        let list = &key.list;
+        info!("Rule for {} {} {} in {}", kind, entity, verb, list);
+        plain.push_str(&format!("\n{} rule {} {} in {}", kind, entity, verb, list));
+        html.push_str(&format!(
+            "<li>{} rule <code>{}</code> {} in <code>{}</code></li>",
+            kind,
+            escape_html(entity),
+            verb,
+            list
+        ));
+        match change {
+            RuleChange::Added(rule) => added.push(rule),
+            RuleChange::Revoked { .. } => revoked.push(key),
+        }
+    }
+    html.push_str("</ul>");
+    let rooms = protected_rooms(client).await?;
+    let acl_results = apply_server_acls(client, config, &rooms, &added).await?;
+    let results = enforce_rules(client, config, &rooms, &RuleIndex::new(added)).await?;
+    if !results.is_empty() {
+        let (results_plain, results_html) = format_results(&results);
+        plain.push_str(&format!("\n{}", results_plain));
+        html.push_str(&results_html);
+    }
+    if !acl_results.is_empty() {
+        let (acl_plain, acl_html) = format_acl_results(&acl_results);
+        plain.push_str(&format!("\n{}", acl_plain));
+        html.push_str(&acl_html);
+    }
+    if !revoked.is_empty() {
+        let (lifted_plain, lifted_html) = lift_revoked(client, config, &revoked).await?;
+        plain.push_str(&lifted_plain);
+        html.push_str(&lifted_html);
+    }
+    notify_management(client, config, &plain, &html).await
 }
 
-// Inspired by the AutoJoin example in matrix-rust-sdk
-/// Handles incoming invites.
-async fn accept_invite(
-    event: &StrippedStateEvent<MemberEventContent>,
-    room: &Room,
+/// Lift the bans and server ACL entries applied because of the `revoked` rules, if configured
+/// to, returning a summary to add to the report of the changes.
+///
+/// Anything another rule still calls for is kept and tracked under that rule instead, and bans
+/// are only lifted while the ban in place is still the one the bot applied.
+async fn lift_revoked(
     client: &Client,
     config: &Config,
-) -> Result<(), anyhow::Error> {
-    if let Room::Invited(room) = room {
-        if !config
-            .bot
-            .allow_invites
+    revoked: &[RuleKey],
+) -> Result<(String, String), anyhow::Error> {
+    let index = policy::rule_index(client).await?;
+    let own_user = client
+        .user_id()
+        .await
+        .ok_or_else(|| anyhow::anyhow!("Client is not logged in"))?;
+    let lock = RuleEnforcements::lock().await;
+    let mut enforcements = RuleEnforcements::load(client).await?;
+    let (bans, denied_servers) = enforcements.take(revoked);
+    if bans.is_empty() && denied_servers.is_empty() {
+        return Ok((String::new(), String::new()));
+    }
+
+    let mut kept_bans = 0;
+    let mut unbans: BTreeMap<RoomId, Vec<UserId>> = BTreeMap::new();
+    for ban in bans {
+        if let Some(rule) = index
+            .matching(&ban.user)
+            .into_iter()
+            .find(|rule| rule.rule.action().bans())
+        {
+            enforcements.bans.push(RuleBan {
+                rule: rule.key(),
+                ..ban
+            });
+        } else if config.bot.unban_on_revoke {
+            unbans.entry(ban.room).or_default().push(ban.user);
+        } else {
+            kept_bans += 1;
+        }
+    }
+
+    let deny = policy::server_acl_deny(index.rules(), own_user.server_name().as_str());
+    let mut kept_servers = 0;
+    let mut lifts: BTreeMap<RoomId, BTreeSet<String>> = BTreeMap::new();
+    for entry in denied_servers {
+        if let Some(rule) = deny.get(&entry.server) {
+            enforcements.denied_servers.push(RuleAclEntry {
+                rule: rule.key(),
+                ..entry
+            });
+        } else if config.bot.relax_acl_on_revoke {
+            lifts.entry(entry.room).or_default().insert(entry.server);
+        } else {
+            kept_servers += 1;
+        }
+    }
+    if !config.bot.dry_run {
+        enforcements.save(client).await?;
+    }
+    drop(lock);
+
+    let mut results = Vec::new();
+    for (room_id, users) in unbans {
+        let room = match client.get_joined_room(&room_id) {
+            Some(room) => room,
+            None => continue,
+        };
+        let mut still_banned = Vec::new();
+        for user in users {
+            // A moderator may have unbanned the user, or banned them again by hand since
+            let ban = room
+                .get_state_event(EventType::RoomMember, user.as_str())
+                .await?
+                .and_then(|raw| {
+                    raw.deserialize_as::<SyncStateEvent<MemberEventContent>>()
+                        .ok()
+                });
+            let banned_by_bot = ban.is_some_and(|ban| {
+                ban.content.membership == MembershipState::Ban && ban.sender == own_user
+            });
+            if banned_by_bot {
+                still_banned.push(user);
+            }
+        }
+        if still_banned.is_empty() {
+            continue;
+        }
+        if config.bot.dry_run {
+            results.push(RoomResult {
+                room_id,
+                verb: "Would unban",
+                succeeded: still_banned,
+                failed: Vec::new(),
+                error: None,
+            });
+        } else {
+            results.extend(unban_users(client, std::slice::from_ref(&room), &still_banned).await);
+        }
+    }
+    let mut acl_results = Vec::new();
+    for (room_id, lift) in lifts {
+        let room = match client.get_joined_room(&room_id) {
+            Some(room) => room,
+            None => continue,
+        };
+        if let Some(update) = server_acl(&room)
+            .await?
+            .and_then(|current| policy::relaxed_server_acl(current, &lift))
+        {
+            acl_results.push(if config.bot.dry_run {
+                AclResult::dry_run(&room, update)
+            } else {
+                send_server_acl(&room, update).await
+            });
+        }
+    }
+
+    let (mut plain, mut html) = (String::new(), String::new());
+    if !results.is_empty() {
+        let (results_plain, results_html) = format_results(&results);
+        plain.push_str(&format!("\n{}", results_plain));
+        html.push_str(&results_html);
+    }
+    if !acl_results.is_empty() {
+        let (acl_plain, acl_html) = format_acl_results(&acl_results);
+        plain.push_str(&format!("\n{}", acl_plain));
+        html.push_str(&acl_html);
+    }
+    if kept_bans > 0 || kept_servers > 0 {
+        let kept = format!(
+            "Kept {} ban(s) and {} server ACL entries applied because of revoked rules",
+            kept_bans, kept_servers
+        );
+        plain.push_str(&format!("\n{}", kept));
+        html.push_str(&format!("<p>{}</p>", escape_html(&kept)));
+    }
+    Ok((plain, html))
+}
+
+/// Apply the actions of `rules` to matching members of each of `rooms`.
+///
+/// The bot itself and members of the management room are never acted on, so a policy list can't
+/// take out the moderators.
+async fn enforce_rules(
+    client: &Client,
+    config: &Config,
+    rooms: &[Joined],
+    rules: &RuleIndex,
+) -> Result<Vec<RoomResult>, anyhow::Error> {
+    if rules.is_empty() {
+        return Ok(Vec::new());
+    }
+    let moderators = moderators(client, config).await?;
+    let mut results = Vec::new();
+    for room in rooms {
+        let members: Vec<UserId> = room
+            .active_members()
+            .await?
             .iter()
-            .any(|user| user == &event.sender)
+            .map(|member| member.user_id().clone())
+            .collect();
+        results.extend(apply_rules(client, config, room, &members, rules, &moderators).await?);
+    }
+    Ok(results)
+}
+
+/// Members of the management room, who are exempt from policy list rules.
+async fn moderators(client: &Client, config: &Config) -> Result<BTreeSet<UserId>, anyhow::Error> {
+    Ok(match client.get_joined_room(&config.bot.management_room) {
+        Some(management) => management
+            .active_members()
+            .await?
+            .iter()
+            .map(|member| member.user_id().clone())
+            .collect(),
+        None => BTreeSet::new(),
+    })
+}
+
+/// Apply the actions of `rules` to each of `users` in `room` that matches them, other than the
+/// bot itself, `moderators` and users with a moderator's power level in the room. Only rooms where
+/// something was done are included in the results.
+///
+/// In dry run mode, what would be done is reported without doing it.
+///
+/// Bans are tracked along with the rule that caused them, so they can be lifted again when the
+/// rule is revoked.
+async fn apply_rules(
+    client: &Client,
+    config: &Config,
+    room: &Joined,
+    users: &[UserId],
+    rules: &RuleIndex,
+    moderators: &BTreeSet<UserId>,
+) -> Result<Vec<RoomResult>, anyhow::Error> {
+    // Users are acted on according to the most severe rule they match
+    let mut actions: BTreeMap<RuleKey, (&Rule, Vec<UserId>)> = BTreeMap::new();
+    for user in users {
+        if user == room.own_user_id() || moderators.contains(user) {
+            continue;
+        }
+        if let Some(rule) = rules.most_severe(user) {
+            info!(
+                "{} in {} matches {} rule {} from {}",
+                user,
+                room.room_id(),
+                rule.rule.kind(),
+                rule.rule.entity(),
+                rule.list
+            );
+            actions
+                .entry(rule.key())
+                .or_insert_with(|| (rule, Vec::new()))
+                .1
+                .push(user.clone());
+        }
+    }
+    if !actions.is_empty() {
+        let power_levels = power_levels(room).await?;
+        for (_, users) in actions.values_mut() {
+            users.retain(|user| !has_moderator_level(&power_levels, user));
+        }
+        actions.retain(|_, (_, users)| !users.is_empty());
+    }
+    let mut results = Vec::new();
+    let mut bans = Vec::new();
+    for (key, (rule, users)) in actions {
+        let reason = Some(rule.rule.reason()).filter(|reason| !reason.is_empty());
+        let action = rule.rule.action();
+        if config.bot.dry_run {
+            results.push(RoomResult {
+                room_id: room.room_id().clone(),
+                verb: action.conditional(),
+                succeeded: users,
+                failed: Vec::new(),
+                error: None,
+            });
+            continue;
+        }
+        let rule_results = apply_actions(
+            client,
+            config,
+            std::slice::from_ref(room),
+            &users,
+            action,
+            reason,
+        )
+        .await;
+        if action.bans() {
+            for user in rule_results.iter().flat_map(|result| &result.succeeded) {
+                bans.push(RuleBan {
+                    rule: key.clone(),
+                    room: room.room_id().clone(),
+                    user: user.clone(),
+                });
+            }
+        }
+        results.extend(rule_results);
+    }
+    if !bans.is_empty() {
+        // Bans applied because of a rule last until it is revoked, not until an earlier
+        // temporary ban expires
+        Timers::update(|timers| {
+            for ban in &bans {
+                timers.remove(TimerKind::Unban, &ban.room, &ban.user);
+            }
+        })?;
+        RuleEnforcements::update(client, |enforcements| {
+            enforcements.add_bans(bans);
+            true
+        })
+        .await?;
+    }
+    Ok(results)
+}
+
+/// Protected rooms the bot is joined to.
+async fn protected_rooms(client: &Client) -> Result<Vec<Joined>, anyhow::Error> {
+    let mut rooms = Vec::new();
+    for room_id in ProtectedRooms::cached(client).await?.rooms {
+        match client.get_joined_room(&room_id) {
+            Some(room) => rooms.push(room),
+            None => warn!("Not joined to protected room {}", room_id),
+        }
+    }
+    Ok(rooms)
+}
+
+/// Deny the servers banned by `rules` in the server ACL of each of `rooms`, sending an update
+/// only to rooms whose ACL is missing some of them.
+///
+/// Denied entries are tracked along with the rule that caused them, so they can be lifted again
+/// when the rule is revoked. In dry run mode, the updates are reported without being sent.
+async fn apply_server_acls<'a>(
+    client: &Client,
+    config: &Config,
+    rooms: &[Joined],
+    rules: impl IntoIterator<Item = &'a Rule>,
+) -> Result<Vec<AclResult>, anyhow::Error> {
+    let own_user = client
+        .user_id()
+        .await
+        .ok_or_else(|| anyhow::anyhow!("Client is not logged in"))?;
+    let deny = policy::server_acl_deny(rules, own_user.server_name().as_str());
+    if deny.is_empty() {
+        return Ok(Vec::new());
+    }
+    let entries: BTreeSet<String> = deny.keys().cloned().collect();
+    let mut results = Vec::new();
+    let mut denied_servers = Vec::new();
+    for room in rooms {
+        let update = match policy::server_acl(server_acl(room).await?, &entries) {
+            Some(update) => update,
+            None => continue,
+        };
+        if config.bot.dry_run {
+            results.push(AclResult::dry_run(room, update));
+            continue;
+        }
+        let result = send_server_acl(room, update).await;
+        for server in &result.denied {
+            denied_servers.push(RuleAclEntry {
+                rule: deny[server].key(),
+                room: room.room_id().clone(),
+                server: server.clone(),
+            });
+        }
+        results.push(result);
+    }
+    if !denied_servers.is_empty() {
+        RuleEnforcements::update(client, |enforcements| {
+            enforcements.add_denied_servers(denied_servers);
+            true
+        })
+        .await?;
+    }
+    Ok(results)
+}
+
+/// Current server ACL of the room, if it has one.
+async fn server_acl(room: &Joined) -> Result<Option<ServerAclEventContent>, anyhow::Error> {
+    Ok(
+        match room.get_state_event(EventType::RoomServerAcl, "").await? {
+            Some(raw) => Some(
+                raw.deserialize_as::<SyncStateEvent<ServerAclEventContent>>()?
+                    .content,
+            ),
+            None => None,
+        },
+    )
+}
+
+/// Send an updated server ACL to the room.
+async fn send_server_acl(room: &Joined, update: AclUpdate) -> AclResult {
+    let room_id = room.room_id().clone();
+    match room
+        .send_state_event(AnyStateEventContent::RoomServerAcl(update.content), "")
+        .await
+    {
+        Ok(_) => {
+            info!(
+                "Updated server ACL in {}, denied: [{}], lifted: [{}]",
+                room_id,
+                update.denied.join(", "),
+                update.lifted.join(", ")
+            );
+            AclResult {
+                room_id,
+                denied: update.denied,
+                lifted: update.lifted,
+                error: None,
+                dry_run: false,
+            }
+        }
+        Err(e) => {
+            warn!("Failed to update server ACL in {}: {}", room_id, e);
+            AclResult {
+                room_id,
+                denied: Vec::new(),
+                lifted: Vec::new(),
+                error: Some(e.to_string()),
+                dry_run: false,
+            }
+        }
+    }
+}
+
+/// How often expired temporary bans and mutes are checked for.
+const TIMER_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Lift temporary bans, mutes and lockdowns as they expire, for as long as the bot runs.
+pub async fn run_timers(client: Client, config: Config) {
+    loop {
+        sleep(TIMER_INTERVAL).await;
+        if let Err(e) = lift_expired(&client, &config).await {
+            error!("Failed to lift expired bans and mutes: {:?}", e);
+        }
+        if let Err(e) = lift_expired_lockdowns(&client, &config).await {
+            error!("Failed to lift expired lockdowns: {:?}", e);
+        }
+    }
+}
+
+/// How long to wait before trying again to lift a temporary ban or mute that could not be lifted.
+const RETRY_DELAY: Duration = Duration::from_secs(10 * 60);
+
+/// Lift the temporary bans and mutes that have expired, reporting them to the management room.
+///
+/// Timers are only removed once they have been lifted, those that could not be are tried again
+/// after [`RETRY_DELAY`].
+async fn lift_expired(client: &Client, config: &Config) -> Result<(), anyhow::Error> {
+    let now = timers::now();
+    let expired: Vec<Timer> = Timers::pending()?
+        .into_iter()
+        .take_while(|timer| timer.expires <= now)
+        .collect();
+    let mut failed_reports = Vec::new();
+    for timer in expired {
+        let noun = match timer.kind {
+            TimerKind::Unban => "ban",
+            TimerKind::Unmute => "mute",
+        };
+        let outcome = match client.get_joined_room(&timer.room) {
+            Some(room) => match timer.kind {
+                TimerKind::Unban => lift_ban(client, &room, &timer.user).await,
+                TimerKind::Unmute => unmute_user(client, &room, &timer.user).await.map(|_| ()),
+            },
+            None => Err(anyhow::anyhow!("not joined to the room")),
+        };
+        let (plain, html) = match outcome {
+            Ok(()) => {
+                info!(
+                    "Lifted expired {} of {} in {}",
+                    noun, timer.user, timer.room
+                );
+                Timers::update(|timers| timers.finish(&timer))?;
+                (
+                    format!(
+                        "Lifted expired {} of {} in {}",
+                        noun, timer.user, timer.room
+                    ),
+                    format!(
+                        "Lifted expired {} of {} in <code>{}</code>",
+                        noun,
+                        escape_html(timer.user.as_str()),
+                        timer.room
+                    ),
+                )
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to lift expired {} of {} in {}: {}",
+                    noun, timer.user, timer.room, e
+                );
+                Timers::update(|timers| {
+                    timers.postpone(&timer, timers::now().saturating_add(RETRY_DELAY.as_secs()))
+                })?;
+                (
+                    format!(
+                        "Could not lift expired {} of {} in {}, trying again in {}: {}",
+                        noun,
+                        timer.user,
+                        timer.room,
+                        format_duration(RETRY_DELAY),
+                        e
+                    ),
+                    format!(
+                        "Could not lift expired {} of {} in <code>{}</code>, trying again in {}: {}",
+                        noun,
+                        escape_html(timer.user.as_str()),
+                        timer.room,
+                        format_duration(RETRY_DELAY),
+                        escape_html(&e.to_string())
+                    ),
+                )
+            }
+        };
+        // A failed report must not keep the remaining timers from being lifted
+        if let Err(e) = notify_management(client, config, &plain, &html).await {
+            failed_reports.push(e);
+        }
+    }
+    if let Some(e) = failed_reports.first() {
+        anyhow::bail!(
+            "Could not report {} lifted bans and mutes: {:?}",
+            failed_reports.len(),
+            e
+        );
+    }
+    Ok(())
+}
+
+/// Lift the ban of `user` in `room`, unless it has already been lifted.
+async fn lift_ban(client: &Client, room: &Joined, user: &UserId) -> Result<(), anyhow::Error> {
+    let member = room
+        .get_state_event(EventType::RoomMember, user.as_str())
+        .await?
+        .and_then(|raw| {
+            raw.deserialize_as::<SyncStateEvent<MemberEventContent>>()
+                .ok()
+        });
+    if member.is_some_and(|member| member.content.membership == MembershipState::Ban) {
+        client
+            .send(unban_user::Request::new(room.room_id(), user), None)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Lift the lockdowns that have expired, reporting them to the management room. Lockdowns that
+/// could not be lifted are tried again after [`RETRY_DELAY`].
+async fn lift_expired_lockdowns(client: &Client, config: &Config) -> Result<(), anyhow::Error> {
+    let now = timers::now();
+    // Lockdowns are rare, so only load them from the homeserver once one might have expired
+    if Lockdowns::cached(client).await?.expired(now).is_empty() {
+        return Ok(());
+    }
+    let _lock = Lockdowns::lock().await;
+    let mut lockdowns = Lockdowns::load(client).await?;
+    let expired = lockdowns.expired(now);
+    if expired.is_empty() {
+        return Ok(());
+    }
+    let results = protection_commands::lift_lockdowns(client, &mut lockdowns, expired).await;
+    for (room_id, result) in &results {
+        if let (Err(_), Some(lockdown)) = (result, lockdowns.rooms.get_mut(room_id)) {
+            lockdown.expires = Some(timers::now().saturating_add(RETRY_DELAY.as_secs()));
+        }
+    }
+    lockdowns.save(client).await?;
+    let (plain, html) =
+        protection_commands::format_lockdown_results(&results, "Lifted expired lockdown of");
+    notify_management(client, config, &plain, &html).await
+}
+
+/// Permission required to invoke a command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    /// Anyone in the management room may invoke the command.
+    Anyone,
+    /// Only members of the management room, with at least the configured management power
+    /// level if one is set, may invoke the command.
+    Moderator,
+}
+
+impl std::fmt::Display for Permission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Anyone => write!(f, "anyone"),
+            Self::Moderator => write!(f, "moderator"),
+        }
+    }
+}
+
+/// A single argument accepted by a command.
+#[derive(Debug)]
+pub struct Argument {
+    /// Name of the argument as shown in usage strings.
+    pub name: &'static str,
+    /// Whether the argument must be supplied.
+    pub required: bool,
+    /// Short description of the argument.
+    pub description: &'static str,
+}
+
+/// A `--flag` accepted by a command.
+#[derive(Debug)]
+pub struct Flag {
+    /// Name of the flag, without the leading dashes.
+    pub name: &'static str,
+    /// Name of the flag's value as shown in usage strings, or `None` if the flag takes no value.
+    pub value: Option<&'static str>,
+    /// Short description of the flag.
+    pub description: &'static str,
+}
+
+/// What a command targets implicitly when it is sent as a reply to another message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplyTarget {
+    /// The sender of the message that was replied to.
+    Sender,
+}
+
+/// Description of a bot command, used for dispatching and generating help text.
+#[derive(Debug)]
+pub struct Command {
+    /// Name used to invoke the command.
+    pub name: &'static str,
+    /// Alternative names the command can be invoked with.
+    pub aliases: &'static [&'static str],
+    /// Arguments accepted by the command, in order.
+    pub arguments: &'static [Argument],
+    /// Flags accepted by the command.
+    pub flags: &'static [Flag],
+    /// Permission required to invoke the command.
+    pub permission: Permission,
+    /// Target filled in as the first argument when the command is sent as a reply, unless one
+    /// is given explicitly.
+    pub reply_target: Option<ReplyTarget>,
+    /// Subcommands, selected by the first argument. Commands with subcommands take no arguments
+    /// of their own.
+    pub subcommands: &'static [Command],
+    /// Short description of what the command does.
+    pub description: &'static str,
+}
+
+impl Command {
+    /// Usage string of the command, e.g. `ban <user-id|glob> [reason]`.
+    #[must_use]
+    pub fn usage(&self) -> String {
+        let mut usage = self.name.to_owned();
+        if !self.subcommands.is_empty() {
+            let names: Vec<&str> = self.subcommands.iter().map(|s| s.name).collect();
+            usage.push_str(&format!(" <{}>", names.join("|")));
+        }
+        for flag in self.flags {
+            match flag.value {
+                Some(value) => usage.push_str(&format!(" [--{} <{}>]", flag.name, value)),
+                None => usage.push_str(&format!(" [--{}]", flag.name)),
+            }
+        }
+        for argument in self.arguments {
+            if argument.required {
+                usage.push_str(&format!(" <{}>", argument.name));
+            } else {
+                usage.push_str(&format!(" [{}]", argument.name));
+            }
+        }
+        usage
+    }
+}
+
+/// All commands understood by the bot.
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        aliases: &["commands"],
+        arguments: &[
+            Argument {
+                name: "command",
+                required: false,
+                description: "Command to show detailed help for",
+            },
+            Argument {
+                name: "subcommand",
+                required: false,
+                description: "Subcommand to show detailed help for",
+            },
+        ],
+        flags: &[],
+        permission: Permission::Anyone,
+        reply_target: None,
+        subcommands: &[],
+        description: "Show available commands, or details about a single command",
+    },
+    Command {
+        name: "ban",
+        aliases: &[],
+        arguments: &[
+            Argument {
+                name: "user-id|glob",
+                required: true,
+                description: "User ID, or glob matching the user IDs of room members",
+            },
+            Argument {
+                name: "duration",
+                required: false,
+                description: "Lift the ban again after this long, e.g. 2h or 7d",
+            },
+            Argument {
+                name: "reason",
+                required: false,
+                description: "Reason for the ban",
+            },
+        ],
+        flags: &[ONLY_ROOM_FLAG, GLOB_YES_FLAG],
+        permission: Permission::Moderator,
+        reply_target: Some(ReplyTarget::Sender),
+        subcommands: &[],
+        description: "Ban users from all protected rooms",
+    },
+    Command {
+        name: "kick",
+        aliases: &[],
+        arguments: &[
+            Argument {
+                name: "user-id|glob",
+                required: true,
+                description: "User ID, or glob matching the user IDs of room members",
+            },
+            Argument {
+                name: "reason",
+                required: false,
+                description: "Reason for the kick",
+            },
+        ],
+        flags: &[ONLY_ROOM_FLAG, GLOB_YES_FLAG],
+        permission: Permission::Moderator,
+        reply_target: Some(ReplyTarget::Sender),
+        subcommands: &[],
+        description: "Kick users from all protected rooms",
+    },
+    Command {
+        name: "unban",
+        aliases: &[],
+        arguments: &[Argument {
+            name: "user-id|glob",
+            required: true,
+            description: "User ID, or glob matching the user IDs of banned users",
+        }],
+        flags: &[ONLY_ROOM_FLAG],
+        permission: Permission::Moderator,
+        reply_target: Some(ReplyTarget::Sender),
+        subcommands: &[],
+        description: "Lift bans in all protected rooms",
+    },
+    Command {
+        name: "redact",
+        aliases: &["purge"],
+        arguments: &[
+            Argument {
+                name: "user-id|event-id",
+                required: true,
+                description: "Event to redact, or user whose recent messages should be redacted",
+            },
+            Argument {
+                name: "limit",
+                required: false,
+                description: "Maximum number of messages to redact for a user, defaults to 25",
+            },
+        ],
+        flags: &[
+            TARGET_ROOM_FLAG,
+            Flag {
+                name: "reason",
+                value: Some("reason"),
+                description: "Reason for the redaction",
+            },
+        ],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Redact an event, or a user's recent messages",
+    },
+    Command {
+        name: "mute",
+        aliases: &[],
+        arguments: &[
+            Argument {
+                name: "user-id",
+                required: true,
+                description: "User to mute",
+            },
+            Argument {
+                name: "duration",
+                required: false,
+                description: "Lift the mute again after this long, e.g. 30m, 2h or 7d",
+            },
+        ],
+        flags: &[TARGET_ROOM_FLAG],
+        permission: Permission::Moderator,
+        reply_target: Some(ReplyTarget::Sender),
+        subcommands: &[],
+        description: "Prevent a user from sending messages by lowering their power level",
+    },
+    Command {
+        name: "unmute",
+        aliases: &[],
+        arguments: &[Argument {
+            name: "user-id",
+            required: true,
+            description: "User to unmute",
+        }],
+        flags: &[TARGET_ROOM_FLAG],
+        permission: Permission::Moderator,
+        reply_target: Some(ReplyTarget::Sender),
+        subcommands: &[],
+        description: "Allow a muted user to send messages again by restoring their power level",
+    },
+    Command {
+        name: "powerlevel",
+        aliases: &["pl"],
+        arguments: &[
+            Argument {
+                name: "user-id|show",
+                required: true,
+                description: "User whose power level to change, or `show` to list power levels",
+            },
+            Argument {
+                name: "level",
+                required: false,
+                description: "New power level, required unless showing power levels",
+            },
+        ],
+        flags: &[
+            TARGET_ROOM_FLAG,
+            Flag {
+                name: "yes",
+                value: None,
+                description: "Apply the change, instead of only showing what would change",
+            },
+        ],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Show or change power levels in the room",
+    },
+    Command {
+        name: "protect",
+        aliases: &[],
+        arguments: &[Argument {
+            name: "room",
+            required: true,
+            description: "Room ID or alias of the room to protect",
+        }],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Join a room and add it to the protected rooms",
+    },
+    Command {
+        name: "unprotect",
+        aliases: &[],
+        arguments: &[Argument {
+            name: "room",
+            required: true,
+            description: "Room ID or alias of the room to stop protecting",
+        }],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Remove a room from the protected rooms",
+    },
+    Command {
+        name: "watch",
+        aliases: &[],
+        arguments: &[Argument {
+            name: "room",
+            required: true,
+            description: "Room ID or alias of the room to watch",
+        }],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description:
+            "Join a room and report users matching policy lists in it, without taking action",
+    },
+    Command {
+        name: "unwatch",
+        aliases: &[],
+        arguments: &[Argument {
+            name: "room",
+            required: true,
+            description: "Room ID or alias of the room to stop watching",
+        }],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Remove a room from the watched rooms",
+    },
+    Command {
+        name: "rooms",
+        aliases: &[],
+        arguments: &[],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "List the protected and watched rooms",
+    },
+    Command {
+        name: "timers",
+        aliases: &[],
+        arguments: &[],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "List temporary bans and mutes that have yet to expire",
+    },
+    Command {
+        name: "list",
+        aliases: &[],
+        arguments: &[],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: LIST_SUBCOMMANDS,
+        description: "Manage the policy lists published or subscribed to by the bot",
+    },
+    Command {
+        name: "flood",
+        aliases: &[],
+        arguments: &[],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: FLOOD_SUBCOMMANDS,
+        description:
+            "Configure how many messages users may send in protected rooms in a short time",
+    },
+    Command {
+        name: "mentions",
+        aliases: &[],
+        arguments: &[],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: MENTIONS_SUBCOMMANDS,
+        description: "Configure how many users a single message in protected rooms may mention",
+    },
+    Command {
+        name: "lockdown",
+        aliases: &[],
+        arguments: &[],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: LOCKDOWN_SUBCOMMANDS,
+        description: "Lock down rooms by hand or when many users join them at once",
+    },
+    Command {
+        name: "links",
+        aliases: &[],
+        arguments: &[],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: LINKS_SUBCOMMANDS,
+        description: "Filter messages in protected rooms linking to denied domains",
+    },
+    Command {
+        name: "filter",
+        aliases: &[],
+        arguments: &[],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: FILTER_SUBCOMMANDS,
+        description: "Filter messages in protected rooms containing words or matching patterns",
+    },
+];
+
+/// Subcommands of the `list` command.
+const LIST_SUBCOMMANDS: &[Command] = &[
+    Command {
+        name: "create",
+        aliases: &[],
+        arguments: &[
+            Argument {
+                name: "alias",
+                required: true,
+                description: "Localpart of the alias of the new policy list room",
+            },
+            Argument {
+                name: "name",
+                required: false,
+                description: "Name of the new policy list room",
+            },
+        ],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Create a new public policy list room",
+    },
+    Command {
+        name: "add",
+        aliases: &[],
+        arguments: &[
+            Argument {
+                name: "user|server|room",
+                required: true,
+                description: "Kind of entity the rule applies to",
+            },
+            Argument {
+                name: "entity",
+                required: true,
+                description: "User ID, server name or room the rule applies to, may be a glob",
+            },
+            Argument {
+                name: "action",
+                required: true,
+                description: "Action to take on matching entities: ban, kick, mute, redact, redact-and-ban, quarantine or warn",
+            },
+            Argument {
+                name: "reason",
+                required: false,
+                description: "Reason for the rule",
+            },
+        ],
+        flags: &[
+            LIST_FLAG,
+            Flag {
+                name: "legacy",
+                value: None,
+                description: "Publish the rule as a legacy sh.nao.list.* event instead of a standard m.policy.rule.* event",
+            },
+        ],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Add a rule to a policy list, replacing any existing rule for the entity",
+    },
+    Command {
+        name: "remove",
+        aliases: &["revoke"],
+        arguments: &[
+            Argument {
+                name: "user|server|room",
+                required: true,
+                description: "Kind of entity the rule applies to",
+            },
+            Argument {
+                name: "entity",
+                required: true,
+                description: "User ID, server name, room or glob of the rule to remove",
+            },
+        ],
+        flags: &[LIST_FLAG],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Revoke a rule from a policy list",
+    },
+    Command {
+        name: "show",
+        aliases: &[],
+        arguments: &[],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Show the rules of the policy lists published by the bot, and the lists it is subscribed to",
+    },
+    Command {
+        name: "watch",
+        aliases: &["subscribe"],
+        arguments: &[Argument {
+            name: "room",
+            required: true,
+            description: "Room ID or alias of the policy list to subscribe to",
+        }],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Subscribe to a policy list published by others and enforce its rules in the protected rooms",
+    },
+    Command {
+        name: "unwatch",
+        aliases: &["unsubscribe"],
+        arguments: &[Argument {
+            name: "room",
+            required: true,
+            description: "Room ID or alias of the policy list to unsubscribe from",
+        }],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Unsubscribe from a policy list, lifting what its rules caused",
+    },
+    Command {
+        name: "simulate",
+        aliases: &[],
+        arguments: &[Argument {
+            name: "room",
+            required: true,
+            description: "Room ID or alias of the policy list to simulate",
+        }],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Report what the rules of a policy list would do in the protected rooms, without doing it",
+    },
+];
+
+/// Subcommands of the `flood` command.
+const FLOOD_SUBCOMMANDS: &[Command] = &[
+    Command {
+        name: "show",
+        aliases: &[],
+        arguments: &[],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Show the flood protection settings of the protected rooms",
+    },
+    Command {
+        name: "set",
+        aliases: &[],
+        arguments: &[
+            Argument {
+                name: "messages",
+                required: true,
+                description: "Number of messages a user may send within the window",
+            },
+            Argument {
+                name: "window",
+                required: true,
+                description: "Length of the window, e.g. 10s or 1m",
+            },
+            Argument {
+                name: "action",
+                required: false,
+                description: "Action to take on users sending more messages: ban, kick, mute, redact, redact-and-ban, quarantine or warn. Defaults to mute",
+            },
+        ],
+        flags: &[ROOM_FLAG],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Turn on flood protection with the given limit",
+    },
+    Command {
+        name: "off",
+        aliases: &[],
+        arguments: &[],
+        flags: &[ROOM_FLAG],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Turn off flood protection",
+    },
+    Command {
+        name: "clear",
+        aliases: &[],
+        arguments: &[],
+        flags: &[ROOM_FLAG],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Remove the flood protection setting, so that a room falls back to the default",
+    },
+];
+
+/// Subcommands of the `mentions` command.
+const MENTIONS_SUBCOMMANDS: &[Command] = &[
+    Command {
+        name: "show",
+        aliases: &[],
+        arguments: &[],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Show the mention protection settings of the protected rooms",
+    },
+    Command {
+        name: "set",
+        aliases: &[],
+        arguments: &[
+            Argument {
+                name: "mentions",
+                required: true,
+                description: "Number of users a single message may mention",
+            },
+            Argument {
+                name: "action",
+                required: false,
+                description: "Action to take on users mentioning more users: ban, kick, mute, redact, redact-and-ban, quarantine or warn. Defaults to mute",
+            },
+        ],
+        flags: &[ROOM_FLAG],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Turn on mention protection with the given limit",
+    },
+    Command {
+        name: "off",
+        aliases: &[],
+        arguments: &[],
+        flags: &[ROOM_FLAG],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Turn off mention protection",
+    },
+    Command {
+        name: "clear",
+        aliases: &[],
+        arguments: &[],
+        flags: &[ROOM_FLAG],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Remove the mention protection setting, so that a room falls back to the default",
+    },
+    Command {
+        name: "allow",
+        aliases: &[],
+        arguments: &[Argument {
+            name: "user-id",
+            required: true,
+            description: "User who may mention any number of users",
+        }],
+        flags: &[ROOM_FLAG],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Exempt a user from mention protection, such as an announcement account",
+    },
+    Command {
+        name: "disallow",
+        aliases: &[],
+        arguments: &[Argument {
+            name: "user-id",
+            required: true,
+            description: "User to no longer exempt",
+        }],
+        flags: &[ROOM_FLAG],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Stop exempting a user from mention protection",
+    },
+];
+
+/// Subcommands of the `lockdown` command.
+const LOCKDOWN_SUBCOMMANDS: &[Command] = &[
+    Command {
+        name: "on",
+        aliases: &[],
+        arguments: &[
+            Argument {
+                name: "room",
+                required: true,
+                description: "Room ID or alias of the room to lock down",
+            },
+            Argument {
+                name: "duration",
+                required: false,
+                description: "How long to lock the room down for, e.g. 30m. Lasts until lifted if not given",
+            },
+        ],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Lock down a room as configured for it, or by making it invite only and restricting messages",
+    },
+    Command {
+        name: "off",
+        aliases: &[],
+        arguments: &[Argument {
+            name: "room",
+            required: false,
+            description: "Room ID or alias of the room to lift the lockdown of. Lifts every lockdown if not given",
+        }],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Lift a lockdown, restoring the join rule and power levels the room had before",
+    },
+    Command {
+        name: "status",
+        aliases: &[],
+        arguments: &[],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "List the rooms that are locked down",
+    },
+    Command {
+        name: "show",
+        aliases: &[],
+        arguments: &[],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Show the join flood protection settings of the protected rooms",
+    },
+    Command {
+        name: "set",
+        aliases: &[],
+        arguments: &[
+            Argument {
+                name: "joins",
+                required: true,
+                description: "Number of users who may join within the window",
+            },
+            Argument {
+                name: "window",
+                required: true,
+                description: "Length of the window, e.g. 10s or 1m",
+            },
+            Argument {
+                name: "cooldown",
+                required: false,
+                description: "How long lockdowns last, e.g. 30m. They last until lifted if not given",
+            },
+        ],
+        flags: &[
+            ROOM_FLAG,
+            Flag {
+                name: "join-rule",
+                value: Some("invite|knock|unchanged"),
+                description: "Join rule to switch the room to, defaults to invite",
+            },
+            Flag {
+                name: "allow-messages",
+                value: None,
+                description: "Don't stop users without a raised power level from sending messages",
+            },
+        ],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Turn on join flood protection, locking rooms down once more users join them in a short time",
+    },
+    Command {
+        name: "disable",
+        aliases: &[],
+        arguments: &[],
+        flags: &[ROOM_FLAG],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Turn off join flood protection",
+    },
+    Command {
+        name: "clear",
+        aliases: &[],
+        arguments: &[],
+        flags: &[ROOM_FLAG],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Remove the join flood protection setting, so that a room falls back to the default",
+    },
+];
+
+/// Subcommands of the `links` command.
+const LINKS_SUBCOMMANDS: &[Command] = &[
+    Command {
+        name: "show",
+        aliases: &[],
+        arguments: &[],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Show the link filter settings of the protected rooms",
+    },
+    Command {
+        name: "set",
+        aliases: &[],
+        arguments: &[Argument {
+            name: "action",
+            required: false,
+            description: "Action to take on users whose messages are redacted: ban, kick, mute, redact, redact-and-ban, quarantine or warn. Defaults to warn",
+        }],
+        flags: &[
+            ROOM_FLAG,
+            Flag {
+                name: "new-members",
+                value: Some("duration"),
+                description: "Only let users who joined less than this long ago link to allowed domains, e.g. 1d",
+            },
+        ],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Turn on the link filter, redacting and reporting messages linking to denied domains",
+    },
+    Command {
+        name: "off",
+        aliases: &[],
+        arguments: &[],
+        flags: &[ROOM_FLAG],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Turn off the link filter",
+    },
+    Command {
+        name: "clear",
+        aliases: &[],
+        arguments: &[],
+        flags: &[ROOM_FLAG],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Remove the link filter setting, so that a room falls back to the default",
+    },
+    Command {
+        name: "list",
+        aliases: &[],
+        arguments: &[],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "List the denied and allowed domains",
+    },
+    Command {
+        name: "deny",
+        aliases: &[],
+        arguments: &[DOMAIN_ARGUMENT],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Add a domain to the deny list",
+    },
+    Command {
+        name: "undeny",
+        aliases: &[],
+        arguments: &[DOMAIN_ARGUMENT],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Remove a domain from the deny list",
+    },
+    Command {
+        name: "allow",
+        aliases: &[],
+        arguments: &[DOMAIN_ARGUMENT],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Add a domain to the list of domains new members may link to",
+    },
+    Command {
+        name: "disallow",
+        aliases: &[],
+        arguments: &[DOMAIN_ARGUMENT],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Remove a domain from the list of domains new members may link to",
+    },
+];
+
+/// Subcommands of the `filter` command.
+const FILTER_SUBCOMMANDS: &[Command] = &[
+    Command {
+        name: "show",
+        aliases: &[],
+        arguments: &[],
+        flags: &[],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Show the content filter rules of the protected rooms",
+    },
+    Command {
+        name: "add",
+        aliases: &[],
+        arguments: &[
+            Argument {
+                name: "word|regex",
+                required: true,
+                description: "Whether the pattern is a word or phrase, or a regular expression",
+            },
+            Argument {
+                name: "pattern",
+                required: true,
+                description: "Word, phrase or regular expression messages may not contain. Quote it if it contains spaces",
+            },
+            Argument {
+                name: "action",
+                required: true,
+                description: "Action to take on users whose messages match, after redacting them: ban, kick, mute, redact, redact-and-ban, quarantine or warn",
+            },
+            Argument {
+                name: "reason",
+                required: false,
+                description: "Reason given for the action",
+            },
+        ],
+        flags: &[ROOM_FLAG],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Add a content filter rule, replacing any existing rule with the same pattern",
+    },
+    Command {
+        name: "remove",
+        aliases: &[],
+        arguments: &[Argument {
+            name: "pattern",
+            required: true,
+            description: "Pattern of the rule to remove",
+        }],
+        flags: &[ROOM_FLAG],
+        permission: Permission::Moderator,
+        reply_target: None,
+        subcommands: &[],
+        description: "Remove a content filter rule",
+    },
+];
+
+/// Domain argument of the commands editing the domain lists.
+const DOMAIN_ARGUMENT: Argument = Argument {
+    name: "domain",
+    required: true,
+    description: "Domain, may be a glob such as *.example.org",
+};
+
+/// Flag selecting the protected room a protection setting applies to.
+const ROOM_FLAG: Flag = Flag {
+    name: "room",
+    value: Some("room"),
+    description:
+        "Protected room to change the setting of, instead of the default for all protected rooms",
+};
+
+/// Flag selecting which published policy list a rule command applies to.
+const LIST_FLAG: Flag = Flag {
+    name: "list",
+    value: Some("room"),
+    description: "Policy list room to use, required if more than one list is published",
+};
+
+/// Flag restricting membership changes to a single room.
+const ONLY_ROOM_FLAG: Flag = Flag {
+    name: "room",
+    value: Some("room"),
+    description: "Only apply to this room, instead of all protected rooms",
+};
+
+/// Flag confirming a ban or kick of every member a glob matches.
+const GLOB_YES_FLAG: Flag = Flag {
+    name: "yes",
+    value: None,
+    description: "Apply to every member a glob matches, instead of only showing who it matches",
+};
+
+/// Flag selecting the room commands acting on a single room apply to.
+const TARGET_ROOM_FLAG: Flag = Flag {
+    name: "room",
+    value: Some("room"),
+    description: "Room to apply to, required",
+};
+
+/// Look up a command by its name or one of its aliases.
+#[must_use]
+pub fn find_command(name: &str) -> Option<&'static Command> {
+    find_in(COMMANDS, name)
+}
+
+/// Look up a subcommand of `command` by its name or one of its aliases.
+#[must_use]
+pub fn find_subcommand(command: &'static Command, name: &str) -> Option<&'static Command> {
+    find_in(command.subcommands, name)
+}
+
+/// Look up a command in `commands` by its name or one of its aliases.
+fn find_in(commands: &'static [Command], name: &str) -> Option<&'static Command> {
+    commands
+        .iter()
+        .find(|command| command.name == name || command.aliases.contains(&name))
+}
+
+/// Handles incoming commands and dispatches relevant functions.
+///
+/// `reply_to` is the event the command was sent in reply to, if any.
+async fn handle_command(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    mut tokens: Vec<String>,
+    reply_to: Option<&EventId>,
+    client: &Client,
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    // Commands are only accepted in the management room, they select the rooms they apply to
+    // explicitly
+    if room.room_id() != &config.bot.management_room {
+        return Ok(());
+    }
+    let name = tokens.first().map_or("help", String::as_str);
+    let command = match find_command(name) {
+        Some(command) => command,
+        None => {
+            command_unknown(event, room, config).await?;
+            return Ok(());
+        }
+    };
+    if !has_permission(client, config, &event.sender, command.permission).await? {
+        info!(
+            "{} lacks permission to run {} in {}",
+            event.sender,
+            command.name,
+            room.room_id()
+        );
+        send_reply(
+            &format!(
+                "You need to be a {} to use {}",
+                command.permission, command.name
+            ),
+            &format!(
+                "You need to be a {} to use <code>{}</code>",
+                command.permission, command.name
+            ),
+            room,
+            event.event_id.clone(),
+        )
+        .await?;
+        return Ok(());
+    }
+    if let (Some(reply_to), Some(reply_target)) = (reply_to, command.reply_target) {
+        insert_reply_target(room, &mut tokens, reply_to, reply_target).await?;
+    }
+    // Commands with subcommands parse the arguments of the subcommand instead of their own
+    let (spec, usage, arguments) = if command.subcommands.is_empty() {
+        (command, command.usage(), tokens.get(1..))
+    } else {
+        match tokens
+            .get(1)
+            .and_then(|name| find_subcommand(command, name))
+        {
+            Some(subcommand) => (
+                subcommand,
+                format!("{} {}", command.name, subcommand.usage()),
+                tokens.get(2..),
+            ),
+            None => {
+                let (plain, html) = format_command_help(command, None, &config.bot.command_prefix);
+                send_reply(&plain, &html, room, event.event_id.clone()).await?;
+                return Ok(());
+            }
+        }
+    };
+    let result = match Arguments::parse(spec, arguments.unwrap_or_default()) {
+        Ok(arguments) => {
+            let arguments = &arguments;
+            match command.name {
+                "help" => command_help(event, room, arguments, config).await,
+                "ban" => {
+                    command_membership(event, room, client, arguments, config, Membership::Ban)
+                        .await
+                }
+                "kick" => {
+                    command_membership(event, room, client, arguments, config, Membership::Kick)
+                        .await
+                }
+                "unban" => {
+                    command_membership(event, room, client, arguments, config, Membership::Unban)
+                        .await
+                }
+                "redact" => command_redact(event, room, client, arguments).await,
+                "mute" => command_mute(event, room, client, arguments).await,
+                "unmute" => command_unmute(event, room, client, arguments).await,
+                "powerlevel" => command_powerlevel(event, room, client, arguments).await,
+                "protect" => command_protect(event, room, client, arguments, config).await,
+                "unprotect" => command_unprotect(event, room, client, arguments).await,
+                "watch" => command_watch(event, room, client, arguments, config).await,
+                "unwatch" => command_unwatch(event, room, client, arguments).await,
+                "rooms" => command_rooms(event, room, client).await,
+                "timers" => command_timers(event, room).await,
+                "list" => command_list(event, room, client, spec.name, arguments, config).await,
+                "flood" => {
+                    protection_commands::command_flood(
+                        event, room, client, spec.name, arguments, config,
+                    )
+                    .await
+                }
+                "mentions" => {
+                    protection_commands::command_mentions(
+                        event, room, client, spec.name, arguments, config,
+                    )
+                    .await
+                }
+                "lockdown" => {
+                    protection_commands::command_lockdown(
+                        event, room, client, spec.name, arguments, config,
+                    )
+                    .await
+                }
+                "links" => {
+                    protection_commands::command_links(
+                        event, room, client, spec.name, arguments, config,
+                    )
+                    .await
+                }
+                "filter" => {
+                    protection_commands::command_filter(
+                        event, room, client, spec.name, arguments, config,
+                    )
+                    .await
+                }
+                _ => command_unknown(event, room, config).await,
+            }
+        }
+        Err(e) => Err(e.into()),
+    };
+    // Report malformed arguments back to the sender, along with how the command should be used
+    match result {
+        Err(e) => match e.downcast::<ParseError>() {
+            Ok(e) => {
+                send_reply(
+                    &format!("Error: {}\nUsage: {}", e, usage),
+                    &format!(
+                        "Error: {}<br>Usage: <code>{}</code>",
+                        escape_html(&e.to_string()),
+                        escape_html(&usage)
+                    ),
+                    room,
+                    event.event_id.clone(),
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        },
+        Ok(()) => Ok(()),
+    }
+}
+
+/// Insert the implicit target of a command sent as a reply as its first argument, unless the
+/// command already names an explicit target.
+async fn insert_reply_target(
+    room: &Joined,
+    tokens: &mut Vec<String>,
+    reply_to: &EventId,
+    reply_target: ReplyTarget,
+) -> Result<(), anyhow::Error> {
+    let explicit = tokens.get(1).is_some_and(|token| match reply_target {
+        ReplyTarget::Sender => token.starts_with('@') || token.contains(['*', '?']),
+    });
+    if explicit {
+        return Ok(());
+    }
+    let target = match reply_target {
+        ReplyTarget::Sender => room
+            .event(get_room_event::Request::new(room.room_id(), reply_to))
+            .await?
+            .event
+            .deserialize()?
+            .sender()
+            .to_string(),
+    };
+    tokens.insert(1.min(tokens.len()), target);
+    Ok(())
+}
+
+/// Check whether `user` is allowed to invoke commands requiring `permission`.
+async fn has_permission(
+    client: &Client,
+    config: &Config,
+    user: &UserId,
+    permission: Permission,
+) -> Result<bool, anyhow::Error> {
+    Ok(match permission {
+        Permission::Anyone => true,
+        Permission::Moderator => is_moderator(client, config, user).await?,
+    })
+}
+
+/// Check whether `user` is a joined member of the management room, with at least
+/// `management_power_level` if one is configured.
+async fn is_moderator(
+    client: &Client,
+    config: &Config,
+    user: &UserId,
+) -> Result<bool, anyhow::Error> {
+    let room = match client.get_joined_room(&config.bot.management_room) {
+        Some(room) => room,
+        None => {
+            warn!(
+                "Not joined to management room {}",
+                config.bot.management_room
+            );
+            return Ok(false);
+        }
+    };
+    let joined = match room
+        .get_state_event(EventType::RoomMember, user.as_str())
+        .await?
+    {
+        Some(raw) => {
+            raw.deserialize_as::<SyncStateEvent<MemberEventContent>>()?
+                .content
+                .membership
+                == MembershipState::Join
+        }
+        None => false,
+    };
+    if !joined {
+        return Ok(false);
+    }
+    Ok(match config.bot.management_power_level {
+        Some(required) => user_power_level(&power_levels(&room).await?, user) >= required,
+        None => true,
+    })
+}
+
+/// Fallback when an unrecognized command is invoked.
+pub(crate) async fn command_unknown(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    send_reply(
+        &format!("Unrecognized command, please try again or see {} help for available commands.", &config.bot.command_prefix),
+        &format!("Unrecognized command, please try again or see <code>{} help</code> for available commands.", &config.bot.command_prefix),
+        room,
+        event.event_id.clone(),
+    ).await?;
+    Ok(())
+}
+
+/// Send help information, either a summary of all commands or details about a single command.
+async fn command_help(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    arguments: &Arguments,
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    let prefix = &config.bot.command_prefix;
+    let (plain, html) = match arguments.get(0) {
+        None => {
+            let mut plain = String::from("Available commands:");
+            let mut html = String::from("<p>Available commands:</p><ul>");
+            for command in COMMANDS {
+                plain.push_str(&format!(
+                    "\n{} {} - {}",
+                    prefix,
+                    command.usage(),
+                    command.description
+                ));
+                html.push_str(&format!(
+                    "<li><code>{} {}</code> - {}</li>",
+                    escape_html(prefix),
+                    escape_html(&command.usage()),
+                    escape_html(command.description)
+                ));
+            }
+            html.push_str("</ul>");
+            plain.push_str(&format!(
+                "\nSee {} help <command> for details about a command.",
+                prefix
+            ));
+            html.push_str(&format!(
+                "<p>See <code>{} help &lt;command&gt;</code> for details about a command.</p>",
+                escape_html(prefix)
+            ));
+            (plain, html)
+        }
+        Some(name) => match find_command(name) {
+            Some(command) => match arguments
+                .get(1)
+                .and_then(|name| find_subcommand(command, name))
+            {
+                Some(subcommand) => format_command_help(subcommand, Some(command), prefix),
+                None => format_command_help(command, None, prefix),
+            },
+            None => {
+                command_unknown(event, room, config).await?;
+                return Ok(());
+            }
+        },
+    };
+    send_reply(&plain, &html, room, event.event_id.clone()).await?;
+    Ok(())
+}
+
+/// Format detailed help text for a single command, or a subcommand of `parent`, as plain text and
+/// HTML.
+fn format_command_help(
+    command: &Command,
+    parent: Option<&Command>,
+    prefix: &str,
+) -> (String, String) {
+    let prefix = &match parent {
+        Some(parent) => format!("{} {}", prefix, parent.name),
+        None => prefix.to_owned(),
+    };
+    let mut plain = format!("{} {}\n{}", prefix, command.usage(), command.description);
+    let mut html = format!(
+        "<p><code>{} {}</code></p><p>{}</p>",
+        escape_html(prefix),
+        escape_html(&command.usage()),
+        escape_html(command.description)
+    );
+    if !command.arguments.is_empty() {
+        plain.push_str("\nArguments:");
+        html.push_str("<p>Arguments:</p><ul>");
+        for argument in command.arguments {
+            plain.push_str(&format!(
+                "\n  {}{} - {}",
+                argument.name,
+                if argument.required { "" } else { " (optional)" },
+                argument.description
+            ));
+            html.push_str(&format!(
+                "<li><code>{}</code>{} - {}</li>",
+                escape_html(argument.name),
+                if argument.required { "" } else { " (optional)" },
+                escape_html(argument.description)
+            ));
+        }
+        html.push_str("</ul>");
+    }
+    if !command.subcommands.is_empty() {
+        plain.push_str("\nSubcommands:");
+        html.push_str("<p>Subcommands:</p><ul>");
+        for subcommand in command.subcommands {
+            plain.push_str(&format!(
+                "\n  {} {} {} - {}",
+                prefix,
+                command.name,
+                subcommand.usage(),
+                subcommand.description
+            ));
+            html.push_str(&format!(
+                "<li><code>{} {} {}</code> - {}</li>",
+                escape_html(prefix),
+                command.name,
+                escape_html(&subcommand.usage()),
+                escape_html(subcommand.description)
+            ));
+        }
+        html.push_str("</ul>");
+        plain.push_str(&format!(
+            "\nSee {} help {} <subcommand> for details about a subcommand.",
+            prefix, command.name
+        ));
+        html.push_str(&format!(
+            "<p>See <code>{} help {} &lt;subcommand&gt;</code> for details about a subcommand.</p>",
+            escape_html(prefix),
+            command.name
+        ));
+    }
+    if !command.flags.is_empty() {
+        plain.push_str("\nOptions:");
+        html.push_str("<p>Options:</p><ul>");
+        for flag in command.flags {
+            let usage = match flag.value {
+                Some(value) => format!("--{} <{}>", flag.name, value),
+                None => format!("--{}", flag.name),
+            };
+            plain.push_str(&format!("\n  {} - {}", usage, flag.description));
+            html.push_str(&format!(
+                "<li><code>{}</code> - {}</li>",
+                escape_html(&usage),
+                escape_html(flag.description)
+            ));
+        }
+        html.push_str("</ul>");
+    }
+    if !command.aliases.is_empty() {
+        plain.push_str(&format!("\nAliases: {}", command.aliases.join(", ")));
+        let aliases: Vec<String> = command
+            .aliases
+            .iter()
+            .map(|alias| format!("<code>{}</code>", escape_html(alias)))
+            .collect();
+        html.push_str(&format!("<p>Aliases: {}</p>", aliases.join(", ")));
+    }
+    if command.reply_target == Some(ReplyTarget::Sender) {
+        plain.push_str("\nWhen sent as a reply, targets the sender of the replied-to message");
+        html.push_str("<p>When sent as a reply, targets the sender of the replied-to message</p>");
+    }
+    // Subcommands inherit the permission of their command, so it is only shown for the command
+    if parent.is_some() {
+        return (plain, html);
+    }
+    plain.push_str(&format!("\nRequired permission: {}", command.permission));
+    html.push_str(&format!(
+        "<p>Required permission: {}</p>",
+        command.permission
+    ));
+    (plain, html)
+}
+
+/// Membership changes that can be applied to users through commands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Membership {
+    /// Ban the user from the room.
+    Ban,
+    /// Kick the user from the room.
+    Kick,
+    /// Lift an existing ban.
+    Unban,
+}
+
+/// Ban, kick or unban a user, or all members matching a glob, in every protected room.
+async fn command_membership(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    arguments: &Arguments,
+    config: &Config,
+    membership: Membership,
+) -> Result<(), anyhow::Error> {
+    let target = arguments.user_target(0)?;
+    // Globs already skip the bot, the invoker and moderators, refuse to target the first two
+    // explicitly as well
+    if let UserTarget::User(user) = &target {
+        if membership != Membership::Unban && (user == room.own_user_id() || user == &event.sender)
+        {
+            let verb = if membership == Membership::Ban {
+                "ban"
+            } else {
+                "kick"
+            };
+            let target = if user == &event.sender {
+                "yourself"
+            } else {
+                "the bot"
+            };
+            send_reply(
+                &format!("Refusing to {} {}", verb, target),
+                &format!("Refusing to {} {}", verb, target),
+                room,
+                event.event_id.clone(),
+            )
+            .await?;
+            return Ok(());
+        }
+    }
+    // Bans may be given a duration ahead of the reason, after which they are lifted again
+    let duration = match membership {
+        Membership::Ban => arguments.get(1).and_then(parser::parse_duration),
+        Membership::Kick | Membership::Unban => None,
+    };
+    let reason = arguments.rest(if duration.is_some() { 2 } else { 1 });
+    let only = match arguments.flag_value("room") {
+        Some(_) => match command_room(event, room, client, arguments).await? {
+            Some(only) => Some(only),
+            None => return Ok(()),
+        },
+        None => None,
+    };
+    let (rooms, not_joined) = target_rooms(client, config, only).await?;
+    if rooms.is_empty() && not_joined.is_empty() {
+        send_reply(
+            "There are no protected rooms, use --room to apply to a single room",
+            "There are no protected rooms, use <code>--room</code> to apply to a single room",
+            room,
+            event.event_id.clone(),
+        )
+        .await?;
+        return Ok(());
+    }
+
+    let verb = match membership {
+        Membership::Ban => Action::Ban.past_tense(),
+        Membership::Kick => Action::Kick.past_tense(),
+        Membership::Unban => "Unbanned",
+    };
+    // Bans and kicks by glob are only shown until they are confirmed, as a broad glob could
+    // take out most of a room
+    let preview = membership != Membership::Unban
+        && matches!(target, UserTarget::Glob(_))
+        && !arguments.flag("yes");
+    let moderators = match target {
+        UserTarget::Glob(_) => moderators(client, config).await?,
+        UserTarget::User(_) => BTreeSet::new(),
+    };
+    let mut results = Vec::new();
+    for target_room in &rooms {
+        // Banned users are no longer members, so unbanning has to look at ban events instead
+        let candidates = if membership == Membership::Unban {
+            banned_users(target_room).await
+        } else {
+            target_room
+                .active_members()
+                .await
+                .map(|members| {
+                    members
+                        .iter()
+                        .map(|member| member.user_id().clone())
+                        .collect()
+                })
+                .map_err(anyhow::Error::from)
+        };
+        // A room that can't be looked at is reported along with the others instead of aborting
+        let candidates: Vec<UserId> = match candidates {
+            Ok(candidates) => candidates,
+            Err(e) => {
+                warn!("Failed to list users of {}: {:?}", target_room.room_id(), e);
+                results.push(RoomResult {
+                    room_id: target_room.room_id().clone(),
+                    verb,
+                    succeeded: Vec::new(),
+                    failed: Vec::new(),
+                    error: Some(e.to_string()),
+                });
+                continue;
+            }
+        };
+        let users = match &target {
+            // Bans also keep users out of rooms they haven't joined yet
+            UserTarget::User(user) if membership == Membership::Ban => vec![user.clone()],
+            UserTarget::User(user) => candidates.into_iter().filter(|c| c == user).collect(),
+            UserTarget::Glob(glob) => {
+                let glob = Glob::new(glob);
+                let own_user_id = target_room.own_user_id();
+                candidates
+                    .into_iter()
+                    // Never let a glob take out the bot itself or any moderator
+                    .filter(|user| {
+                        user != own_user_id && user != &event.sender && !moderators.contains(user)
+                    })
+                    .filter(|user| glob.is_match(user.as_str()))
+                    .collect::<Vec<UserId>>()
+            }
+        };
+        if preview && !users.is_empty() {
+            let action = if membership == Membership::Ban {
+                Action::Ban
+            } else {
+                Action::Kick
+            };
+            results.push(RoomResult {
+                room_id: target_room.room_id().clone(),
+                verb: action.conditional(),
+                succeeded: users,
+                failed: Vec::new(),
+                error: None,
+            });
+        } else if !users.is_empty() {
+            let target_rooms = std::slice::from_ref(target_room);
+            let reason = reason.as_deref();
+            let mut room_results = match membership {
+                Membership::Ban => {
+                    apply_actions(client, config, target_rooms, &users, &Action::Ban, reason).await
+                }
+                Membership::Kick => {
+                    apply_actions(client, config, target_rooms, &users, &Action::Kick, reason).await
+                }
+                Membership::Unban => unban_users(client, target_rooms, &users).await,
+            };
+            if membership != Membership::Kick {
+                let changed: Vec<UserId> = room_results
+                    .iter()
+                    .flat_map(|result| &result.succeeded)
+                    .cloned()
+                    .collect();
+                // A new ban replaces any temporary ban, and unbanning lifts it early
+                let timers = Timers::update(|timers| {
+                    for user in &changed {
+                        match duration {
+                            Some(duration) => timers.add(Timer::new(
+                                TimerKind::Unban,
+                                target_room.room_id().clone(),
+                                user.clone(),
+                                duration,
+                            )),
+                            None => {
+                                timers.remove(TimerKind::Unban, target_room.room_id(), user);
+                            }
+                        }
+                    }
+                });
+                // Bans and unbans by moderators take precedence over those caused by rules, so
+                // they aren't undone when the rule is revoked
+                let enforcements = RuleEnforcements::update(client, |enforcements| {
+                    enforcements.forget_bans(target_room.room_id(), &changed)
+                })
+                .await;
+                // The changes were made, so failing to record them is reported with the room
+                // rather than keeping the remaining rooms from being changed
+                if let (Err(e), Some(result)) = (timers.and(enforcements), room_results.last_mut())
+                {
+                    warn!(
+                        "Failed to record changes in {}: {:?}",
+                        target_room.room_id(),
+                        e
+                    );
+                    result.error = Some(format!("Could not record the changes: {}", e));
+                }
+            }
+            results.extend(room_results);
+        }
+    }
+
+    let (mut plain, mut html) = if results.is_empty() {
+        let target = arguments.required(0)?;
+        (
+            format!("No members matching {} found", target),
+            format!(
+                "No members matching <code>{}</code> found",
+                escape_html(target)
+            ),
+        )
+    } else {
+        format_results(&results)
+    };
+    if preview && !results.is_empty() {
+        plain.push_str("\nSend the command again with --yes to apply it");
+        html.push_str("<p>Send the command again with <code>--yes</code> to apply it</p>");
+    } else if let (Some(duration), false) = (duration, results.is_empty()) {
+        plain.push_str(&format!(
+            "\nThe bans will be lifted in {}",
+            format_duration(duration)
+        ));
+        html.push_str(&format!(
+            "<p>The bans will be lifted in {}</p>",
+            format_duration(duration)
+        ));
+    }
+    for room_id in not_joined {
+        plain.push_str(&format!("\nNot joined to protected room {}", room_id));
+        html.push_str(&format!(
+            "<p>Not joined to protected room <code>{}</code></p>",
+            room_id
+        ));
+    }
+    send_reply(&plain, &html, room, event.event_id.clone()).await?;
+    Ok(())
+}
+
+/// Rooms a moderation action applies to: every protected room, or only `only` if it is given.
+///
+/// Protected rooms the bot isn't joined to are returned separately, so they can be reported.
+async fn target_rooms(
+    client: &Client,
+    config: &Config,
+    only: Option<Joined>,
+) -> Result<(Vec<Joined>, Vec<RoomId>), anyhow::Error> {
+    if let Some(only) = only {
+        return Ok((vec![only], Vec::new()));
+    }
+    let mut rooms = Vec::new();
+    let mut not_joined = Vec::new();
+    for room_id in ProtectedRooms::load(client).await?.rooms {
+        if room_id == config.bot.management_room {
+            continue;
+        }
+        match client.get_joined_room(&room_id) {
+            Some(protected) => rooms.push(protected),
+            None => {
+                warn!("Not joined to protected room {}", room_id);
+                not_joined.push(room_id);
+            }
+        }
+    }
+    Ok((rooms, not_joined))
+}
+
+/// Joined room given with the `--room` flag of a command acting on a single room. If the room
+/// can't be resolved or the bot isn't joined to it, this is reported to the sender and `None` is
+/// returned.
+async fn command_room(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    arguments: &Arguments,
+) -> Result<Option<Joined>, anyhow::Error> {
+    let target = arguments
+        .flag_value("room")
+        .ok_or_else(|| ParseError::MissingFlagValue("room".to_owned()))?;
+    let target = parser::parse_room("room", target)?;
+    let room_id = match resolve_room_or_reply(event, room, client, &target).await? {
+        Some(room_id) => room_id,
+        None => return Ok(None),
+    };
+    match client.get_joined_room(&room_id) {
+        Some(joined) => Ok(Some(joined)),
+        None => {
+            let message = format!("Not joined to {}", room_id);
+            send_reply(
+                &message,
+                &escape_html(&message),
+                room,
+                event.event_id.clone(),
+            )
+            .await?;
+            Ok(None)
+        }
+    }
+}
+
+/// Join a room and add it to the protected rooms.
+async fn command_protect(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    arguments: &Arguments,
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    let target = arguments.room(0)?;
+    let room_id = match join_room(event, room, client, &target, config).await? {
+        Some(room_id) => room_id,
+        None => return Ok(()),
+    };
+    let mut protected = ProtectedRooms::load(client).await?;
+    let refusal = if protected.rooms.contains(&room_id) {
+        Some(format!("{} is already protected", room_id))
+    } else if WatchedRooms::load(client).await?.rooms.contains(&room_id) {
+        Some(format!(
+            "{} is watched, unwatch it before protecting it",
+            room_id
+        ))
+    } else {
+        None
+    };
+    if let Some(refusal) = refusal {
+        send_reply(
+            &refusal,
+            &escape_html(&refusal),
+            room,
+            event.event_id.clone(),
+        )
+        .await?;
+        return Ok(());
+    }
+    protected.rooms.insert(room_id.clone());
+    protected.save(client).await?;
+    info!("Now protecting {}", room_id);
+
+    let mut plain = format!("Now protecting {}", room_id);
+    let mut html = format!("Now protecting <code>{}</code>", room_id);
+    // The room was only just joined, so its state may not have arrived through sync yet
+    if let Some(protected_room) = client.get_joined_room(&room_id) {
+        let power_levels = power_levels(&protected_room).await?;
+        let own_level = user_power_level(&power_levels, protected_room.own_user_id());
+        if own_level < i64::from(power_levels.ban) {
+            let warning = format!(
+                "Warning: the bot has power level {} there, but banning requires {}",
+                own_level, power_levels.ban
+            );
+            plain.push_str(&format!("\n{}", warning));
+            html.push_str(&format!("<br>{}", escape_html(&warning)));
+        }
+        // Members already in the room are held to the same rules as those joining later
+        let rooms = [protected_room];
+        let rules = policy::rule_index(client).await?;
+        let acl_results = apply_server_acls(client, config, &rooms, rules.rules()).await?;
+        let results = enforce_rules(client, config, &rooms, &rules).await?;
+        if !results.is_empty() {
+            let (results_plain, results_html) = format_results(&results);
+            plain.push_str(&format!("\n{}", results_plain));
+            html.push_str(&results_html);
+        }
+        if !acl_results.is_empty() {
+            let (acl_plain, acl_html) = format_acl_results(&acl_results);
+            plain.push_str(&format!("\n{}", acl_plain));
+            html.push_str(&acl_html);
+        }
+    }
+    send_reply(&plain, &html, room, event.event_id.clone()).await?;
+    Ok(())
+}
+
+/// Remove a room from the protected rooms. The bot stays in the room.
+async fn command_unprotect(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    arguments: &Arguments,
+) -> Result<(), anyhow::Error> {
+    let target = arguments.room(0)?;
+    let room_id = match resolve_room_or_reply(event, room, client, &target).await? {
+        Some(room_id) => room_id,
+        None => return Ok(()),
+    };
+    let mut protected = ProtectedRooms::load(client).await?;
+    let message = if protected.rooms.remove(&room_id) {
+        protected.save(client).await?;
+        info!("No longer protecting {}", room_id);
+        format!("No longer protecting {}", room_id)
+    } else {
+        format!("{} is not protected", room_id)
+    };
+    send_reply(
+        &message,
+        &escape_html(&message),
+        room,
+        event.event_id.clone(),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Join a room and add it to the watched rooms, reporting members that already match policy
+/// list rules.
+async fn command_watch(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    arguments: &Arguments,
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    let target = arguments.room(0)?;
+    let room_id = match join_room(event, room, client, &target, config).await? {
+        Some(room_id) => room_id,
+        None => return Ok(()),
+    };
+    let mut watched = WatchedRooms::load(client).await?;
+    let refusal = if watched.rooms.contains(&room_id) {
+        Some(format!("{} is already watched", room_id))
+    } else if ProtectedRooms::load(client).await?.rooms.contains(&room_id) {
+        Some(format!(
+            "{} is protected, which already includes watching it",
+            room_id
+        ))
+    } else {
+        None
+    };
+    if let Some(refusal) = refusal {
+        send_reply(
+            &refusal,
+            &escape_html(&refusal),
+            room,
+            event.event_id.clone(),
+        )
+        .await?;
+        return Ok(());
+    }
+    watched.rooms.insert(room_id.clone());
+    watched.save(client).await?;
+    info!("Now watching {}", room_id);
+    send_reply(
+        &format!("Now watching {}", room_id),
+        &format!("Now watching <code>{}</code>", room_id),
+        room,
+        event.event_id.clone(),
+    )
+    .await?;
+
+    // Members that were already there before the room was watched are reported once up front
+    if let Some(watched_room) = client.get_joined_room(&room_id) {
+        let members: Vec<UserId> = watched_room
+            .active_members()
+            .await?
+            .iter()
+            .map(|member| member.user_id().clone())
+            .collect();
+        let rules = policy::rule_index(client).await?;
+        report_matches(client, config, &watched_room, &members, &rules).await?;
+    }
+    Ok(())
+}
+
+/// Remove a room from the watched rooms. The bot stays in the room.
+async fn command_unwatch(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    arguments: &Arguments,
+) -> Result<(), anyhow::Error> {
+    let target = arguments.room(0)?;
+    let room_id = match resolve_room_or_reply(event, room, client, &target).await? {
+        Some(room_id) => room_id,
+        None => return Ok(()),
+    };
+    let mut watched = WatchedRooms::load(client).await?;
+    let message = if watched.rooms.remove(&room_id) {
+        watched.save(client).await?;
+        info!("No longer watching {}", room_id);
+        format!("No longer watching {}", room_id)
+    } else {
+        format!("{} is not watched", room_id)
+    };
+    send_reply(
+        &message,
+        &escape_html(&message),
+        room,
+        event.event_id.clone(),
+    )
+    .await?;
+    Ok(())
+}
+
+/// List the protected and watched rooms.
+async fn command_rooms(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+) -> Result<(), anyhow::Error> {
+    let mut plain = String::new();
+    let mut html = String::new();
+    for (title, rooms) in [
+        ("Protected rooms", ProtectedRooms::load(client).await?.rooms),
+        ("Watched rooms", WatchedRooms::load(client).await?.rooms),
+    ] {
+        if rooms.is_empty() {
+            plain.push_str(&format!("{}: none\n", title));
+            html.push_str(&format!("<p>{}: none</p>", title));
+            continue;
+        }
+        plain.push_str(&format!("{}:\n", title));
+        html.push_str(&format!("<p>{}:</p><ul>", title));
+        for room_id in &rooms {
+            let name = match client.get_joined_room(room_id) {
+                Some(joined) => joined
+                    .display_name()
+                    .await
+                    .unwrap_or_else(|_| room_id.to_string()),
+                None => String::from("not joined"),
+            };
+            plain.push_str(&format!("{} ({})\n", room_id, name));
+            html.push_str(&format!(
+                "<li><code>{}</code> ({})</li>",
+                room_id,
+                escape_html(&name)
+            ));
+        }
+        html.push_str("</ul>");
+    }
+    send_reply(plain.trim_end(), &html, room, event.event_id.clone()).await?;
+    Ok(())
+}
+
+/// List the temporary bans and mutes that have yet to expire.
+async fn command_timers(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+) -> Result<(), anyhow::Error> {
+    let timers = Timers::pending()?;
+    if timers.is_empty() {
+        send_reply(
+            "There are no temporary bans or mutes",
+            "There are no temporary bans or mutes",
+            room,
+            event.event_id.clone(),
+        )
+        .await?;
+        return Ok(());
+    }
+    let mut plain = String::from("Pending timers:");
+    let mut html = String::from("<p>Pending timers:</p><ul>");
+    for timer in timers {
+        let remaining = format_remaining(timer.remaining());
+        plain.push_str(&format!(
+            "\n{} {} in {} in {}",
+            timer.kind, timer.user, timer.room, remaining
+        ));
+        html.push_str(&format!(
+            "<li>{} {} in <code>{}</code> in {}</li>",
+            timer.kind,
+            escape_html(timer.user.as_str()),
+            timer.room,
+            remaining
+        ));
+    }
+    html.push_str("</ul>");
+    send_reply(&plain, &html, room, event.event_id.clone()).await?;
+    Ok(())
+}
+
+/// Resolve `target` and join it if the bot isn't in it yet, replying with the reason and
+/// returning `None` if that fails. The management room is refused, as it can't be moderated
+/// like other rooms.
+async fn join_room(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    target: &RoomIdOrAliasId,
+    config: &Config,
+) -> Result<Option<RoomId>, anyhow::Error> {
+    let room_id = match resolve_room_or_reply(event, room, client, target).await? {
+        Some(room_id) => room_id,
+        None => return Ok(None),
+    };
+    if room_id == config.bot.management_room {
+        send_reply(
+            "The management room can't be used for this",
+            "The management room can't be used for this",
+            room,
+            event.event_id.clone(),
+        )
+        .await?;
+        return Ok(None);
+    }
+    if client.get_joined_room(&room_id).is_none() {
+        info!("Joining {}", room_id);
+        if let Err(e) = client.join_room_by_id_or_alias(target, &[]).await {
+            let message = format!("Could not join {}: {}", target, e);
+            send_reply(
+                &message,
+                &escape_html(&message),
+                room,
+                event.event_id.clone(),
+            )
+            .await?;
+            return Ok(None);
+        }
+    }
+    Ok(Some(room_id))
+}
+
+/// Resolve `target` to a room ID, replying with the reason and returning `None` if that fails.
+pub(crate) async fn resolve_room_or_reply(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    target: &RoomIdOrAliasId,
+) -> Result<Option<RoomId>, anyhow::Error> {
+    match resolve_room(client, target).await {
+        Ok(room_id) => Ok(Some(room_id)),
+        Err(e) => {
+            let message = format!("Could not resolve {}: {}", target, e);
+            send_reply(
+                &message,
+                &escape_html(&message),
+                room,
+                event.event_id.clone(),
+            )
+            .await?;
+            Ok(None)
+        }
+    }
+}
+
+/// Create policy lists and manage the rules published in them.
+async fn command_list(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    subcommand: &str,
+    arguments: &Arguments,
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    match subcommand {
+        "create" => command_list_create(event, room, client, arguments).await,
+        "add" => command_list_add(event, room, client, arguments).await,
+        "remove" => command_list_remove(event, room, client, arguments).await,
+        "show" => command_list_show(event, room, client).await,
+        "watch" => command_list_watch(event, room, client, arguments, config).await,
+        "unwatch" => command_list_unwatch(event, room, client, arguments, config).await,
+        "simulate" => command_list_simulate(event, room, client, arguments, config).await,
+        _ => command_unknown(event, room, config).await,
+    }
+}
+
+/// Create a new policy list room published by the bot.
+async fn command_list_create(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    arguments: &Arguments,
+) -> Result<(), anyhow::Error> {
+    // Accept full aliases as well, only the localpart is needed to create the room
+    let alias = arguments.required(0)?;
+    let localpart = alias
+        .trim_start_matches('#')
+        .split(':')
+        .next()
+        .unwrap_or_default();
+    let name = arguments.rest(1);
+    let (plain, html) = match policy::create_list(client, localpart, name.as_deref()).await {
+        Ok(room_id) => (
+            format!("Created policy list {}", room_id),
+            format!("Created policy list <code>{}</code>", room_id),
+        ),
+        Err(e) => {
+            let message = format!("Could not create policy list: {}", e);
+            (message.clone(), escape_html(&message))
+        }
+    };
+    send_reply(&plain, &html, room, event.event_id.clone()).await?;
+    Ok(())
+}
+
+/// Add a rule to a published policy list, replacing the existing rule for the same entity.
+async fn command_list_add(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    arguments: &Arguments,
+) -> Result<(), anyhow::Error> {
+    let kind = rule_kind(arguments)?;
+    let entity = arguments.required(1)?;
+    if kind == "user" {
+        parser::parse_user_target("entity", entity)?;
+    }
+    let action = action_argument(arguments, 2)?
+        .ok_or_else(|| ParseError::MissingArgument(String::from("action")))?;
+    let reason = arguments.rest(3).unwrap_or_default();
+    let rule = List::new(kind, entity.to_owned(), action, reason)
+        .ok_or_else(|| anyhow::anyhow!("Unknown rule kind {}", kind))?;
+    let format = if arguments.flag("legacy") {
+        RuleFormat::Legacy
+    } else {
+        RuleFormat::Standard
+    };
+    let list = match published_list(event, room, client, arguments).await? {
+        Some(list) => list,
+        None => return Ok(()),
+    };
+
+    let verb = if policy::find_rules(&list, kind, entity).await?.is_empty() {
+        "Added"
+    } else {
+        "Updated"
+    };
+    let (plain, html) = match policy::publish_rule(client, &list, &rule, format).await {
+        Ok(()) => {
+            info!("{} {} rule {} in {}", verb, kind, entity, list.room_id());
+            (
+                format!(
+                    "{} {} rule {} ({}) in {}",
+                    verb,
+                    kind,
+                    entity,
+                    rule.action(),
+                    list.room_id()
+                ),
+                format!(
+                    "{} {} rule <code>{}</code> ({}) in <code>{}</code>",
+                    verb,
+                    kind,
+                    escape_html(entity),
+                    rule.action(),
+                    list.room_id()
+                ),
+            )
+        }
+        Err(e) => {
+            let message = format!("Could not publish rule: {}", e);
+            (message.clone(), escape_html(&message))
+        }
+    };
+    send_reply(&plain, &html, room, event.event_id.clone()).await?;
+    Ok(())
+}
+
+/// Revoke a rule from a published policy list, in whichever formats it was published.
+async fn command_list_remove(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    arguments: &Arguments,
+) -> Result<(), anyhow::Error> {
+    let kind = rule_kind(arguments)?;
+    let entity = arguments.required(1)?;
+    let list = match published_list(event, room, client, arguments).await? {
+        Some(list) => list,
+        None => return Ok(()),
+    };
+
+    let rules = policy::find_rules(&list, kind, entity).await?;
+    let mut message = if rules.is_empty() {
+        format!("There is no {} rule {} in {}", kind, entity, list.room_id())
+    } else {
+        format!("Revoked {} rule {} in {}", kind, entity, list.room_id())
+    };
+    for (event_type, _) in rules {
+        if let Err(e) = policy::revoke_rule(client, list.room_id(), event_type, entity).await {
+            message = format!("Could not revoke rule: {}", e);
+            break;
+        }
+        info!("Revoked {} {} in {}", event_type, entity, list.room_id());
+    }
+    send_reply(
+        &message,
+        &escape_html(&message),
+        room,
+        event.event_id.clone(),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Show the rules of every policy list published by the bot, and the policy lists it is
+/// subscribed to.
+async fn command_list_show(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+) -> Result<(), anyhow::Error> {
+    let lists = PolicyLists::load(client).await?;
+    if lists.published.is_empty() && lists.subscribed.is_empty() {
+        send_reply(
+            "No policy lists are published or subscribed to yet",
+            "No policy lists are published or subscribed to yet",
+            room,
+            event.event_id.clone(),
+        )
+        .await?;
+        return Ok(());
+    }
+    let mut plain = String::new();
+    let mut html = String::new();
+    for room_id in lists.rooms() {
+        let rules = match client.get_joined_room(room_id) {
+            Some(list) => policy::room_rules(&list).await?,
+            None => {
+                plain.push_str(&format!("{}: not joined\n", room_id));
+                html.push_str(&format!("<p><code>{}</code>: not joined</p>", room_id));
+                continue;
+            }
+        };
+        // Subscribed lists can hold thousands of rules, so only those published by the bot are
+        // shown in full
+        if lists.subscribed.contains(room_id) {
+            plain.push_str(&format!(
+                "{} (subscribed, {} rules)\n",
+                room_id,
+                rules.len()
+            ));
+            html.push_str(&format!(
+                "<p><code>{}</code> (subscribed, {} rules)</p>",
+                room_id,
+                rules.len()
+            ));
+            continue;
+        }
+        plain.push_str(&format!(
+            "{} (published, {} rules):\n",
+            room_id,
+            rules.len()
+        ));
+        html.push_str(&format!(
+            "<p><code>{}</code> (published, {} rules):</p><ul>",
+            room_id,
+            rules.len()
+        ));
+        for Rule { rule, .. } in &rules {
+            plain.push_str(&format!(
+                "{} {}: {} ({})\n",
+                rule.kind(),
+                rule.entity(),
+                rule.action(),
+                rule.reason()
+            ));
+            html.push_str(&format!(
+                "<li>{} <code>{}</code>: {} ({})</li>",
+                rule.kind(),
+                escape_html(rule.entity()),
+                rule.action(),
+                escape_html(rule.reason())
+            ));
+        }
+        html.push_str("</ul>");
+    }
+    send_reply(plain.trim_end(), &html, room, event.event_id.clone()).await?;
+    Ok(())
+}
+
+/// Subscribe to a policy list published by others and apply its rules to the protected rooms.
+async fn command_list_watch(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    arguments: &Arguments,
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    let target = arguments.room(0)?;
+    let room_id = match resolve_room_or_reply(event, room, client, &target).await? {
+        Some(room_id) => room_id,
+        None => return Ok(()),
+    };
+    let mut lists = PolicyLists::load(client).await?;
+    let refusal = if room_id == config.bot.management_room {
+        Some(String::from("The management room can't be used for this"))
+    } else if lists.published.contains(&room_id) {
+        Some(format!("{} is published by the bot", room_id))
+    } else if lists.subscribed.contains(&room_id) {
+        Some(format!("Already subscribed to {}", room_id))
+    } else {
+        None
+    };
+    if let Some(refusal) = refusal {
+        send_reply(
+            &refusal,
+            &escape_html(&refusal),
+            room,
+            event.event_id.clone(),
+        )
+        .await?;
+        return Ok(());
+    }
+
+    // Subscribe before joining, so the rules arriving with the state of the room through sync
+    // are recognized and applied
+    lists.subscribed.insert(room_id.clone());
+    lists.save(client).await?;
+    info!("Subscribed to policy list {}", room_id);
+    let list = match client.get_joined_room(&room_id) {
+        Some(list) => list,
+        None => {
+            if let Err(e) = client.join_room_by_id_or_alias(&target, &[]).await {
+                lists.subscribed.remove(&room_id);
+                lists.save(client).await?;
+                let message = format!("Could not join {}: {}", target, e);
+                send_reply(
+                    &message,
+                    &escape_html(&message),
+                    room,
+                    event.event_id.clone(),
+                )
+                .await?;
+                return Ok(());
+            }
+            send_reply(
+                &format!(
+                    "Subscribed to {}, its rules will be applied once its state arrives",
+                    room_id
+                ),
+                &format!(
+                    "Subscribed to <code>{}</code>, its rules will be applied once its state arrives",
+                    room_id
+                ),
+                room,
+                event.event_id.clone(),
+            )
+            .await?;
+            return Ok(());
+        }
+    };
+
+    // The bot was already in the room, so its state won't arrive again and is applied right away
+    let rules = policy::room_rules(&list).await?;
+    policy::update_index(|index| {
+        for rule in &rules {
+            index.insert(rule.clone());
+        }
+    });
+    let rooms = protected_rooms(client).await?;
+    let acl_results = apply_server_acls(client, config, &rooms, &rules).await?;
+    let rules = RuleIndex::new(rules);
+    let results = enforce_rules(client, config, &rooms, &rules).await?;
+    let (mut plain, mut html) = (
+        format!("Subscribed to {} with {} rules", room_id, rules.len()),
+        format!(
+            "<p>Subscribed to <code>{}</code> with {} rules</p>",
+            room_id,
+            rules.len()
+        ),
+    );
+    if !results.is_empty() {
+        let (results_plain, results_html) = format_results(&results);
+        plain.push_str(&format!("\n{}", results_plain));
+        html.push_str(&results_html);
+    }
+    if !acl_results.is_empty() {
+        let (acl_plain, acl_html) = format_acl_results(&acl_results);
+        plain.push_str(&format!("\n{}", acl_plain));
+        html.push_str(&acl_html);
+    }
+    send_reply(&plain, &html, room, event.event_id.clone()).await?;
+    Ok(())
+}
+
+/// Report what the rules of a policy list would do to the members and server ACLs of the
+/// protected rooms, as if in dry run mode.
+async fn command_list_simulate(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    arguments: &Arguments,
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    let target = arguments.room(0)?;
+    let room_id = match resolve_room_or_reply(event, room, client, &target).await? {
+        Some(room_id) => room_id,
+        None => return Ok(()),
+    };
+    let list = match client.get_joined_room(&room_id) {
+        Some(list) => list,
+        None => {
+            let (plain, html) = match client.join_room_by_id_or_alias(&target, &[]).await {
+                Ok(_) => (
+                    format!(
+                        "Joined {}, simulate it again once its state has arrived",
+                        room_id
+                    ),
+                    format!(
+                        "Joined <code>{}</code>, simulate it again once its state has arrived",
+                        room_id
+                    ),
+                ),
+                Err(e) => {
+                    let message = format!("Could not join {}: {}", target, e);
+                    (message.clone(), escape_html(&message))
+                }
+            };
+            send_reply(&plain, &html, room, event.event_id.clone()).await?;
+            return Ok(());
+        }
+    };
+
+    let mut simulation = config.clone();
+    simulation.bot.dry_run = true;
+    let rules = policy::room_rules(&list).await?;
+    let rooms = protected_rooms(client).await?;
+    let acl_results = apply_server_acls(client, &simulation, &rooms, &rules).await?;
+    let count = rules.len();
+    let results = enforce_rules(client, &simulation, &rooms, &RuleIndex::new(rules)).await?;
+    let (mut plain, mut html) = (
+        format!("Simulated {} rules from {}", count, room_id),
+        format!(
+            "<p>Simulated {} rules from <code>{}</code></p>",
+            count, room_id
+        ),
+    );
+    if results.is_empty() && acl_results.is_empty() {
+        plain.push_str(", they would not affect any protected room");
+        html = format!(
+            "Simulated {} rules from <code>{}</code>, they would not affect any protected room",
+            count, room_id
+        );
+    }
+    if !results.is_empty() {
+        let (results_plain, results_html) = format_results(&results);
+        plain.push_str(&format!("\n{}", results_plain));
+        html.push_str(&results_html);
+    }
+    if !acl_results.is_empty() {
+        let (acl_plain, acl_html) = format_acl_results(&acl_results);
+        plain.push_str(&format!("\n{}", acl_plain));
+        html.push_str(&acl_html);
+    }
+    send_reply(&plain, &html, room, event.event_id.clone()).await?;
+    Ok(())
+}
+
+/// Unsubscribe from a policy list, so that rules only found in it are no longer enforced, and
+/// lift what its rules caused as if they had been revoked.
+async fn command_list_unwatch(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    arguments: &Arguments,
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    let target = arguments.room(0)?;
+    let room_id = match resolve_room_or_reply(event, room, client, &target).await? {
+        Some(room_id) => room_id,
+        None => return Ok(()),
+    };
+    let mut lists = PolicyLists::load(client).await?;
+    if !lists.subscribed.remove(&room_id) {
+        let message = format!("Not subscribed to {}", room_id);
+        send_reply(
+            &message,
+            &escape_html(&message),
+            room,
+            event.event_id.clone(),
+        )
+        .await?;
+        return Ok(());
+    }
+    lists.save(client).await?;
+    policy::update_index(|index| index.remove_list(&room_id));
+    info!("Unsubscribed from policy list {}", room_id);
+    // Whatever the list's rules caused is lifted as if they had been revoked
+    let rules = RuleEnforcements::load(client).await?.rules_from(&room_id);
+    let (lifted_plain, lifted_html) = lift_revoked(client, config, &rules).await?;
+
+    // Stay in the room if it is in use for anything else
+    let in_use = ProtectedRooms::load(client).await?.rooms.contains(&room_id)
+        || WatchedRooms::load(client).await?.rooms.contains(&room_id);
+    if let (false, Some(list)) = (in_use, client.get_joined_room(&room_id)) {
+        if let Err(e) = list.leave().await {
+            warn!("Failed to leave policy list {}: {}", room_id, e);
+        }
+    }
+    let message = format!("Unsubscribed from {}", room_id);
+    send_reply(
+        &format!("{}{}", message, lifted_plain),
+        &format!("<p>{}</p>{}", escape_html(&message), lifted_html),
+        room,
+        event.event_id.clone(),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Kind of entity a rule command applies to, `user`, `server` or `room`.
+fn rule_kind(arguments: &Arguments) -> Result<&'static str, ParseError> {
+    let kind = arguments.required(0)?;
+    List::KINDS
+        .iter()
+        .find(|known| **known == kind)
+        .copied()
+        .ok_or_else(|| ParseError::InvalidArgument {
+            name: String::from("user|server|room"),
+            value: kind.to_owned(),
+            reason: String::from("expected user, server or room"),
+        })
+}
+
+/// Optional action at `index`.
+pub(crate) fn action_argument(
+    arguments: &Arguments,
+    index: usize,
+) -> Result<Option<Action>, ParseError> {
+    arguments
+        .get(index)
+        .map(|action| {
+            action
+                .parse::<Action>()
+                .map_err(|reason| ParseError::InvalidArgument {
+                    name: String::from("action"),
+                    value: action.to_owned(),
+                    reason,
+                })
+        })
+        .transpose()
+}
+
+/// The published policy list a rule command applies to, selected with `--list` or implicitly if
+/// only one list is published. Replies with the reason and returns `None` if there is none.
+async fn published_list(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    arguments: &Arguments,
+) -> Result<Option<Joined>, anyhow::Error> {
+    let published = PolicyLists::load(client).await?.published;
+    let room_id = match arguments.flag_value("list") {
+        Some(list) => {
+            let target = parser::parse_room("list", list)?;
+            match resolve_room_or_reply(event, room, client, &target).await? {
+                Some(room_id) => room_id,
+                None => return Ok(None),
+            }
+        }
+        None => {
+            let mut lists = published.iter();
+            let selected = match (lists.next(), lists.next()) {
+                (Some(only), None) => Ok(only.clone()),
+                (None, _) => Err((
+                    "No policy lists are published yet, create one with list create",
+                    "No policy lists are published yet, create one with <code>list create</code>",
+                )),
+                (Some(_), Some(_)) => Err((
+                    "Several policy lists are published, choose one with --list",
+                    "Several policy lists are published, choose one with <code>--list</code>",
+                )),
+            };
+            match selected {
+                Ok(room_id) => room_id,
+                Err((plain, html)) => {
+                    send_reply(plain, html, room, event.event_id.clone()).await?;
+                    return Ok(None);
+                }
+            }
+        }
+    };
+    let refusal = if !published.contains(&room_id) {
+        format!("{} is not a policy list published by the bot", room_id)
+    } else if let Some(list) = client.get_joined_room(&room_id) {
+        return Ok(Some(list));
+    } else {
+        format!("Not joined to policy list {}", room_id)
+    };
+    send_reply(
+        &refusal,
+        &escape_html(&refusal),
+        room,
+        event.event_id.clone(),
+    )
+    .await?;
+    Ok(None)
+}
+
+/// Resolve a room alias to the ID of the room it points to.
+async fn resolve_room(client: &Client, room: &RoomIdOrAliasId) -> Result<RoomId, anyhow::Error> {
+    if room.is_room_id() {
+        return Ok(RoomId::try_from(room.as_str())?);
+    }
+    let alias = RoomAliasId::try_from(room.as_str())?;
+    Ok(client
+        .send(get_alias::Request::new(&alias), None)
+        .await?
+        .room_id)
+}
+
+/// Redact a single event, or the most recent messages sent by a user.
+async fn command_redact(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    arguments: &Arguments,
+) -> Result<(), anyhow::Error> {
+    let target = match command_room(event, room, client, arguments).await? {
+        Some(target) => target,
+        None => return Ok(()),
+    };
+    let reason = arguments.flag_value("reason");
+    if arguments.required(0)?.starts_with('$') {
+        let event_id = arguments.event(0)?;
+        let (plain, html) = match target.redact(&event_id, reason, None).await {
+            Ok(_) => (
+                format!("Redacted {}", event_id),
+                format!("Redacted <code>{}</code>", escape_html(event_id.as_str())),
+            ),
+            Err(e) => (
+                format!("Failed to redact {}: {}", event_id, e),
+                format!(
+                    "Failed to redact <code>{}</code>: {}",
+                    escape_html(event_id.as_str()),
+                    escape_html(&e.to_string())
+                ),
+            ),
+        };
+        send_reply(&plain, &html, room, event.event_id.clone()).await?;
+        return Ok(());
+    }
+
+    let user = arguments.user(0)?;
+    let limit = match arguments.number::<usize>(1)? {
+        None => DEFAULT_REDACT_LIMIT,
+        Some(limit) if limit > 0 => limit,
+        Some(_) => {
+            send_reply(
+                "The limit must be a positive number",
+                "The limit must be a positive number",
+                room,
+                event.event_id.clone(),
+            )
+            .await?;
+            return Ok(());
+        }
+    };
+
+    let (redacted, failed) = redact_recent(client, &target, &user, limit, reason).await?;
+    let mut plain = format!(
+        "Redacted {} message(s) from {} in {}",
+        redacted,
+        user,
+        target.room_id()
+    );
+    let mut html = format!(
+        "Redacted {} message(s) from {} in <code>{}</code>",
+        redacted,
+        escape_html(user.as_str()),
+        target.room_id()
+    );
+    if !failed.is_empty() {
+        html.push_str("<ul>");
+        for (event_id, error) in failed {
+            plain.push_str(&format!("\nFailed to redact {}: {}", event_id, error));
+            html.push_str(&format!(
+                "<li>Failed to redact <code>{}</code>: {}</li>",
+                escape_html(event_id.as_str()),
+                escape_html(&error)
+            ));
+        }
+        html.push_str("</ul>");
+    }
+    send_reply(&plain, &html, room, event.event_id.clone()).await?;
+    Ok(())
+}
+
+/// Redact up to `limit` of the most recent messages sent by `user`, returning the number of
+/// messages redacted along with the ones that couldn't be.
+async fn redact_recent(
+    client: &Client,
+    room: &Joined,
+    user: &UserId,
+    limit: usize,
+    reason: Option<&str>,
+) -> Result<(usize, Vec<(EventId, String)>), anyhow::Error> {
+    let mut redacted = 0;
+    let mut failed = Vec::new();
+    for event_id in recent_messages(client, room, user, limit).await? {
+        match room.redact(&event_id, reason, None).await {
+            Ok(_) => redacted += 1,
+            Err(e) => {
+                warn!("Failed to redact {} in {}: {}", event_id, room.room_id(), e);
+                failed.push((event_id, e.to_string()));
+            }
+        }
+    }
+    Ok((redacted, failed))
+}
+
+/// Number of messages `redact` removes when no limit is given.
+const DEFAULT_REDACT_LIMIT: usize = 25;
+
+/// Page through the room timeline backwards, collecting the IDs of up to `limit` unredacted
+/// messages sent by `user`.
+async fn recent_messages(
+    client: &Client,
+    room: &Joined,
+    user: &UserId,
+    limit: usize,
+) -> Result<Vec<EventId>, anyhow::Error> {
+    let mut from = match client.sync_token().await.or_else(|| room.last_prev_batch()) {
+        Some(token) => token,
+        None => return Err(anyhow::anyhow!("No pagination token available")),
+    };
+    let senders = [user.clone()];
+    let filter = assign!(RoomEventFilter::empty(), { senders: Some(&senders) });
+    let mut event_ids = Vec::new();
+    while event_ids.len() < limit {
+        let request = assign!(MessagesRequest::backward(room.room_id(), &from), {
+            limit: UInt::from(50_u32),
+            filter: Some(filter.clone()),
+        });
+        let response = room.messages(request).await?;
+        for raw in &response.chunk {
+            // Redacted events and state events like memberships are left alone
+            if let Ok(AnyRoomEvent::Message(message)) = raw.deserialize() {
+                if message.sender() == user
+                    && !matches!(message, AnyMessageEvent::RoomRedaction(_))
+                    && event_ids.len() < limit
+                {
+                    event_ids.push(message.event_id().clone());
+                }
+            }
+        }
+        match response.end {
+            Some(end) if !response.chunk.is_empty() && end != from => from = end,
+            _ => break,
+        }
+    }
+    Ok(event_ids)
+}
+
+/// Users currently banned from the room.
+async fn banned_users(room: &Joined) -> Result<Vec<UserId>, anyhow::Error> {
+    Ok(room
+        .get_state_events(EventType::RoomMember)
+        .await?
+        .iter()
+        .filter_map(|raw| {
+            raw.deserialize_as::<SyncStateEvent<MemberEventContent>>()
+                .ok()
+        })
+        .filter(|member| member.content.membership == MembershipState::Ban)
+        .filter_map(|member| UserId::try_from(member.state_key).ok())
+        .collect())
+}
+
+/// Mute a user by lowering their power level below what is required to send messages, optionally
+/// lifting the mute again after the given duration. Temporary mutes survive restarts of the bot.
+async fn command_mute(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    arguments: &Arguments,
+) -> Result<(), anyhow::Error> {
+    let user = arguments.user(0)?;
+    let duration = arguments.duration(1)?;
+    let target = match command_room(event, room, client, arguments).await? {
+        Some(target) => target,
+        None => return Ok(()),
+    };
+    // As with changing power levels, moderators can only mute users below their own level
+    let power_levels = power_levels(&target).await?;
+    let invoker_level = user_power_level(&power_levels, &event.sender);
+    let current = user_power_level(&power_levels, &user);
+    if user != event.sender && current >= invoker_level {
+        let refusal = format!(
+            "{} has power level {}, which is not below your power level {}",
+            user, current, invoker_level
+        );
+        send_reply(
+            &refusal,
+            &escape_html(&refusal),
+            room,
+            event.event_id.clone(),
+        )
+        .await?;
+        return Ok(());
+    }
+
+    let (plain, html) = match mute_user(client, &target, &user).await {
+        Ok(None) => (
+            format!("{} is already muted in {}", user, target.room_id()),
+            format!(
+                "{} is already muted in <code>{}</code>",
+                escape_html(user.as_str()),
+                target.room_id()
+            ),
+        ),
+        Ok(Some(change)) => {
+            // A new mute replaces any temporary mute
+            Timers::update(|timers| match duration {
+                Some(duration) => timers.add(Timer::new(
+                    TimerKind::Unmute,
+                    target.room_id().clone(),
+                    user.clone(),
+                    duration,
+                )),
+                None => {
+                    timers.remove(TimerKind::Unmute, target.room_id(), &user);
+                }
+            })?;
+            if let Some(duration) = duration {
+                (
+                    format!("{} for {}", change, format_duration(duration)),
+                    format!(
+                        "{} for {}",
+                        escape_html(&change.to_string()),
+                        format_duration(duration)
+                    ),
+                )
+            } else {
+                (change.to_string(), escape_html(&change.to_string()))
+            }
+        }
+        Err(e) => (
+            format!("Could not mute {}: {}", user, e),
+            format!(
+                "Could not mute {}: {}",
+                escape_html(user.as_str()),
+                escape_html(&e.to_string())
+            ),
+        ),
+    };
+    send_reply(&plain, &html, room, event.event_id.clone()).await?;
+    Ok(())
+}
+
+/// Lift a mute by restoring the user's ability to send messages.
+async fn command_unmute(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    arguments: &Arguments,
+) -> Result<(), anyhow::Error> {
+    let user = arguments.user(0)?;
+    let target = match command_room(event, room, client, arguments).await? {
+        Some(target) => target,
+        None => return Ok(()),
+    };
+    let (plain, html) = match unmute_user(client, &target, &user).await {
+        Ok(change) => {
+            Timers::update(|timers| timers.remove(TimerKind::Unmute, target.room_id(), &user))?;
+            match change {
+                Some(change) => (change.to_string(), escape_html(&change.to_string())),
+                None => (
+                    format!("{} is not muted in {}", user, target.room_id()),
+                    format!(
+                        "{} is not muted in <code>{}</code>",
+                        escape_html(user.as_str()),
+                        target.room_id()
+                    ),
+                ),
+            }
+        }
+        Err(e) => (
+            format!("Could not unmute {}: {}", user, e),
+            format!(
+                "Could not unmute {}: {}",
+                escape_html(user.as_str()),
+                escape_html(&e.to_string())
+            ),
+        ),
+    };
+    send_reply(&plain, &html, room, event.event_id.clone()).await?;
+    Ok(())
+}
+
+/// Show the room's power levels, or change the power level of a single user.
+async fn command_powerlevel(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    arguments: &Arguments,
+) -> Result<(), anyhow::Error> {
+    let target = match command_room(event, room, client, arguments).await? {
+        Some(target) => target,
+        None => return Ok(()),
+    };
+    if arguments.required(0)? == "show" {
+        let (plain, html) = format_power_levels(&power_levels(&target).await?);
+        send_reply(&plain, &html, room, event.event_id.clone()).await?;
+        return Ok(());
+    }
+    let user = arguments.user(0)?;
+    let level = match arguments.number::<i64>(1)?.and_then(Int::new) {
+        Some(level) => i64::from(level),
+        None => {
+            return Err(ParseError::MissingArgument("level".to_owned()).into());
+        }
+    };
+    change_power_level(event, room, &target, &user, level, arguments.flag("yes")).await
+}
+
+/// Change the power level of `user` in `target` on behalf of the sender of `event`. Unless
+/// `confirmed` is set, only the diff is shown, and the command has to be sent again with `--yes`
+/// to apply it.
+async fn change_power_level(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    target: &Joined,
+    user: &UserId,
+    level: i64,
+    confirmed: bool,
+) -> Result<(), anyhow::Error> {
+    let power_levels = power_levels(target).await?;
+    let invoker_level = user_power_level(&power_levels, &event.sender);
+    let current = user_power_level(&power_levels, user);
+    let refusal = if current == level {
+        Some(format!("{} already has power level {}", user, level))
+    } else if user != &event.sender && current >= invoker_level {
+        Some(format!(
+            "{} has power level {}, which is not below your power level {}",
+            user, current, invoker_level
+        ))
+    } else if level > invoker_level {
+        Some(format!(
+            "Power level {} is above your power level {}",
+            level, invoker_level
+        ))
+    } else {
+        check_can_change_power_level(target, &power_levels, user, level)
+            .err()
+            .map(|e| format!("Could not change power level of {}: {}", user, e))
+    };
+    if let Some(refusal) = refusal {
+        send_reply(
+            &refusal,
+            &escape_html(&refusal),
+            room,
+            event.event_id.clone(),
+        )
+        .await?;
+        return Ok(());
+    }
+
+    let diff = format!("{}: {} -> {}", user, current, level);
+    let html_diff = format!(
+        "<pre><code>- {}: {}\n+ {}: {}</code></pre>",
+        escape_html(user.as_str()),
+        current,
+        escape_html(user.as_str()),
+        level
+    );
+    if !confirmed {
+        send_reply(
+            &format!(
+                "Would change in {}:\n{}\nSend the command again with --yes to apply it",
+                target.room_id(),
+                diff
+            ),
+            &format!(
+                "Would change in <code>{}</code>:{}<p>Send the command again with <code>--yes</code> to apply it</p>",
+                target.room_id(),
+                html_diff
+            ),
+            room,
+            event.event_id.clone(),
+        )
+        .await?;
+        return Ok(());
+    }
+    send_reply(
+        &format!("Applying in {}:\n{}", target.room_id(), diff),
+        &format!(
+            "Applying in <code>{}</code>:{}",
+            target.room_id(),
+            html_diff
+        ),
+        room,
+        event.event_id.clone(),
+    )
+    .await?;
+    let (plain, html) = match set_power_level(target, power_levels, user, level).await {
+        Ok(change) => (change.to_string(), escape_html(&change.to_string())),
+        Err(e) => (
+            format!("Could not change power level of {}: {}", user, e),
+            format!(
+                "Could not change power level of {}: {}",
+                escape_html(user.as_str()),
+                escape_html(&e.to_string())
+            ),
+        ),
+    };
+    send_reply(&plain, &html, room, event.event_id.clone()).await?;
+    Ok(())
+}
+
+/// Format the interesting parts of `m.room.power_levels` as plain text and HTML.
+fn format_power_levels(power_levels: &PowerLevelsEventContent) -> (String, String) {
+    let defaults = [
+        ("users_default", power_levels.users_default),
+        ("events_default", power_levels.events_default),
+        ("state_default", power_levels.state_default),
+        ("ban", power_levels.ban),
+        ("kick", power_levels.kick),
+        ("redact", power_levels.redact),
+        ("invite", power_levels.invite),
+    ];
+    let mut users: Vec<(&UserId, &Int)> = power_levels.users.iter().collect();
+    users.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+
+    let mut plain = String::from("Power levels:");
+    let mut html = String::from("<p>Power levels:</p><ul>");
+    for (name, level) in &defaults {
+        plain.push_str(&format!("\n{}: {}", name, level));
+        html.push_str(&format!("<li><code>{}</code>: {}</li>", name, level));
+    }
+    html.push_str("</ul><p>Users:</p><ul>");
+    plain.push_str("\nUsers:");
+    for (user, level) in users {
+        plain.push_str(&format!("\n{}: {}", user, level));
+        html.push_str(&format!(
+            "<li>{}: {}</li>",
+            escape_html(user.as_str()),
+            level
+        ));
+    }
+    html.push_str("</ul>");
+    (plain, html)
+}
+
+/// A change to a single user's power level.
+#[derive(Debug)]
+struct PowerLevelChange {
+    /// The room the change was applied in.
+    room_id: RoomId,
+    /// The user whose power level was changed.
+    user: UserId,
+    /// Power level before the change.
+    old: i64,
+    /// Power level after the change.
+    new: i64,
+}
+
+impl std::fmt::Display for PowerLevelChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Changed power level of {} in {} from {} to {}",
+            self.user, self.room_id, self.old, self.new
+        )
+    }
+}
+
+/// Current `m.room.power_levels` content of the room, or the spec defaults if there is none.
+pub(crate) async fn power_levels(room: &Joined) -> Result<PowerLevelsEventContent, anyhow::Error> {
+    Ok(
+        match room.get_state_event(EventType::RoomPowerLevels, "").await? {
+            Some(raw) => {
+                raw.deserialize_as::<SyncStateEvent<PowerLevelsEventContent>>()?
+                    .content
+            }
+            None => PowerLevelsEventContent::default(),
+        },
+    )
+}
+
+/// Power level of `user` according to `power_levels`.
+fn user_power_level(power_levels: &PowerLevelsEventContent, user: &UserId) -> i64 {
+    power_levels
+        .users
+        .get(user)
+        .copied()
+        .unwrap_or(power_levels.users_default)
+        .into()
+}
+
+/// Whether `user` has a power level that lets them kick or ban others, which puts them out of
+/// reach of automatic actions.
+fn has_moderator_level(power_levels: &PowerLevelsEventContent, user: &UserId) -> bool {
+    user_power_level(power_levels, user) >= i64::from(power_levels.kick.min(power_levels.ban))
+}
+
+/// Power level required to send events of `event_type`.
+fn event_power_level(
+    power_levels: &PowerLevelsEventContent,
+    event_type: &EventType,
+    state: bool,
+) -> i64 {
+    power_levels
+        .events
+        .get(event_type)
+        .copied()
+        .unwrap_or(if state {
+            power_levels.state_default
+        } else {
+            power_levels.events_default
+        })
+        .into()
+}
+
+/// Ensure the bot is allowed to change the power level of `user` from its current value.
+fn check_can_change_power_level(
+    room: &Joined,
+    power_levels: &PowerLevelsEventContent,
+    user: &UserId,
+    new: i64,
+) -> Result<(), anyhow::Error> {
+    let own_level = user_power_level(power_levels, room.own_user_id());
+    let required = event_power_level(power_levels, &EventType::RoomPowerLevels, true);
+    if own_level < required {
+        anyhow::bail!(
+            "the bot has power level {} but changing power levels requires {}",
+            own_level,
+            required
+        );
+    }
+    let current = user_power_level(power_levels, user);
+    if current >= own_level {
+        anyhow::bail!(
+            "{} has power level {}, which is not below the bot's power level {}",
+            user,
+            current,
+            own_level
+        );
+    }
+    if new > own_level {
+        anyhow::bail!(
+            "power level {} is above the bot's power level {}",
+            new,
+            own_level
+        );
+    }
+    Ok(())
+}
+
+/// Set the power level of `user` in the room to `new`, removing the override if it matches
+/// `users_default`.
+async fn set_power_level(
+    room: &Joined,
+    mut power_levels: PowerLevelsEventContent,
+    user: &UserId,
+    new: i64,
+) -> Result<PowerLevelChange, anyhow::Error> {
+    let old = user_power_level(&power_levels, user);
+    if new == i64::from(power_levels.users_default) {
+        power_levels.users.remove(user);
+    } else {
+        let level = Int::new(new).ok_or_else(|| anyhow::anyhow!("invalid power level {}", new))?;
+        power_levels.users.insert(user.clone(), level);
+    }
+    room.send_state_event(AnyStateEventContent::RoomPowerLevels(power_levels), "")
+        .await?;
+    Ok(PowerLevelChange {
+        room_id: room.room_id().clone(),
+        user: user.clone(),
+        old,
+        new,
+    })
+}
+
+/// Lower the power level of `user` until they can no longer send messages. Returns `None` if
+/// they already can't.
+async fn mute_user(
+    client: &Client,
+    room: &Joined,
+    user: &UserId,
+) -> Result<Option<PowerLevelChange>, anyhow::Error> {
+    quarantine_user(client, room, user, None).await
+}
+
+/// Highest power level at which users can no longer send messages.
+fn muted_power_level(power_levels: &PowerLevelsEventContent) -> i64 {
+    event_power_level(power_levels, &EventType::RoomMessage, false)
+        .min(power_levels.events_default.into())
+        - 1
+}
+
+/// `power_levels` of `room` as they were before a lockdown restricted messages, so that users
+/// the lockdown silences aren't taken for muted.
+async fn unrestricted_power_levels(
+    client: &Client,
+    room: &Joined,
+    power_levels: &PowerLevelsEventContent,
+) -> Result<PowerLevelsEventContent, anyhow::Error> {
+    let mut unrestricted = power_levels.clone();
+    if let Some(events_default) = Lockdowns::load(client)
+        .await?
+        .rooms
+        .get(room.room_id())
+        .and_then(|lockdown| lockdown.events_default)
+    {
+        unrestricted.events_default = Int::new(events_default)
+            .ok_or_else(|| anyhow::anyhow!("invalid power level {}", events_default))?;
+    }
+    Ok(unrestricted)
+}
+
+/// Restrict `user` to `level` until a moderator reviews them, or to the muted power level if no
+/// level is given. The level they had before is recorded so that unmuting them restores it.
+/// Returns `None` if they are already restricted to it.
+async fn quarantine_user(
+    client: &Client,
+    room: &Joined,
+    user: &UserId,
+    level: Option<i64>,
+) -> Result<Option<PowerLevelChange>, anyhow::Error> {
+    let power_levels = power_levels(room).await?;
+    let unrestricted = unrestricted_power_levels(client, room, &power_levels).await?;
+    let quarantined = level.unwrap_or_else(|| muted_power_level(&unrestricted));
+    let current = user_power_level(&power_levels, user);
+    if current <= quarantined {
+        return Ok(None);
+    }
+    check_can_change_power_level(room, &power_levels, user, quarantined)?;
+    let change = set_power_level(room, power_levels, user, quarantined).await?;
+    // Users who could still send messages have been unmuted since any level recorded earlier
+    let unmuted = current >= event_power_level(&unrestricted, &EventType::RoomMessage, false);
+    if let Err(e) = Mutes::update(client, |mutes| {
+        if unmuted {
+            mutes.remove(room.room_id(), user);
+        }
+        mutes.add(room.room_id().clone(), user.clone(), current)
+    })
+    .await
+    {
+        warn!(
+            "Could not record the power level {} had in {}: {}",
+            user,
+            room.room_id(),
+            e
+        );
+    }
+    Ok(Some(change))
+}
+
+/// Raise the power level of a muted `user` back to the level they had before, or to the room
+/// default if it wasn't recorded, and at least to the level required to send messages. Returns
+/// `None` if they aren't muted.
+async fn unmute_user(
+    client: &Client,
+    room: &Joined,
+    user: &UserId,
+) -> Result<Option<PowerLevelChange>, anyhow::Error> {
+    let power_levels = power_levels(room).await?;
+    let required = event_power_level(
+        &unrestricted_power_levels(client, room, &power_levels).await?,
+        &EventType::RoomMessage,
+        false,
+    );
+    let _lock = Mutes::lock().await;
+    let mut mutes = Mutes::load(client).await?;
+    let previous = mutes.remove(room.room_id(), user);
+    let change = if user_power_level(&power_levels, user) >= required {
+        None
+    } else {
+        let unmuted = previous
+            .unwrap_or_else(|| power_levels.users_default.into())
+            .max(required);
+        check_can_change_power_level(room, &power_levels, user, unmuted)?;
+        Some(set_power_level(room, power_levels, user, unmuted).await?)
+    };
+    if previous.is_some() {
+        if let Err(e) = mutes.save(client).await {
+            warn!(
+                "Could not forget the power level {} had in {}: {}",
+                user,
+                room.room_id(),
+                e
+            );
+        }
+    }
+    Ok(change)
+}
+
+/// Format a duration roughly, in at most the two largest units it spans, e.g. `6d 23h`.
+pub(crate) fn format_remaining(duration: Duration) -> String {
+    let mut seconds = duration.as_secs();
+    let mut parts = Vec::new();
+    for (size, unit) in [(60 * 60 * 24, 'd'), (60 * 60, 'h'), (60, 'm'), (1, 's')] {
+        if seconds >= size && parts.len() < 2 {
+            parts.push(format!("{}{}", seconds / size, unit));
+            seconds %= size;
+        } else if !parts.is_empty() {
+            // Skip smaller units once a gap has been left, so `1d 0h 5m` shows as `1d`
+            break;
+        }
+    }
+    if parts.is_empty() {
+        String::from("0s")
+    } else {
+        parts.join(" ")
+    }
+}
+
+/// Format a duration in the largest whole unit accepted by [`parser::parse_duration`].
+pub(crate) fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    [
+        (60 * 60 * 24 * 7, 'w'),
+        (60 * 60 * 24, 'd'),
+        (60 * 60, 'h'),
+        (60, 'm'),
+    ]
+    .iter()
+    .find(|(size, _)| seconds.is_multiple_of(*size))
+    .map_or_else(
+        || format!("{}s", seconds),
+        |(size, unit)| format!("{}{}", seconds / size, unit),
+    )
+}
+
+/// Outcome of applying an action to a set of users in a single room.
+#[derive(Debug)]
+struct RoomResult {
+    /// The room the action was applied in.
+    room_id: RoomId,
+    /// Past tense of the action, used when reporting the result.
+    verb: &'static str,
+    /// Users the action was successfully applied to.
+    succeeded: Vec<UserId>,
+    /// Users the action failed for, along with the error returned by the homeserver.
+    failed: Vec<(UserId, String)>,
+    /// Error that prevented the action from being applied in the room at all.
+    error: Option<String>,
+}
+
+impl RoomResult {
+    /// Record the outcome of applying the action to `user`.
+    fn record(&mut self, user: &UserId, response: Result<(), anyhow::Error>) {
+        match response {
+            Ok(()) => self.succeeded.push(user.clone()),
+            Err(e) => {
+                warn!(
+                    "Failed to apply {} to {} in {}: {}",
+                    self.verb, user, self.room_id, e
+                );
+                self.failed.push((user.clone(), e.to_string()));
+            }
+        }
+    }
+}
+
+/// Apply `action` to each of `users` in each of `rooms`, collecting the results per room.
+async fn apply_actions(
+    client: &Client,
+    config: &Config,
+    rooms: &[Joined],
+    users: &[UserId],
+    action: &Action,
+    reason: Option<&str>,
+) -> Vec<RoomResult> {
+    let mut results = Vec::with_capacity(rooms.len());
+    for room in rooms {
+        let mut result = RoomResult {
+            room_id: room.room_id().clone(),
+            verb: action.past_tense(),
+            succeeded: Vec::new(),
+            failed: Vec::new(),
+            error: None,
+        };
+        for user in users {
+            let response = apply_action(client, config, room, user, action, reason).await;
+            result.record(user, response);
+        }
+        results.push(result);
+    }
+    results
+}
+
+/// Apply `action` to `user` in `room`.
+async fn apply_action(
+    client: &Client,
+    config: &Config,
+    room: &Joined,
+    user: &UserId,
+    action: &Action,
+    reason: Option<&str>,
+) -> Result<(), anyhow::Error> {
+    match action {
+        Action::Ban => room.ban_user(user, reason).await?,
+        Action::Kick => room.kick_user(user, reason).await?,
+        // Users who are already muted or quarantined are left as they are
+        Action::Mute => match mute_user(client, room, user).await? {
+            Some(change) => info!("{}", change),
+            None => debug!("{} is already muted in {}", user, room.room_id()),
+        },
+        Action::Redact | Action::RedactAndBan => {
+            let (redacted, failed) =
+                redact_recent(client, room, user, DEFAULT_REDACT_LIMIT, reason).await?;
+            info!(
+                "Redacted {} message(s) from {} in {}, {} failed",
+                redacted,
+                user,
+                room.room_id(),
+                failed.len()
+            );
+            if action == &Action::RedactAndBan {
+                room.ban_user(user, reason).await?;
+            }
+        }
+        // The results report asks moderators to review quarantined users
+        Action::Quarantine => {
+            match quarantine_user(client, room, user, config.bot.quarantine_power_level).await? {
+                Some(change) => info!("{}", change),
+                None => debug!("{} is already quarantined in {}", user, room.room_id()),
+            }
+        }
+        // Warnings are the results report callers send to the management room
+        Action::Warn => {}
+    }
+    Ok(())
+}
+
+/// Lift the bans of each of `users` in each of `rooms`, collecting the results per room.
+async fn unban_users(client: &Client, rooms: &[Joined], users: &[UserId]) -> Vec<RoomResult> {
+    let mut results = Vec::with_capacity(rooms.len());
+    for room in rooms {
+        let mut result = RoomResult {
+            room_id: room.room_id().clone(),
+            verb: "Unbanned",
+            succeeded: Vec::new(),
+            failed: Vec::new(),
+            error: None,
+        };
+        for user in users {
+            let response = client
+                .send(unban_user::Request::new(room.room_id(), user), None)
+                .await
+                .map(|_| ())
+                .map_err(Into::into);
+            result.record(user, response);
+        }
+        results.push(result);
+    }
+    results
+}
+
+/// Format per-room results into a plain text and HTML summary.
+fn format_results(results: &[RoomResult]) -> (String, String) {
+    let mut plain = String::new();
+    let mut html = String::from("<ul>");
+    for result in results {
+        if !result.succeeded.is_empty() {
+            let users = result
+                .succeeded
+                .iter()
+                .map(UserId::as_str)
+                .collect::<Vec<&str>>()
+                .join(", ");
+            plain.push_str(&format!(
+                "{} in {}: {}\n",
+                result.verb, result.room_id, users
+            ));
+            html.push_str(&format!(
+                "<li>{} in <code>{}</code>: {}</li>",
+                result.verb,
+                result.room_id,
+                escape_html(&users)
+            ));
+        }
+        for (user, error) in &result.failed {
+            plain.push_str(&format!(
+                "Failed in {} for {}: {}\n",
+                result.room_id, user, error
+            ));
+            html.push_str(&format!(
+                "<li>Failed in <code>{}</code> for {}: {}</li>",
+                result.room_id,
+                escape_html(user.as_str()),
+                escape_html(error)
+            ));
+        }
+        if let Some(error) = &result.error {
+            plain.push_str(&format!("Failed in {}: {}\n", result.room_id, error));
+            html.push_str(&format!(
+                "<li>Failed in <code>{}</code>: {}</li>",
+                result.room_id,
+                escape_html(error)
+            ));
+        }
+    }
+    html.push_str("</ul>");
+    (plain.trim_end().to_owned(), html)
+}
+
+/// Outcome of updating the server ACL of a single room.
+#[derive(Debug)]
+struct AclResult {
+    /// The room whose ACL was updated.
+    room_id: RoomId,
+    /// Entries added to the deny list.
+    denied: Vec<String>,
+    /// Entries removed from the deny list.
+    lifted: Vec<String>,
+    /// The error returned by the homeserver, if the update failed.
+    error: Option<String>,
+    /// Whether the update was only worked out in dry run mode, and not sent.
+    dry_run: bool,
+}
+
+impl AclResult {
+    /// Result of an update that was worked out in dry run mode, but not sent.
+    fn dry_run(room: &Joined, update: AclUpdate) -> Self {
+        Self {
+            room_id: room.room_id().clone(),
+            denied: update.denied,
+            lifted: update.lifted,
+            error: None,
+            dry_run: true,
+        }
+    }
+}
+
+/// Format server ACL updates into a plain text and HTML summary.
+fn format_acl_results(results: &[AclResult]) -> (String, String) {
+    let mut plain = String::new();
+    let mut html = String::from("<ul>");
+    for result in results {
+        if let Some(error) = &result.error {
+            plain.push_str(&format!(
+                "Failed to update server ACL in {}: {}\n",
+                result.room_id, error
+            ));
+            html.push_str(&format!(
+                "<li>Failed to update server ACL in <code>{}</code>: {}</li>",
+                result.room_id,
+                escape_html(error)
+            ));
+        }
+        let verbs = if result.dry_run {
+            ("would deny", "would no longer deny")
+        } else {
+            ("now denies", "no longer denies")
+        };
+        for (verb, servers) in [(verbs.0, &result.denied), (verbs.1, &result.lifted)] {
+            if servers.is_empty() {
+                continue;
+            }
+            let servers = servers.join(", ");
+            plain.push_str(&format!(
+                "Server ACL in {} {} {}\n",
+                result.room_id, verb, servers
+            ));
+            html.push_str(&format!(
+                "<li>Server ACL in <code>{}</code> {} {}</li>",
+                result.room_id,
+                verb,
+                escape_html(&servers)
+            ));
+        }
+    }
+    html.push_str("</ul>");
+    (plain.trim_end().to_owned(), html)
+}
+
+/// Escape text for safe inclusion in `formatted_body`.
+pub(crate) fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Send an `m.notice` to the management room.
+pub(crate) async fn notify_management(
+    client: &Client,
+    config: &Config,
+    plain: &str,
+    html: &str,
+) -> Result<(), anyhow::Error> {
+    let room = client
+        .get_joined_room(&config.bot.management_room)
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "Not joined to management room {}",
+                config.bot.management_room
+            )
+        })?;
+    let content =
+        AnyMessageEventContent::RoomMessage(MessageEventContent::notice_html(plain, html));
+    room.send(content, None).await?;
+    Ok(())
+}
+
+/// Send `m.notice` reply to user.
+pub(crate) async fn send_reply(
+    plain: &str,
+    html: &str,
+    room: &Joined,
+    event_id: EventId,
+) -> Result<(), anyhow::Error> {
+    let mut notice = MessageEventContent::notice_html(plain, html);
+    notice.relates_to = Some(Relation::Reply {
+        in_reply_to: InReplyTo::new(event_id),
+    });
+    let content = AnyMessageEventContent::RoomMessage(notice);
+    room.send(content, None).await?;
+    Ok(())
+}
+
+// Inspired by the AutoJoin example in matrix-rust-sdk
+/// Handles incoming invites.
+async fn accept_invite(
+    event: &StrippedStateEvent<MemberEventContent>,
+    room: &Room,
+    client: &Client,
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    if let Room::Invited(room) = room {
+        // Invites to the management room itself are always accepted, as are invites from
+        // moderators and explicitly allowed users
+        if room.room_id() != &config.bot.management_room
+            && !config
+                .bot
+                .allow_invites
+                .iter()
+                .any(|user| user == &event.sender)
+            && !is_moderator(client, config, &event.sender).await?
         {
             info!(
                 "Unauthorized user {} tried to invite bot to {}",
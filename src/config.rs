@@ -5,7 +5,7 @@
 //! Configuration related functionality.
 
 use anyhow::Result;
-use matrix_sdk::ruma::UserId;
+use matrix_sdk::ruma::{RoomId, UserId};
 use serde::{Deserialize, Serialize};
 use std::os::unix::fs::PermissionsExt;
 use std::path::PathBuf;
@@ -103,4 +103,29 @@ pub struct Bot {
     pub command_prefix: String,
     /// Collection of users the bot will accept invites from.
     pub allow_invites: Vec<UserId>,
+    /// Room used to manage the bot. Commands are only accepted in this room, only its members
+    /// may run moderation commands, and they may also invite the bot to other rooms.
+    ///
+    /// This setting is required, configurations without it fail to load.
+    pub management_room: RoomId,
+    /// Minimum power level in the management room required to run moderation commands. If
+    /// unset, all joined members of the management room may do so.
+    #[serde(default)]
+    pub management_power_level: Option<i64>,
+    /// Power level users are restricted to by the quarantine action until a moderator has
+    /// reviewed them. If unset, quarantined users are lowered until they can no longer send
+    /// messages.
+    #[serde(default)]
+    pub quarantine_power_level: Option<i64>,
+    /// Whether to lift bans applied because of a policy list rule once the rule is revoked.
+    #[serde(default)]
+    pub unban_on_revoke: bool,
+    /// Whether to remove server ACL entries denied because of a policy list rule once the rule
+    /// is revoked.
+    #[serde(default)]
+    pub relax_acl_on_revoke: bool,
+    /// Whether to only report what policy list rules would do, without banning anyone or
+    /// changing any server ACLs.
+    #[serde(default)]
+    pub dry_run: bool,
 }
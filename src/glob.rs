@@ -0,0 +1,232 @@
+// Clobber - a matrix moderation bot
+// Copyright (C) 2020 Emelie <em@nao.sh>
+// Licensed under the EUPL
+
+//! Glob matching for user IDs and server names, where `*` matches any number of characters and
+//! `?` matches exactly one.
+
+use std::collections::HashMap;
+
+/// A compiled glob pattern.
+#[derive(Clone, Debug)]
+pub struct Glob {
+    /// The pattern as given.
+    pattern: String,
+    /// Characters of the pattern, with runs of `*` collapsed into one.
+    chars: Vec<char>,
+}
+
+impl Glob {
+    /// Compile a glob pattern.
+    #[must_use]
+    pub fn new(pattern: &str) -> Self {
+        let mut chars: Vec<char> = Vec::with_capacity(pattern.len());
+        for c in pattern.chars() {
+            if c != '*' || chars.last() != Some(&'*') {
+                chars.push(c);
+            }
+        }
+        Self {
+            pattern: pattern.to_owned(),
+            chars,
+        }
+    }
+
+    /// The pattern as given.
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Whether the pattern contains no wildcards, and so only matches itself.
+    #[must_use]
+    pub fn is_literal(&self) -> bool {
+        !self.chars.iter().any(|&c| is_wildcard(c))
+    }
+
+    /// Whether the pattern matches all of `candidate`.
+    #[must_use]
+    pub fn is_match(&self, candidate: &str) -> bool {
+        let candidate: Vec<char> = candidate.chars().collect();
+        self.matches_chars(&candidate)
+    }
+
+    /// Literal text the pattern starts with, up to the first wildcard.
+    fn literal_prefix(&self) -> String {
+        self.chars
+            .iter()
+            .take_while(|&&c| !is_wildcard(c))
+            .collect()
+    }
+
+    /// Literal text the pattern ends with, after the last wildcard.
+    fn literal_suffix(&self) -> String {
+        let start = self
+            .chars
+            .iter()
+            .rposition(|&c| is_wildcard(c))
+            .map_or(0, |i| i + 1);
+        self.chars[start..].iter().collect()
+    }
+
+    /// Match against a candidate that has already been split into characters.
+    fn matches_chars(&self, candidate: &[char]) -> bool {
+        let pattern = &self.chars;
+        let (mut p, mut c) = (0, 0);
+        // Position of the last `*` seen in the pattern, and the candidate position it was matched
+        // at. Only the last one needs to be remembered, as any earlier `*` can only ever have to
+        // match less than it already does.
+        let mut backtrack: Option<(usize, usize)> = None;
+        while c < candidate.len() {
+            if p < pattern.len() && (pattern[p] == '?' || pattern[p] == candidate[c]) {
+                p += 1;
+                c += 1;
+            } else if p < pattern.len() && pattern[p] == '*' {
+                backtrack = Some((p, c));
+                p += 1;
+            } else if let Some((star, matched)) = backtrack {
+                p = star + 1;
+                c = matched + 1;
+                backtrack = Some((star, matched + 1));
+            } else {
+                return false;
+            }
+        }
+        pattern[p..].iter().all(|&c| c == '*')
+    }
+}
+
+/// Whether `c` is a wildcard character.
+const fn is_wildcard(c: char) -> bool {
+    c == '*' || c == '?'
+}
+
+/// A set of glob patterns, each associated with a value.
+///
+/// Patterns are indexed by their literal text, so that finding the patterns matching a candidate
+/// only needs to try the few patterns that can possibly match rather than every pattern in the
+/// set. Only patterns that both start and end with a wildcard are tried one by one.
+#[derive(Clone, Debug)]
+pub struct GlobSet<T> {
+    /// Patterns along with their values, in insertion order.
+    entries: Vec<(Glob, T)>,
+    /// Patterns without wildcards, by their text.
+    exact: HashMap<String, Vec<usize>>,
+    /// Patterns ending in literal text, by that text.
+    suffixes: HashMap<String, Vec<usize>>,
+    /// Patterns ending in a wildcard but starting with literal text, by that text.
+    prefixes: HashMap<String, Vec<usize>>,
+    /// Patterns starting and ending with a wildcard.
+    other: Vec<usize>,
+}
+
+impl<T> Default for GlobSet<T> {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+            exact: HashMap::new(),
+            suffixes: HashMap::new(),
+            prefixes: HashMap::new(),
+            other: Vec::new(),
+        }
+    }
+}
+
+impl<T> GlobSet<T> {
+    /// Create an empty set.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a pattern to the set.
+    pub fn insert(&mut self, pattern: &str, value: T) {
+        let glob = Glob::new(pattern);
+        let index = self.entries.len();
+        if glob.is_literal() {
+            self.exact
+                .entry(glob.pattern.clone())
+                .or_default()
+                .push(index);
+        } else {
+            let suffix = glob.literal_suffix();
+            let prefix = glob.literal_prefix();
+            if !suffix.is_empty() {
+                self.suffixes.entry(suffix).or_default().push(index);
+            } else if !prefix.is_empty() {
+                self.prefixes.entry(prefix).or_default().push(index);
+            } else {
+                self.other.push(index);
+            }
+        }
+        self.entries.push((glob, value));
+    }
+
+    /// Number of patterns in the set.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the set contains no patterns.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Values of all patterns matching `candidate`, in the order they were inserted.
+    #[must_use]
+    pub fn matches(&self, candidate: &str) -> Vec<&T> {
+        let mut indices: Vec<usize> = self.other.clone();
+        if let Some(exact) = self.exact.get(candidate) {
+            indices.extend(exact);
+        }
+        let boundaries = candidate
+            .char_indices()
+            .map(|(i, _)| i)
+            .chain(std::iter::once(candidate.len()));
+        for i in boundaries {
+            if let Some(suffixed) = self.suffixes.get(&candidate[i..]) {
+                indices.extend(suffixed);
+            }
+            if let Some(prefixed) = self.prefixes.get(&candidate[..i]) {
+                indices.extend(prefixed);
+            }
+        }
+        indices.sort_unstable();
+        indices.dedup();
+
+        let chars: Vec<char> = candidate.chars().collect();
+        indices
+            .into_iter()
+            .map(|i| &self.entries[i])
+            .filter(|(glob, _)| glob.is_literal() || glob.matches_chars(&chars))
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    /// Whether any pattern in the set matches `candidate`.
+    #[must_use]
+    pub fn is_match(&self, candidate: &str) -> bool {
+        !self.matches(candidate).is_empty()
+    }
+}
+
+/// Host part of a server name, without the port if there is one. IPv6 literals keep their
+/// brackets, and anything with more than one `:` is taken to be an unbracketed IPv6 address or
+/// glob, which has no port.
+#[must_use]
+pub fn host(server_name: &str) -> &str {
+    if server_name.starts_with('[') {
+        // IPv6 literal, e.g. `[::1]:8448`
+        match server_name.find(']') {
+            Some(end) => &server_name[..=end],
+            None => server_name,
+        }
+    } else {
+        match server_name.split_once(':') {
+            Some((host, port)) if !port.contains(':') => host,
+            _ => server_name,
+        }
+    }
+}
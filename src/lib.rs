@@ -8,7 +8,7 @@ use matrix_sdk::{
     room::Room,
     ruma::events::{
         room::{member::MemberEventContent, message::MessageEventContent},
-        StrippedStateEvent, SyncMessageEvent,
+        StrippedStateEvent, SyncMessageEvent, SyncStateEvent,
     },
     Client, SyncSettings,
 };
@@ -17,7 +17,14 @@ use tracing::{debug, error, info, warn};
 
 pub mod bot;
 pub mod config;
+pub mod glob;
 pub mod matrix;
+pub mod parser;
+pub mod policy;
+pub mod protection_commands;
+pub mod protections;
+pub mod state;
+pub mod timers;
 
 use crate::config::Config;
 
@@ -62,8 +69,28 @@ pub async fn init() -> Result<()> {
     };
     client.sync_once(SyncSettings::default()).await?;
     let config = Config::read_config()?;
+    if client
+        .get_joined_room(&config.bot.management_room)
+        .is_none()
+    {
+        info!("Joining management room {}", config.bot.management_room);
+        if let Err(e) = client.join_room_by_id(&config.bot.management_room).await {
+            error!(
+                "Could not join management room {}: {}",
+                config.bot.management_room, e
+            );
+        }
+    }
 
-    client.register_event_handler(bot::on_room_member).await;
+    client
+        .register_event_handler({
+            let config = config.clone();
+            move |ev: SyncStateEvent<MemberEventContent>, room: Room, client: Client| {
+                let config = config.clone();
+                async move { bot::on_room_member(ev, room, client, config).await }
+            }
+        })
+        .await;
     client
         .register_event_handler({
             let config = config.clone();
@@ -82,8 +109,19 @@ pub async fn init() -> Result<()> {
             }
         })
         .await;
+    // Index the rules of the followed policy lists up front rather than on the first join
+    if let Err(e) = policy::rule_index(&client).await {
+        error!("Could not index policy list rules: {:?}", e);
+    }
+    tokio::spawn(bot::run_timers(client.clone(), config.clone()));
     let settings = SyncSettings::default().token(client.sync_token().await.unwrap());
     // Sync until the end of ~time~
-    client.sync(settings).await;
+    client
+        .sync_with_callback(settings, |response| {
+            let client = client.clone();
+            let config = config.clone();
+            async move { bot::on_sync(response, client, config).await }
+        })
+        .await;
     Ok(())
 }
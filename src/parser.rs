@@ -0,0 +1,408 @@
+// Clobber - a matrix moderation bot
+// Copyright (C) 2020 Emelie <em@nao.sh>
+// Licensed under the EUPL
+
+//! Command line parsing: tokenizing messages and converting arguments into typed values.
+
+use matrix_sdk::ruma::{EventId, RoomIdOrAliasId, UserId};
+use std::{collections::BTreeMap, convert::TryFrom, fmt, str::FromStr, time::Duration};
+
+use crate::bot::Command;
+
+/// Errors that can occur while parsing a command line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// A quoted string was opened but never closed.
+    UnterminatedQuote,
+    /// A `--flag` was given that the command does not accept.
+    UnknownFlag(String),
+    /// A `--flag` that takes a value was given without one.
+    MissingFlagValue(String),
+    /// A required argument was not supplied.
+    MissingArgument(String),
+    /// An argument could not be converted into the expected type.
+    InvalidArgument {
+        /// Name of the argument.
+        name: String,
+        /// The value that was supplied.
+        value: String,
+        /// Why the value was rejected.
+        reason: String,
+    },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnterminatedQuote => write!(f, "unterminated quoted string"),
+            Self::UnknownFlag(flag) => write!(f, "unknown option --{}", flag),
+            Self::MissingFlagValue(flag) => write!(f, "option --{} requires a value", flag),
+            Self::MissingArgument(name) => write!(f, "missing argument <{}>", name),
+            Self::InvalidArgument {
+                name,
+                value,
+                reason,
+            } => write!(f, "invalid {} '{}': {}", name, value, reason),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Split the text following a command prefix into tokens.
+///
+/// Tokens are separated by any amount of whitespace. Text enclosed in double or single quotes is
+/// kept together as a single token, and a backslash escapes the character following it outside of
+/// single quotes.
+pub fn tokenize(input: &str) -> Result<Vec<String>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    // Whether a token has been started, so that `""` yields an empty token
+    let mut in_token = false;
+    let mut quote: Option<char> = None;
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => token.push(c),
+            (_, '\\') => {
+                in_token = true;
+                token.push(chars.next().unwrap_or('\\'));
+            }
+            (Some(_), c) => token.push(c),
+            (None, '"') | (None, '\'') => {
+                in_token = true;
+                quote = Some(c);
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_token {
+                    tokens.push(std::mem::take(&mut token));
+                    in_token = false;
+                }
+            }
+            (None, c) => {
+                in_token = true;
+                token.push(c);
+            }
+        }
+    }
+    if quote.is_some() {
+        return Err(ParseError::UnterminatedQuote);
+    }
+    if in_token {
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// Strip `prefix` from the start of a message body, returning the remaining text if the message
+/// is a command.
+///
+/// Prefixes longer than a single character must be followed by whitespace, so that `?clobber`
+/// does not match `?clobbering`, while single character prefixes such as `!` may be directly
+/// followed by the command name.
+#[must_use]
+pub fn strip_prefix<'a>(body: &'a str, prefix: &str) -> Option<&'a str> {
+    let rest = body.trim_start().strip_prefix(prefix)?;
+    if prefix.chars().count() > 1 && !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    Some(rest)
+}
+
+/// Strip a leading mention of `user_id` from a message, returning the remaining text if the
+/// message is addressed to that user.
+///
+/// Both the bare user ID (`@clobber:example.org ban ...`) and a pill in `formatted_body`
+/// (`<a href="https://matrix.to/#/@clobber:example.org">Clobber</a>: ban ...`) are recognised.
+/// In the latter case the pill's text, usually the display name, is stripped from `body`.
+#[must_use]
+pub fn strip_mention<'a>(
+    body: &'a str,
+    formatted_body: Option<&str>,
+    user_id: &UserId,
+) -> Option<&'a str> {
+    let body = body.trim_start();
+    let rest = body.strip_prefix(user_id.as_str()).or_else(|| {
+        let pill = formatted_body?.trim_start().strip_prefix("<a href=")?;
+        let quote = pill.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        let (href, pill) = pill[1..].split_once(quote)?;
+        let mentioned = href
+            .strip_prefix("https://matrix.to/#/")?
+            .replace("%40", "@")
+            .replace("%3A", ":")
+            .replace("%3a", ":");
+        if mentioned != user_id.as_str() {
+            return None;
+        }
+        let (_, pill) = pill.split_once('>')?;
+        let (text, _) = pill.split_once("</a>")?;
+        body.strip_prefix(unescape_html(text).as_str())
+    })?;
+    let rest = rest
+        .strip_prefix(':')
+        .or_else(|| rest.strip_prefix(','))
+        .unwrap_or(rest);
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    Some(rest)
+}
+
+/// Strip the quoted fallback that clients prepend to the `body` of replies.
+#[must_use]
+pub fn strip_reply_fallback(body: &str) -> &str {
+    if !body.starts_with('>') {
+        return body;
+    }
+    let mut offset = 0;
+    for line in body.split_inclusive('\n') {
+        offset += line.len();
+        if !line.starts_with('>') {
+            // The fallback is terminated by an empty line
+            if line.trim().is_empty() {
+                return &body[offset..];
+            }
+            return &body[offset - line.len()..];
+        }
+    }
+    ""
+}
+
+/// Strip the `<mx-reply>` fallback that clients prepend to the `formatted_body` of replies.
+#[must_use]
+pub fn strip_reply_fallback_html(formatted_body: &str) -> &str {
+    match formatted_body.trim_start().strip_prefix("<mx-reply>") {
+        Some(reply) => reply
+            .split_once("</mx-reply>")
+            .map_or(formatted_body, |(_, rest)| rest),
+        None => formatted_body,
+    }
+}
+
+/// Undo the escaping of the handful of HTML entities clients use in display names.
+fn unescape_html(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Target of a command that accepts either a single user or a glob matching several users.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UserTarget {
+    /// A single, valid user ID.
+    User(UserId),
+    /// A glob to match against user IDs.
+    Glob(String),
+}
+
+/// Positional arguments and flags supplied to a command.
+#[derive(Debug)]
+pub struct Arguments {
+    /// The command the arguments were parsed for.
+    command: &'static Command,
+    /// Positional arguments, in order.
+    positional: Vec<String>,
+    /// Flags that were supplied, along with their value if they take one.
+    flags: BTreeMap<&'static str, Option<String>>,
+}
+
+impl Arguments {
+    /// Separate `tokens` into positional arguments and the flags accepted by `command`.
+    ///
+    /// Flags may be given as `--name value` or `--name=value`. A lone `--` ends flag parsing, so
+    /// that the remaining tokens are treated as positional arguments even if they start with `--`.
+    pub fn parse(command: &'static Command, tokens: &[String]) -> Result<Self, ParseError> {
+        let mut positional = Vec::new();
+        let mut flags = BTreeMap::new();
+        let mut tokens = tokens.iter();
+        while let Some(token) = tokens.next() {
+            if token == "--" {
+                positional.extend(tokens.cloned());
+                break;
+            }
+            let name = match token.strip_prefix("--") {
+                Some(name) if !name.is_empty() => name,
+                _ => {
+                    positional.push(token.clone());
+                    continue;
+                }
+            };
+            let (name, inline_value) = match name.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_owned())),
+                None => (name, None),
+            };
+            let flag = command
+                .flags
+                .iter()
+                .find(|flag| flag.name == name)
+                .ok_or_else(|| ParseError::UnknownFlag(name.to_owned()))?;
+            let value = if flag.value.is_some() {
+                match inline_value.or_else(|| tokens.next().cloned()) {
+                    Some(value) => Some(value),
+                    None => return Err(ParseError::MissingFlagValue(name.to_owned())),
+                }
+            } else {
+                None
+            };
+            flags.insert(flag.name, value);
+        }
+        Ok(Self {
+            command,
+            positional,
+            flags,
+        })
+    }
+
+    /// Number of positional arguments.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.positional.len()
+    }
+
+    /// Whether no positional arguments were supplied.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.positional.is_empty()
+    }
+
+    /// Positional argument at `index`, if supplied.
+    #[must_use]
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.positional.get(index).map(String::as_str)
+    }
+
+    /// Positional argument at `index`, or an error if it was not supplied.
+    pub fn required(&self, index: usize) -> Result<&str, ParseError> {
+        self.get(index)
+            .ok_or_else(|| ParseError::MissingArgument(self.name(index)))
+    }
+
+    /// All positional arguments from `index` onwards joined by spaces, or `None` if there are
+    /// none. Used for free-form text such as reasons.
+    #[must_use]
+    pub fn rest(&self, index: usize) -> Option<String> {
+        let rest = self.positional.get(index..)?.join(" ");
+        if rest.trim().is_empty() {
+            None
+        } else {
+            Some(rest.trim().to_owned())
+        }
+    }
+
+    /// Whether the flag `name` was supplied.
+    #[must_use]
+    pub fn flag(&self, name: &str) -> bool {
+        self.flags.contains_key(name)
+    }
+
+    /// Value of the flag `name`, if it was supplied.
+    #[must_use]
+    pub fn flag_value(&self, name: &str) -> Option<&str> {
+        self.flags.get(name).and_then(Option::as_deref)
+    }
+
+    /// Required user ID at `index`.
+    pub fn user(&self, index: usize) -> Result<UserId, ParseError> {
+        parse_user_id(&self.name(index), self.required(index)?)
+    }
+
+    /// Required user ID or glob at `index`.
+    pub fn user_target(&self, index: usize) -> Result<UserTarget, ParseError> {
+        parse_user_target(&self.name(index), self.required(index)?)
+    }
+
+    /// Required event ID at `index`.
+    pub fn event(&self, index: usize) -> Result<EventId, ParseError> {
+        let value = self.required(index)?;
+        EventId::try_from(value).map_err(|e| invalid(&self.name(index), value, e))
+    }
+
+    /// Required room ID or alias at `index`.
+    pub fn room(&self, index: usize) -> Result<RoomIdOrAliasId, ParseError> {
+        parse_room(&self.name(index), self.required(index)?)
+    }
+
+    /// Optional duration at `index`.
+    pub fn duration(&self, index: usize) -> Result<Option<Duration>, ParseError> {
+        self.get(index)
+            .map(|value| {
+                parse_duration(value).ok_or_else(|| {
+                    invalid(&self.name(index), value, "expected e.g. 30s, 10m, 2h or 7d")
+                })
+            })
+            .transpose()
+    }
+
+    /// Optional number at `index`.
+    pub fn number<T>(&self, index: usize) -> Result<Option<T>, ParseError>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        self.get(index)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|e| invalid(&self.name(index), value, e))
+            })
+            .transpose()
+    }
+
+    /// Name of the argument at `index` according to the command's argument spec.
+    fn name(&self, index: usize) -> String {
+        self.command
+            .arguments
+            .get(index)
+            .map_or_else(|| format!("argument {}", index + 1), |a| a.name.to_owned())
+    }
+}
+
+/// Construct an [`ParseError::InvalidArgument`].
+fn invalid(name: &str, value: &str, reason: impl fmt::Display) -> ParseError {
+    ParseError::InvalidArgument {
+        name: name.to_owned(),
+        value: value.to_owned(),
+        reason: reason.to_string(),
+    }
+}
+
+/// Parse a user ID.
+pub fn parse_user_id(name: &str, value: &str) -> Result<UserId, ParseError> {
+    UserId::try_from(value).map_err(|e| invalid(name, value, e))
+}
+
+/// Parse a user ID, or a glob if `value` contains `*` or `?`.
+pub fn parse_user_target(name: &str, value: &str) -> Result<UserTarget, ParseError> {
+    if value.contains(['*', '?']) {
+        Ok(UserTarget::Glob(value.to_owned()))
+    } else {
+        parse_user_id(name, value).map(UserTarget::User)
+    }
+}
+
+/// Parse a room ID (`!room:server`) or alias (`#alias:server`).
+pub fn parse_room(name: &str, value: &str) -> Result<RoomIdOrAliasId, ParseError> {
+    RoomIdOrAliasId::try_from(value).map_err(|e| invalid(name, value, e))
+}
+
+/// Parse a duration such as `30s`, `10m`, `2h`, `7d` or `1w`.
+#[must_use]
+pub fn parse_duration(duration: &str) -> Option<Duration> {
+    let unit = duration.chars().last()?;
+    let amount: u64 = duration[..duration.len() - unit.len_utf8()].parse().ok()?;
+    let seconds = match unit {
+        's' => amount,
+        'm' => amount.checked_mul(60)?,
+        'h' => amount.checked_mul(60 * 60)?,
+        'd' => amount.checked_mul(60 * 60 * 24)?,
+        'w' => amount.checked_mul(60 * 60 * 24 * 7)?,
+        _ => return None,
+    };
+    if seconds == 0 {
+        return None;
+    }
+    Some(Duration::from_secs(seconds))
+}
@@ -0,0 +1,712 @@
+// Clobber - a matrix moderation bot
+// Copyright (C) 2020 Emelie <em@nao.sh>
+// Licensed under the EUPL
+
+//! Policy lists and the rules published in them.
+
+use anyhow::Result;
+use matrix_sdk::{
+    room::Joined,
+    ruma::{
+        api::client::r0::{
+            room::{
+                create_room::{self, RoomPreset},
+                Visibility,
+            },
+            state::send_state_event,
+        },
+        assign,
+        events::{room::server_acl::ServerAclEventContent, AnyStateEventContent, EventType},
+        identifiers::RoomName,
+        serde::Raw,
+        RoomId, UserId,
+    },
+    Client,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue as RawJsonValue;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::TryFrom,
+    sync::{Arc, Mutex},
+};
+#[allow(unused_imports)]
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    bot::{List, RuleFormat},
+    glob::{self, Glob, GlobSet},
+    state::{AccountData, PolicyLists, RuleKey},
+};
+
+/// State event types rules are read from: the standard types, the legacy clobber types and the
+/// unstable types still used by lists published with older versions of Mjolnir.
+pub const RULE_EVENT_TYPES: &[&str] = &[
+    "m.policy.rule.user",
+    "m.policy.rule.server",
+    "m.policy.rule.room",
+    "sh.nao.list.user",
+    "sh.nao.list.server",
+    "sh.nao.list.room",
+    "org.matrix.mjolnir.rule.user",
+    "org.matrix.mjolnir.rule.server",
+    "org.matrix.mjolnir.rule.room",
+];
+
+/// Enum of available actions to apply to entity that matches rules.
+///
+/// Each action is implemented once by the bot, and shared by commands, policy list enforcement and
+/// automatic protections.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum Action {
+    /// Ban the entity from the room.
+    Ban,
+    /// Kick the entity from the room.
+    Kick,
+    /// Lower the entity's power level until they can no longer send messages.
+    Mute,
+    /// Redact the entity's recent messages.
+    Redact,
+    /// Redact the entity's recent messages, then ban them.
+    RedactAndBan,
+    /// Restrict the entity to the quarantine power level until a moderator has reviewed them.
+    Quarantine,
+    /// Only notify the moderators in the management room.
+    Warn,
+}
+
+impl Action {
+    /// All actions, in the order they are listed to users.
+    pub const ALL: [Self; 7] = [
+        Self::Ban,
+        Self::Kick,
+        Self::Mute,
+        Self::Redact,
+        Self::RedactAndBan,
+        Self::Quarantine,
+        Self::Warn,
+    ];
+
+    /// Recommendation used for the action in `m.policy.rule.*` events. Only `m.ban` is defined
+    /// by the specification, the other actions use namespaced recommendations.
+    #[must_use]
+    pub const fn recommendation(&self) -> &'static str {
+        match self {
+            Self::Ban => "m.ban",
+            Self::Kick => "sh.nao.clobber.kick",
+            Self::Mute => "sh.nao.clobber.mute",
+            Self::Redact => "sh.nao.clobber.redact",
+            Self::RedactAndBan => "sh.nao.clobber.redact_and_ban",
+            Self::Quarantine => "sh.nao.clobber.quarantine",
+            Self::Warn => "sh.nao.clobber.warn",
+        }
+    }
+
+    /// Action corresponding to a recommendation in `m.policy.rule.*` events, if it is supported.
+    #[must_use]
+    pub fn from_recommendation(recommendation: &str) -> Option<Self> {
+        match recommendation {
+            // Lists published by older versions of Mjolnir still use the unstable name
+            "org.matrix.mjolnir.ban" => Some(Self::Ban),
+            _ => Self::ALL
+                .iter()
+                .find(|action| action.recommendation() == recommendation)
+                .cloned(),
+        }
+    }
+
+    /// How severe the action is, used to pick a single action when several rules apply to the
+    /// same entity. Higher is more severe.
+    #[must_use]
+    pub const fn severity(&self) -> u8 {
+        match self {
+            Self::Warn => 0,
+            Self::Redact => 1,
+            Self::Quarantine => 2,
+            Self::Mute => 3,
+            Self::Kick => 4,
+            Self::Ban => 5,
+            Self::RedactAndBan => 6,
+        }
+    }
+
+    /// Whether the action bans the entity.
+    #[must_use]
+    pub const fn bans(&self) -> bool {
+        matches!(self, Self::Ban | Self::RedactAndBan)
+    }
+
+    /// What the action would do, used when reporting results in dry run mode.
+    #[must_use]
+    pub const fn conditional(&self) -> &'static str {
+        match self {
+            Self::Ban => "Would ban",
+            Self::Kick => "Would kick",
+            Self::Mute => "Would mute",
+            Self::Redact => "Would redact recent messages of",
+            Self::RedactAndBan => "Would redact and ban",
+            Self::Quarantine => "Would quarantine",
+            Self::Warn => "Would report",
+        }
+    }
+
+    /// Past tense of the action, used when reporting results.
+    #[must_use]
+    pub const fn past_tense(&self) -> &'static str {
+        match self {
+            Self::Ban => "Banned",
+            Self::Kick => "Kicked",
+            Self::Mute => "Muted",
+            Self::Redact => "Redacted recent messages of",
+            Self::RedactAndBan => "Redacted and banned",
+            Self::Quarantine => "Quarantined pending review",
+            Self::Warn => "Reported",
+        }
+    }
+}
+
+impl std::str::FromStr for Action {
+    type Err = String;
+
+    fn from_str(action: &str) -> Result<Self, Self::Err> {
+        let action = action.to_lowercase();
+        Self::ALL
+            .iter()
+            .find(|candidate| candidate.to_string() == action)
+            .cloned()
+            .ok_or_else(|| {
+                String::from("expected ban, kick, mute, redact, redact-and-ban, quarantine or warn")
+            })
+    }
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ban => write!(f, "ban"),
+            Self::Kick => write!(f, "kick"),
+            Self::Mute => write!(f, "mute"),
+            Self::Redact => write!(f, "redact"),
+            Self::RedactAndBan => write!(f, "redact-and-ban"),
+            Self::Quarantine => write!(f, "quarantine"),
+            Self::Warn => write!(f, "warn"),
+        }
+    }
+}
+
+/// A rule along with the policy list and the state event it was published as.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    /// The policy list room the rule was read from.
+    pub list: RoomId,
+    /// Type of the state event the rule was published as.
+    pub event_type: String,
+    /// State key the rule was published under. Usually the entity, but lists are free to use
+    /// something else, such as `rule:<entity>` or a hash.
+    pub state_key: String,
+    /// The rule itself.
+    pub rule: List,
+}
+
+impl Rule {
+    /// Read a rule from a state event in the policy list `list`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the event is not a rule event, or if the rule has been revoked by sending empty
+    /// content.
+    pub fn from_event(list: &RoomId, event: &RawJsonValue) -> serde_json::Result<Self> {
+        let RuleEventKey {
+            event_type,
+            state_key,
+            ..
+        } = serde_json::from_str(event.get())?;
+        Ok(Self {
+            list: list.clone(),
+            event_type,
+            state_key,
+            rule: serde_json::from_str(event.get())?,
+        })
+    }
+
+    /// Key identifying the rule.
+    #[must_use]
+    pub fn key(&self) -> RuleKey {
+        RuleKey {
+            list: self.list.clone(),
+            event_type: self.event_type.clone(),
+            state_key: self.state_key.clone(),
+        }
+    }
+}
+
+/// Fields identifying which rule a state event affects.
+#[derive(Deserialize)]
+struct RuleEventKey {
+    #[serde(rename = "type")]
+    event_type: String,
+    state_key: String,
+    #[serde(default)]
+    unsigned: RuleEventUnsigned,
+}
+
+/// Unsigned data of a rule state event, holding the content it replaced.
+#[derive(Default, Deserialize)]
+struct RuleEventUnsigned {
+    prev_content: Option<PreviousRule>,
+}
+
+/// The part of a replaced rule needed to describe it once it has been revoked.
+#[derive(Deserialize)]
+struct PreviousRule {
+    entity: Option<String>,
+}
+
+/// Rules of every followed policy list, indexed once and then kept up to date as rules change.
+static INDEX: Mutex<Option<Arc<RuleIndex>>> = Mutex::new(None);
+
+/// Rules indexed by entity, so that users can be checked against thousands of rules quickly.
+///
+/// Rules can be added and removed without reindexing the others. Removed rules leave a gap
+/// behind until there are enough of them to be worth compacting.
+#[derive(Clone, Debug, Default)]
+pub struct RuleIndex {
+    /// The indexed rules, or `None` where a rule has been removed since.
+    rules: Vec<Option<Rule>>,
+    /// Positions of the current rules in `rules`, by key.
+    keys: BTreeMap<RuleKey, usize>,
+    /// Positions of user rules in `rules`, by entity.
+    users: GlobSet<usize>,
+    /// Positions of server rules in `rules`, by lowercased entity.
+    servers: GlobSet<usize>,
+}
+
+impl RuleIndex {
+    /// Number of removed rules tolerated before the index is compacted.
+    const MAX_REMOVED: usize = 256;
+
+    /// Index `rules`.
+    #[must_use]
+    pub fn new(rules: Vec<Rule>) -> Self {
+        let mut index = Self::default();
+        for rule in rules {
+            index.insert(rule);
+        }
+        index
+    }
+
+    /// Add `rule`, replacing the rule with the same key if there is one.
+    pub fn insert(&mut self, rule: Rule) {
+        self.remove(&rule.key());
+        let i = self.rules.len();
+        match &rule.rule {
+            List::User { entity, .. } => self.users.insert(entity, i),
+            // Server names are case-insensitive
+            List::Server { entity, .. } => self.servers.insert(&entity.to_lowercase(), i),
+            List::Room { .. } => {}
+        }
+        self.keys.insert(rule.key(), i);
+        self.rules.push(Some(rule));
+    }
+
+    /// Remove the rule with the given key, returning it.
+    pub fn remove(&mut self, key: &RuleKey) -> Option<Rule> {
+        let removed = self.keys.remove(key).and_then(|i| self.rules[i].take());
+        if self.rules.len() - self.keys.len() > Self::MAX_REMOVED {
+            *self = Self::new(self.rules.drain(..).flatten().collect());
+        }
+        removed
+    }
+
+    /// Remove every rule of the policy list `list`.
+    pub fn remove_list(&mut self, list: &RoomId) {
+        let keys: Vec<RuleKey> = self
+            .keys
+            .keys()
+            .filter(|key| &key.list == list)
+            .cloned()
+            .collect();
+        for key in keys {
+            self.remove(&key);
+        }
+    }
+
+    /// Apply a change to a rule.
+    pub fn apply(&mut self, change: &RuleChange) {
+        match change {
+            RuleChange::Added(rule) => self.insert(rule.clone()),
+            RuleChange::Revoked { .. } => {
+                self.remove(&change.key());
+            }
+        }
+    }
+
+    /// All indexed rules, in the order they were indexed.
+    pub fn rules(&self) -> impl Iterator<Item = &Rule> {
+        self.rules.iter().flatten()
+    }
+
+    /// Number of indexed rules.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Whether there are no rules in the index.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Rules that apply to `user`, either by user ID or by their server name, in the order they
+    /// were indexed.
+    #[must_use]
+    pub fn matching(&self, user: &UserId) -> Vec<&Rule> {
+        let server_name = user.server_name().as_str().to_lowercase();
+        let mut matches: Vec<usize> = self
+            .users
+            .matches(user.as_str())
+            .into_iter()
+            .copied()
+            .collect();
+        matches.extend(self.matching_servers(&server_name));
+        matches.sort_unstable();
+        matches.dedup();
+        matches
+            .into_iter()
+            .filter_map(|i| self.rules[i].as_ref())
+            .collect()
+    }
+
+    /// The rule with the most severe action of those that apply to `user`, or the first indexed
+    /// of them if several are equally severe.
+    #[must_use]
+    pub fn most_severe(&self, user: &UserId) -> Option<&Rule> {
+        // `max_by_key` keeps the last of equal elements
+        self.matching(user)
+            .into_iter()
+            .rev()
+            .max_by_key(|rule| rule.rule.action().severity())
+    }
+
+    /// Positions of server rules matching `server_name`. Rules match either the full server
+    /// name, or just its host so that rules without a port apply to every port.
+    fn matching_servers(&self, server_name: &str) -> Vec<usize> {
+        let mut matches: Vec<usize> = self
+            .servers
+            .matches(server_name)
+            .into_iter()
+            .copied()
+            .collect();
+        let host = glob::host(server_name);
+        if host != server_name {
+            matches.extend(self.servers.matches(host));
+        }
+        matches
+    }
+}
+
+/// Index of the rules of every policy list the bot follows. It is built from the state of the
+/// lists the first time it is needed, and kept up to date with [`update_index`] from then on.
+pub async fn rule_index(client: &Client) -> Result<Arc<RuleIndex>> {
+    if let Some(index) = lock_index().as_ref() {
+        return Ok(Arc::clone(index));
+    }
+    let index = Arc::new(RuleIndex::new(rules(client).await?));
+    debug!("Indexed {} policy list rules", index.len());
+    // Another handler may have built the index in the meantime, in which case that one is kept
+    Ok(Arc::clone(lock_index().get_or_insert(index)))
+}
+
+/// Update the cached rule index, if it has been built yet. Otherwise it will be built from the
+/// current state of the lists when it is first needed, which already includes the change.
+pub fn update_index(update: impl FnOnce(&mut RuleIndex)) {
+    if let Some(index) = lock_index().as_mut() {
+        update(Arc::make_mut(index));
+    }
+}
+
+/// Lock the cached rule index, even if a handler panicked while holding the lock.
+fn lock_index() -> std::sync::MutexGuard<'static, Option<Arc<RuleIndex>>> {
+    INDEX
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+/// Read the rules of every policy list the bot follows.
+pub async fn rules(client: &Client) -> Result<Vec<Rule>> {
+    let mut rules = Vec::new();
+    for room_id in PolicyLists::cached(client).await?.rooms() {
+        match client.get_joined_room(room_id) {
+            Some(room) => rules.extend(room_rules(&room).await?),
+            None => warn!("Not joined to policy list {}", room_id),
+        }
+    }
+    Ok(rules)
+}
+
+/// Read the rules currently published in a policy list room.
+///
+/// Rules that have been revoked by sending empty content, or that can't be parsed, are skipped.
+pub async fn room_rules(room: &Joined) -> Result<Vec<Rule>> {
+    let mut rules = Vec::new();
+    for event_type in RULE_EVENT_TYPES {
+        for raw in room.get_state_events(EventType::from(*event_type)).await? {
+            match Rule::from_event(room.room_id(), raw.json()) {
+                Ok(rule) => rules.push(rule),
+                Err(e) => debug!("Skipping rule in {}: {}", room.room_id(), e),
+            }
+        }
+    }
+    Ok(rules)
+}
+
+/// A change to a rule in a policy list.
+#[derive(Clone, Debug)]
+pub enum RuleChange {
+    /// A rule was added, or replaced an existing rule with the same state key.
+    Added(Rule),
+    /// A rule was revoked.
+    Revoked {
+        /// The policy list room the rule was revoked in.
+        list: RoomId,
+        /// Type of the state event the rule was published as.
+        event_type: String,
+        /// State key the rule was published under.
+        state_key: String,
+        /// Kind of entity the rule applied to.
+        kind: &'static str,
+        /// The entity the rule applied to, if the event still carries the revoked content, or
+        /// the state key otherwise.
+        entity: String,
+    },
+}
+
+impl RuleChange {
+    /// Key identifying the rule that changed.
+    #[must_use]
+    pub fn key(&self) -> RuleKey {
+        match self {
+            Self::Added(rule) => rule.key(),
+            Self::Revoked {
+                list,
+                event_type,
+                state_key,
+                ..
+            } => RuleKey {
+                list: list.clone(),
+                event_type: event_type.clone(),
+                state_key: state_key.clone(),
+            },
+        }
+    }
+}
+
+/// Interpret a state event received through sync as a change to a rule in the policy list
+/// `list`, or `None` if it isn't a rule event.
+#[must_use]
+pub fn rule_change(list: &RoomId, event: &RawJsonValue) -> Option<RuleChange> {
+    let RuleEventKey {
+        event_type,
+        state_key,
+        unsigned,
+    } = serde_json::from_str(event.get()).ok()?;
+    let kind = List::kind_of(&event_type)?;
+    // Anything that no longer parses as a rule, typically empty content, revokes the rule
+    Some(match Rule::from_event(list, event) {
+        Ok(rule) => RuleChange::Added(rule),
+        Err(_) => RuleChange::Revoked {
+            list: list.clone(),
+            entity: unsigned
+                .prev_content
+                .and_then(|previous| previous.entity)
+                .unwrap_or_else(|| state_key.clone()),
+            event_type,
+            state_key,
+            kind,
+        },
+    })
+}
+
+/// Create a new public policy list room with the given alias localpart and name, and add it to
+/// the lists published by the bot.
+pub async fn create_list(client: &Client, alias: &str, name: Option<&str>) -> Result<RoomId> {
+    let name = name.map(<&RoomName>::try_from).transpose()?;
+    let request = assign!(create_room::Request::new(), {
+        name,
+        preset: Some(RoomPreset::PublicChat),
+        room_alias_name: Some(alias),
+        topic: Some("Policy list published by clobber"),
+        visibility: Visibility::Public,
+    });
+    let room_id = client.create_room(request).await?.room_id;
+    let mut lists = PolicyLists::load(client).await?;
+    lists.published.insert(room_id.clone());
+    lists.save(client).await?;
+    info!("Created policy list {}", room_id);
+    Ok(room_id)
+}
+
+/// Publish `rule` in a policy list room in the given format, replacing any existing rule for the
+/// same entity, including one published in the other format.
+pub async fn publish_rule(
+    client: &Client,
+    list: &Joined,
+    rule: &List,
+    format: RuleFormat,
+) -> Result<()> {
+    let content = serde_json::value::to_raw_value(&rule.content(format))?;
+    let event_type = rule.event_type(format);
+    send_rule_event(client, list.room_id(), event_type, rule.entity(), content).await?;
+    for (existing, _) in find_rules(list, rule.kind(), rule.entity()).await? {
+        if existing != event_type {
+            revoke_rule(client, list.room_id(), existing, rule.entity()).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Revoke the rule for `entity` of the given event type by replacing it with empty content.
+pub async fn revoke_rule(
+    client: &Client,
+    list: &RoomId,
+    event_type: &str,
+    entity: &str,
+) -> Result<()> {
+    let content = serde_json::value::to_raw_value(&serde_json::json!({}))?;
+    send_rule_event(client, list, event_type, entity, content).await
+}
+
+/// Send a rule state event keyed by entity.
+async fn send_rule_event(
+    client: &Client,
+    list: &RoomId,
+    event_type: &str,
+    entity: &str,
+    content: Box<RawJsonValue>,
+) -> Result<()> {
+    let request = send_state_event::Request::new_raw(
+        list,
+        event_type,
+        entity,
+        Raw::<AnyStateEventContent>::from_json(content),
+    );
+    client.send(request, None).await?;
+    Ok(())
+}
+
+/// Find the currently active rules of the given kind for `entity` in a policy list room published
+/// by the bot, along with the event type each of them was published as.
+pub async fn find_rules(
+    room: &Joined,
+    kind: &str,
+    entity: &str,
+) -> Result<Vec<(&'static str, List)>> {
+    let mut rules = Vec::new();
+    for format in RuleFormat::ALL {
+        let event_type = match List::event_type_of(kind, format) {
+            Some(event_type) => event_type,
+            None => continue,
+        };
+        let rule = room
+            .get_state_event(EventType::from(event_type), entity)
+            .await?
+            .and_then(|raw| raw.deserialize_as::<List>().ok());
+        if let Some(rule) = rule {
+            rules.push((event_type, rule));
+        }
+    }
+    Ok(rules)
+}
+
+/// Server ACL entries denying the servers banned by `rules`, along with the first rule denying
+/// each, leaving out any entry that would deny `own_server`.
+///
+/// ACL entries can't include a port, so rules for a server name with a port deny the whole host.
+#[must_use]
+pub fn server_acl_deny<'a>(
+    rules: impl IntoIterator<Item = &'a Rule>,
+    own_server: &str,
+) -> BTreeMap<String, &'a Rule> {
+    let own_host = glob::host(own_server).to_lowercase();
+    let mut deny = BTreeMap::new();
+    for rule in rules {
+        let entry = match &rule.rule {
+            List::Server { entity, action, .. } if action.bans() => {
+                glob::host(entity).to_lowercase()
+            }
+            _ => continue,
+        };
+        if Glob::new(&entry).is_match(&own_host) {
+            warn!(
+                "Not denying {} as it would deny the bot's own server",
+                entry
+            );
+            continue;
+        }
+        deny.entry(entry).or_insert(rule);
+    }
+    deny
+}
+
+/// A change to the server ACL of a room.
+#[derive(Clone, Debug)]
+pub struct AclUpdate {
+    /// The complete new ACL.
+    pub content: ServerAclEventContent,
+    /// Entries added to the deny list.
+    pub denied: Vec<String>,
+    /// Entries removed from the deny list.
+    pub lifted: Vec<String>,
+}
+
+/// Work out the server ACL of a room with the current ACL `current` once every entry of `deny`
+/// is denied, or `None` if the room already denies all of them.
+///
+/// Existing entries are left alone. Rooms without an ACL get one allowing every other server.
+#[must_use]
+pub fn server_acl(
+    current: Option<ServerAclEventContent>,
+    deny: &BTreeSet<String>,
+) -> Option<AclUpdate> {
+    let mut content =
+        current.unwrap_or_else(|| ServerAclEventContent::new(true, vec!["*".to_owned()], vec![]));
+    let denied: Vec<String> = deny
+        .iter()
+        .filter(|entry| !content.deny.contains(entry))
+        .cloned()
+        .collect();
+    if denied.is_empty() {
+        return None;
+    }
+    content.deny.extend(denied.iter().cloned());
+    Some(AclUpdate {
+        content,
+        denied,
+        lifted: Vec::new(),
+    })
+}
+
+/// Work out the server ACL of a room with the current ACL `current` once the entries in `lift`
+/// are no longer denied, or `None` if none of them are.
+#[must_use]
+pub fn relaxed_server_acl(
+    mut content: ServerAclEventContent,
+    lift: &BTreeSet<String>,
+) -> Option<AclUpdate> {
+    let lifted: Vec<String> = content
+        .deny
+        .iter()
+        .filter(|entry| lift.contains(*entry))
+        .cloned()
+        .collect();
+    if lifted.is_empty() {
+        return None;
+    }
+    content.deny.retain(|entry| !lift.contains(entry));
+    Some(AclUpdate {
+        content,
+        denied: Vec::new(),
+        lifted,
+    })
+}
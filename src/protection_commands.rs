@@ -0,0 +1,924 @@
+// Clobber - a matrix moderation bot
+// Copyright (C) 2020 Emelie <em@nao.sh>
+// Licensed under the EUPL
+
+//! Commands showing and changing the automatic protections, and the lockdowns they put rooms
+//! under.
+
+use matrix_sdk::{
+    room::Joined,
+    ruma::events::{
+        room::{join_rules::JoinRulesEventContent, message::MessageEventContent},
+        AnyStateEventContent, SyncMessageEvent,
+    },
+    ruma::{Int, RoomId, UserId},
+    Client,
+};
+use std::time::Duration;
+#[allow(unused_imports)]
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    bot::{
+        action_argument, command_unknown, escape_html, format_duration, format_remaining,
+        power_levels, resolve_room_or_reply, send_reply,
+    },
+    config::Config,
+    parser::{self, Arguments, ParseError},
+    policy::Action,
+    protections,
+    state::{
+        AccountData, ContentRule, DomainLists, FloodLimit, JoinLimit, LinkFilter, Lockdown,
+        LockdownJoinRule, LockdownMode, Lockdowns, MentionLimit, PatternKind, Protections,
+        RoomProtections, Setting,
+    },
+    timers,
+};
+
+/// Show and change the flood protection settings.
+pub(crate) async fn command_flood(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    subcommand: &str,
+    arguments: &Arguments,
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    let setting = match subcommand {
+        "show" => {
+            return show_protection(event, room, client, "Flood protection", |settings| {
+                settings
+                    .flood
+                    .as_ref()
+                    .map(|setting| describe_setting(setting, describe_flood))
+            })
+            .await
+        }
+        "set" => {
+            let messages = arguments
+                .number::<usize>(0)?
+                .ok_or_else(|| ParseError::MissingArgument(String::from("messages")))?;
+            let window = arguments
+                .duration(1)?
+                .ok_or_else(|| ParseError::MissingArgument(String::from("window")))?;
+            let action = action_argument(arguments, 2)?.unwrap_or(Action::Mute);
+            Some(Setting::On(FloodLimit {
+                messages,
+                seconds: window.as_secs(),
+                action,
+            }))
+        }
+        "off" => Some(Setting::Off),
+        "clear" => None,
+        _ => return command_unknown(event, room, config).await,
+    };
+    change_protection(
+        event,
+        room,
+        client,
+        arguments,
+        "Flood protection",
+        |settings| {
+            let description = setting.as_ref().map_or_else(
+                || String::from("not set"),
+                |setting| describe_setting(setting, describe_flood),
+            );
+            settings.flood = setting;
+            description
+        },
+    )
+    .await
+}
+
+/// Show and change the mass mention protection settings.
+pub(crate) async fn command_mentions(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    subcommand: &str,
+    arguments: &Arguments,
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    let setting = match subcommand {
+        "show" => {
+            return show_protection(
+                event,
+                room,
+                client,
+                "Mention protection",
+                describe_mention_settings,
+            )
+            .await
+        }
+        "set" => {
+            let mentions = arguments
+                .number::<usize>(0)?
+                .ok_or_else(|| ParseError::MissingArgument(String::from("mentions")))?;
+            let action = action_argument(arguments, 1)?.unwrap_or(Action::Mute);
+            Some(Setting::On(MentionLimit { mentions, action }))
+        }
+        "off" => Some(Setting::Off),
+        "clear" => None,
+        "allow" | "disallow" => {
+            let user = arguments.user(0)?;
+            let allow = subcommand == "allow";
+            return change_protection(
+                event,
+                room,
+                client,
+                arguments,
+                "Mention protection",
+                |settings| {
+                    if allow {
+                        settings.mention_allowed.insert(user);
+                    } else {
+                        settings.mention_allowed.remove(&user);
+                    }
+                    describe_mention_settings(settings).unwrap_or_else(|| String::from("not set"))
+                },
+            )
+            .await;
+        }
+        _ => return command_unknown(event, room, config).await,
+    };
+    change_protection(
+        event,
+        room,
+        client,
+        arguments,
+        "Mention protection",
+        |settings| {
+            settings.mentions = setting;
+            describe_mention_settings(settings).unwrap_or_else(|| String::from("not set"))
+        },
+    )
+    .await
+}
+
+/// Lock down rooms by hand, lift lockdowns, and show and change the join flood protection
+/// settings.
+pub(crate) async fn command_lockdown(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    subcommand: &str,
+    arguments: &Arguments,
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    let setting = match subcommand {
+        "on" => return command_lockdown_on(event, room, client, arguments, config).await,
+        "off" => return command_lockdown_off(event, room, client, arguments).await,
+        "status" => return command_lockdown_status(event, room, client).await,
+        "show" => {
+            return show_protection(event, room, client, "Join flood protection", |settings| {
+                settings
+                    .joins
+                    .as_ref()
+                    .map(|setting| describe_setting(setting, describe_join_limit))
+            })
+            .await
+        }
+        "set" => {
+            let joins = arguments
+                .number::<usize>(0)?
+                .ok_or_else(|| ParseError::MissingArgument(String::from("joins")))?;
+            let window = arguments
+                .duration(1)?
+                .ok_or_else(|| ParseError::MissingArgument(String::from("window")))?;
+            let join_rule = match arguments.flag_value("join-rule") {
+                None => Some(LockdownJoinRule::Invite),
+                Some("unchanged") => None,
+                Some(join_rule) => {
+                    Some(join_rule.parse::<LockdownJoinRule>().map_err(|reason| {
+                        ParseError::InvalidArgument {
+                            name: String::from("join-rule"),
+                            value: join_rule.to_owned(),
+                            reason: format!("{}, or unchanged", reason),
+                        }
+                    })?)
+                }
+            };
+            Some(Setting::On(JoinLimit {
+                joins,
+                seconds: window.as_secs(),
+                lockdown: LockdownMode {
+                    join_rule,
+                    restrict_messages: !arguments.flag("allow-messages"),
+                    cooldown: arguments.duration(2)?.map(|cooldown| cooldown.as_secs()),
+                },
+            }))
+        }
+        "disable" => Some(Setting::Off),
+        "clear" => None,
+        _ => return command_unknown(event, room, config).await,
+    };
+    change_protection(
+        event,
+        room,
+        client,
+        arguments,
+        "Join flood protection",
+        |settings| {
+            let description = setting.as_ref().map_or_else(
+                || String::from("not set"),
+                |setting| describe_setting(setting, describe_join_limit),
+            );
+            settings.joins = setting;
+            description
+        },
+    )
+    .await
+}
+
+/// Lock down a room by hand, the way it would be locked down by join flood protection.
+async fn command_lockdown_on(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    arguments: &Arguments,
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    let target = arguments.room(0)?;
+    let duration = arguments.duration(1)?;
+    let room_id = match resolve_room_or_reply(event, room, client, &target).await? {
+        Some(room_id) => room_id,
+        None => return Ok(()),
+    };
+    let refusal = if room_id == config.bot.management_room {
+        Some(String::from("The management room can't be locked down"))
+    } else if Lockdowns::load(client).await?.rooms.contains_key(&room_id) {
+        Some(format!("{} is already locked down", room_id))
+    } else {
+        None
+    };
+    let locked = match (refusal, client.get_joined_room(&room_id)) {
+        (Some(refusal), _) => Err(refusal),
+        (None, None) => Err(format!("Not joined to {}", room_id)),
+        (None, Some(locked)) => Ok(locked),
+    };
+    let locked = match locked {
+        Ok(locked) => locked,
+        Err(refusal) => {
+            send_reply(
+                &refusal,
+                &escape_html(&refusal),
+                room,
+                event.event_id.clone(),
+            )
+            .await?;
+            return Ok(());
+        }
+    };
+
+    let mode = Protections::load(client)
+        .await?
+        .get(&room_id, |settings| &settings.joins)
+        .map_or_else(LockdownMode::default, |limit| limit.lockdown);
+    let message = match lock_down(client, &locked, mode, duration).await {
+        Ok(LockdownOutcome::Locked) => {
+            info!("Locked down {}", room_id);
+            format!(
+                "Locked down {} {}",
+                room_id,
+                describe_lockdown_duration(duration)
+            )
+        }
+        Ok(LockdownOutcome::AlreadyLocked) => format!("{} is already locked down", room_id),
+        Ok(LockdownOutcome::Unchanged) => format!(
+            "{} already is as restricted as a lockdown would make it, nothing was changed",
+            room_id
+        ),
+        Err(e) => format!("Could not lock down {}: {}", room_id, e),
+    };
+    send_reply(
+        &message,
+        &escape_html(&message),
+        room,
+        event.event_id.clone(),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Lift the lockdown of a room, or of every locked down room.
+async fn command_lockdown_off(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    arguments: &Arguments,
+) -> Result<(), anyhow::Error> {
+    let target = match arguments.get(0) {
+        Some(_) => {
+            let target = arguments.room(0)?;
+            match resolve_room_or_reply(event, room, client, &target).await? {
+                Some(room_id) => Some(room_id),
+                None => return Ok(()),
+            }
+        }
+        None => None,
+    };
+    let _lock = Lockdowns::lock().await;
+    let mut lockdowns = Lockdowns::load(client).await?;
+    let lifted: Vec<(RoomId, Lockdown)> = match &target {
+        Some(room_id) => lockdowns
+            .rooms
+            .get_key_value(room_id)
+            .map(|(room_id, lockdown)| (room_id.clone(), lockdown.clone()))
+            .into_iter()
+            .collect(),
+        None => lockdowns.rooms.clone().into_iter().collect(),
+    };
+    if lifted.is_empty() {
+        let message = target.map_or_else(
+            || String::from("No rooms are locked down"),
+            |room_id| format!("{} is not locked down", room_id),
+        );
+        send_reply(
+            &message,
+            &escape_html(&message),
+            room,
+            event.event_id.clone(),
+        )
+        .await?;
+        return Ok(());
+    }
+    let results = lift_lockdowns(client, &mut lockdowns, lifted).await;
+    lockdowns.save(client).await?;
+    let (plain, html) = format_lockdown_results(&results, "Lifted lockdown of");
+    send_reply(&plain, &html, room, event.event_id.clone()).await?;
+    Ok(())
+}
+
+/// List the rooms that are locked down, and when their lockdowns expire.
+async fn command_lockdown_status(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+) -> Result<(), anyhow::Error> {
+    let lockdowns = Lockdowns::load(client).await?;
+    if lockdowns.rooms.is_empty() {
+        send_reply(
+            "No rooms are locked down",
+            "No rooms are locked down",
+            room,
+            event.event_id.clone(),
+        )
+        .await?;
+        return Ok(());
+    }
+    let mut plain = String::from("Locked down rooms:");
+    let mut html = String::from("<p>Locked down rooms:</p><ul>");
+    let now = timers::now();
+    for (room_id, lockdown) in &lockdowns.rooms {
+        let remaining = lockdown.expires.map_or_else(
+            || String::from("until lifted"),
+            |expires| {
+                format!(
+                    "for another {}",
+                    format_remaining(Duration::from_secs(expires.saturating_sub(now)))
+                )
+            },
+        );
+        plain.push_str(&format!("\n{} {}", room_id, remaining));
+        html.push_str(&format!("<li><code>{}</code> {}</li>", room_id, remaining));
+    }
+    html.push_str("</ul>");
+    send_reply(&plain, &html, room, event.event_id.clone()).await?;
+    Ok(())
+}
+
+/// Describe a join limit for replies.
+fn describe_join_limit(limit: &JoinLimit) -> String {
+    let mut measures = Vec::new();
+    if let Some(join_rule) = limit.lockdown.join_rule {
+        measures.push(format!("switch the join rule to {}", join_rule));
+    }
+    if limit.lockdown.restrict_messages {
+        measures.push(String::from("restrict messages"));
+    }
+    if measures.is_empty() {
+        measures.push(String::from("only report it"));
+    }
+    format!(
+        "more than {} joins within {}: {} {}",
+        limit.joins,
+        format_duration(Duration::from_secs(limit.seconds)),
+        measures.join(" and "),
+        describe_lockdown_duration(limit.lockdown.cooldown.map(Duration::from_secs))
+    )
+}
+
+/// Show and change the link filter settings and the domain lists.
+pub(crate) async fn command_links(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    subcommand: &str,
+    arguments: &Arguments,
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    let setting = match subcommand {
+        "show" => {
+            return show_protection(event, room, client, "Link filter", |settings| {
+                settings
+                    .links
+                    .as_ref()
+                    .map(|setting| describe_setting(setting, describe_link_filter))
+            })
+            .await
+        }
+        "set" => {
+            let action = action_argument(arguments, 0)?.unwrap_or(Action::Warn);
+            let new_member_seconds = match arguments.flag_value("new-members") {
+                Some(duration) => Some(
+                    parser::parse_duration(duration)
+                        .ok_or_else(|| ParseError::InvalidArgument {
+                            name: String::from("new-members"),
+                            value: duration.to_owned(),
+                            reason: String::from("expected e.g. 30s, 10m, 2h or 7d"),
+                        })?
+                        .as_secs(),
+                ),
+                None => None,
+            };
+            Some(Setting::On(LinkFilter {
+                action,
+                new_member_seconds,
+            }))
+        }
+        "off" => Some(Setting::Off),
+        "clear" => None,
+        "list" => return command_links_list(event, room, client).await,
+        "deny" | "undeny" | "allow" | "disallow" => {
+            return command_links_edit(event, room, client, subcommand, arguments).await
+        }
+        _ => return command_unknown(event, room, config).await,
+    };
+    change_protection(event, room, client, arguments, "Link filter", |settings| {
+        let description = setting.as_ref().map_or_else(
+            || String::from("not set"),
+            |setting| describe_setting(setting, describe_link_filter),
+        );
+        settings.links = setting;
+        description
+    })
+    .await
+}
+
+/// List the denied and allowed domains.
+async fn command_links_list(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+) -> Result<(), anyhow::Error> {
+    let lists = DomainLists::load(client).await?;
+    let mut plain = String::new();
+    let mut html = String::new();
+    for (title, domains) in [
+        ("Denied domains", &lists.denied),
+        ("Allowed domains for new members", &lists.allowed),
+    ] {
+        if domains.is_empty() {
+            plain.push_str(&format!("{}: none\n", title));
+            html.push_str(&format!("<p>{}: none</p>", title));
+            continue;
+        }
+        let domains = domains.iter().cloned().collect::<Vec<String>>().join(", ");
+        plain.push_str(&format!("{}: {}\n", title, domains));
+        html.push_str(&format!("<p>{}: {}</p>", title, escape_html(&domains)));
+    }
+    send_reply(plain.trim_end(), &html, room, event.event_id.clone()).await?;
+    Ok(())
+}
+
+/// Add a domain to or remove it from the deny or allow list.
+async fn command_links_edit(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    subcommand: &str,
+    arguments: &Arguments,
+) -> Result<(), anyhow::Error> {
+    let domain = arguments.required(0)?.to_lowercase();
+    let mut lists = DomainLists::load(client).await?;
+    let (list, name, add) = match subcommand {
+        "deny" => (&mut lists.denied, "deny list", true),
+        "undeny" => (&mut lists.denied, "deny list", false),
+        "allow" => (&mut lists.allowed, "allow list", true),
+        _ => (&mut lists.allowed, "allow list", false),
+    };
+    let changed = if add {
+        list.insert(domain.clone())
+    } else {
+        list.remove(&domain)
+    };
+    let message = match (changed, add) {
+        (true, true) => format!("Added {} to the {}", domain, name),
+        (true, false) => format!("Removed {} from the {}", domain, name),
+        (false, true) => format!("{} is already on the {}", domain, name),
+        (false, false) => format!("{} is not on the {}", domain, name),
+    };
+    if changed {
+        lists.save(client).await?;
+        info!("{}", message);
+    }
+    send_reply(
+        &message,
+        &escape_html(&message),
+        room,
+        event.event_id.clone(),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Show and change the content filter rules.
+pub(crate) async fn command_filter(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    subcommand: &str,
+    arguments: &Arguments,
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    match subcommand {
+        "show" => {
+            show_protection(event, room, client, "Content filter", |settings| {
+                (!settings.content.is_empty()).then(|| describe_content_rules(&settings.content))
+            })
+            .await
+        }
+        "add" => {
+            let kind = arguments.required(0)?;
+            let kind =
+                kind.parse::<PatternKind>()
+                    .map_err(|reason| ParseError::InvalidArgument {
+                        name: String::from("word|regex"),
+                        value: kind.to_owned(),
+                        reason,
+                    })?;
+            let pattern = arguments.required(1)?.to_owned();
+            let action = action_argument(arguments, 2)?
+                .ok_or_else(|| ParseError::MissingArgument(String::from("action")))?;
+            let rule = ContentRule {
+                kind,
+                pattern,
+                action,
+                reason: arguments.rest(3).unwrap_or_default(),
+            };
+            // Only rules that compile are stored, so that the filter always compiles
+            if let Err(e) = protections::ContentFilter::new(vec![rule.clone()]) {
+                return Err(ParseError::InvalidArgument {
+                    name: String::from("pattern"),
+                    value: rule.pattern,
+                    reason: e.to_string(),
+                }
+                .into());
+            }
+            change_protection(
+                event,
+                room,
+                client,
+                arguments,
+                "Content filter",
+                |settings| {
+                    settings
+                        .content
+                        .retain(|existing| existing.pattern != rule.pattern);
+                    settings.content.push(rule);
+                    describe_content_rules(&settings.content)
+                },
+            )
+            .await
+        }
+        "remove" => {
+            let pattern = arguments.required(0)?.to_owned();
+            change_protection(
+                event,
+                room,
+                client,
+                arguments,
+                "Content filter",
+                |settings| {
+                    settings
+                        .content
+                        .retain(|existing| existing.pattern != pattern);
+                    if settings.content.is_empty() {
+                        String::from("no rules")
+                    } else {
+                        describe_content_rules(&settings.content)
+                    }
+                },
+            )
+            .await
+        }
+        _ => command_unknown(event, room, config).await,
+    }
+}
+
+/// Describe content filter rules for replies.
+fn describe_content_rules(rules: &[ContentRule]) -> String {
+    rules
+        .iter()
+        .map(|rule| {
+            if rule.reason.is_empty() {
+                format!("{} {}: {}", rule.kind, rule.pattern, rule.action)
+            } else {
+                format!(
+                    "{} {}: {} ({})",
+                    rule.kind, rule.pattern, rule.action, rule.reason
+                )
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("; ")
+}
+
+/// Describe a link filter for replies.
+fn describe_link_filter(filter: &LinkFilter) -> String {
+    match filter.new_member_seconds {
+        Some(seconds) => format!(
+            "redact links to denied domains, and to domains not allowed from members who joined less than {} ago: {}",
+            format_duration(Duration::from_secs(seconds)),
+            filter.action
+        ),
+        None => format!("redact links to denied domains: {}", filter.action),
+    }
+}
+
+/// Change a setting of the room selected with `--room`, or the default of all protected rooms,
+/// and reply with the result. `change` changes the settings and describes the protection after
+/// the change.
+async fn change_protection(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    arguments: &Arguments,
+    name: &str,
+    change: impl FnOnce(&mut RoomProtections) -> String + Send,
+) -> Result<(), anyhow::Error> {
+    let target = match settings_room(event, room, client, arguments).await? {
+        Some(target) => target,
+        None => return Ok(()),
+    };
+
+    let mut protections = Protections::load(client).await?;
+    let description = change(protections.settings_mut(target.as_ref()));
+    protections.rooms.retain(|_, settings| !settings.is_empty());
+    protections.save(client).await?;
+    let scope = target.map_or_else(
+        || String::from("by default"),
+        |room_id| format!("in {}", room_id),
+    );
+    info!("Set {} {} to {}", name.to_lowercase(), scope, description);
+    let message = format!("{} {}: {}", name, scope, description);
+    send_reply(
+        &message,
+        &escape_html(&message),
+        room,
+        event.event_id.clone(),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Reply with the default setting of a protection along with those of single rooms, as described
+/// by `describe`. It returns `None` for settings that don't set the protection.
+async fn show_protection(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    name: &str,
+    describe: impl Fn(&RoomProtections) -> Option<String> + Send,
+) -> Result<(), anyhow::Error> {
+    let protections = Protections::load(client).await?;
+    let default = describe(&protections.defaults).unwrap_or_else(|| String::from("not set"));
+    let mut plain = format!("{} by default: {}", name, default);
+    let mut html = format!("<p>{} by default: {}</p><ul>", name, escape_html(&default));
+    for (room_id, settings) in &protections.rooms {
+        if let Some(description) = describe(settings) {
+            plain.push_str(&format!("\nIn {}: {}", room_id, description));
+            html.push_str(&format!(
+                "<li>In <code>{}</code>: {}</li>",
+                room_id,
+                escape_html(&description)
+            ));
+        }
+    }
+    html.push_str("</ul>");
+    send_reply(&plain, &html, room, event.event_id.clone()).await?;
+    Ok(())
+}
+
+/// Describe a protection setting for replies, using `describe` if it is turned on.
+fn describe_setting<T>(setting: &Setting<T>, describe: impl Fn(&T) -> String) -> String {
+    match setting {
+        Setting::Off => String::from("off"),
+        Setting::On(value) => describe(value),
+    }
+}
+
+/// Describe a flood limit for replies.
+fn describe_flood(limit: &FloodLimit) -> String {
+    format!(
+        "more than {} messages within {}: {}",
+        limit.messages,
+        format_duration(Duration::from_secs(limit.seconds)),
+        limit.action
+    )
+}
+
+/// Describe the mention protection settings for replies, or `None` if they are not set.
+fn describe_mention_settings(settings: &RoomProtections) -> Option<String> {
+    let mut description = settings.mentions.as_ref().map(|setting| {
+        describe_setting(setting, |limit| {
+            format!("more than {} mentions: {}", limit.mentions, limit.action)
+        })
+    });
+    if !settings.mention_allowed.is_empty() {
+        let allowed = settings
+            .mention_allowed
+            .iter()
+            .map(UserId::as_str)
+            .collect::<Vec<&str>>()
+            .join(", ");
+        description = Some(format!(
+            "{}, allowed for {}",
+            description.as_deref().unwrap_or("not set"),
+            allowed
+        ));
+    }
+    description
+}
+
+/// The room a protection setting command applies to, selected with `--room`, or `Some(None)` for
+/// the defaults of all protected rooms. Replies with the reason and returns `None` if the room
+/// can't be resolved.
+async fn settings_room(
+    event: &SyncMessageEvent<MessageEventContent>,
+    room: &Joined,
+    client: &Client,
+    arguments: &Arguments,
+) -> Result<Option<Option<RoomId>>, anyhow::Error> {
+    match arguments.flag_value("room") {
+        Some(target) => {
+            let target = parser::parse_room("room", target)?;
+            Ok(resolve_room_or_reply(event, room, client, &target)
+                .await?
+                .map(Some))
+        }
+        None => Ok(Some(None)),
+    }
+}
+
+/// What locking down a room did.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum LockdownOutcome {
+    /// The room was locked down.
+    Locked,
+    /// The room was already locked down, and that lockdown was left as it is.
+    AlreadyLocked,
+    /// The room was already as restricted as the lockdown would make it, so nothing changed.
+    Unchanged,
+}
+
+/// Lock down `room` as described by `mode` for `duration`, or until it is lifted by hand,
+/// remembering how the room was before so that it can be restored.
+pub(crate) async fn lock_down(
+    client: &Client,
+    room: &Joined,
+    mode: LockdownMode,
+    duration: Option<Duration>,
+) -> Result<LockdownOutcome, anyhow::Error> {
+    let _lock = Lockdowns::lock().await;
+    let mut lockdowns = Lockdowns::load(client).await?;
+    if lockdowns.rooms.contains_key(room.room_id()) {
+        return Ok(LockdownOutcome::AlreadyLocked);
+    }
+    let mut lockdown = Lockdown {
+        join_rule: None,
+        events_default: None,
+        expires: duration.map(|duration| timers::now().saturating_add(duration.as_secs())),
+    };
+    let result = restrict_room(room, mode, &mut lockdown).await;
+    // The lockdown is recorded even if only part of it went through, so that it is undone later
+    if lockdowns.add(room.room_id().clone(), lockdown) {
+        lockdowns.save(client).await?;
+        result.map(|()| LockdownOutcome::Locked)
+    } else {
+        result.map(|()| LockdownOutcome::Unchanged)
+    }
+}
+
+/// Change the join rule and power levels of `room` as described by `mode`, recording the previous
+/// values in `lockdown`.
+async fn restrict_room(
+    room: &Joined,
+    mode: LockdownMode,
+    lockdown: &mut Lockdown,
+) -> Result<(), anyhow::Error> {
+    if let Some(join_rule) = mode.join_rule.map(LockdownJoinRule::join_rule) {
+        let previous = room.join_rule();
+        if previous != join_rule {
+            room.send_state_event(
+                AnyStateEventContent::RoomJoinRules(JoinRulesEventContent::new(join_rule)),
+                "",
+            )
+            .await?;
+            lockdown.join_rule = Some(previous);
+        }
+    }
+    if mode.restrict_messages {
+        let mut power_levels = power_levels(room).await?;
+        let previous = i64::from(power_levels.events_default);
+        // Anyone with a raised power level may still talk
+        let restricted = i64::from(power_levels.users_default) + 1;
+        if previous < restricted {
+            power_levels.events_default = Int::new(restricted)
+                .ok_or_else(|| anyhow::anyhow!("invalid power level {}", restricted))?;
+            room.send_state_event(AnyStateEventContent::RoomPowerLevels(power_levels), "")
+                .await?;
+            lockdown.events_default = Some(previous);
+        }
+    }
+    Ok(())
+}
+
+/// Restore the join rule and power levels `room` had before it was locked down.
+async fn lift_lockdown(room: &Joined, lockdown: &Lockdown) -> Result<(), anyhow::Error> {
+    if let Some(join_rule) = &lockdown.join_rule {
+        room.send_state_event(
+            AnyStateEventContent::RoomJoinRules(JoinRulesEventContent::new(join_rule.clone())),
+            "",
+        )
+        .await?;
+    }
+    if let Some(events_default) = lockdown.events_default {
+        let mut power_levels = power_levels(room).await?;
+        power_levels.events_default = Int::new(events_default)
+            .ok_or_else(|| anyhow::anyhow!("invalid power level {}", events_default))?;
+        room.send_state_event(AnyStateEventContent::RoomPowerLevels(power_levels), "")
+            .await?;
+    }
+    Ok(())
+}
+
+/// Describe how long a lockdown lasts for replies.
+pub(crate) fn describe_lockdown_duration(duration: Option<Duration>) -> String {
+    duration.map_or_else(
+        || String::from("until lifted"),
+        |duration| format!("for {}", format_remaining(duration)),
+    )
+}
+
+/// Lift each of `lifted`, removing those that were lifted from `lockdowns` and keeping the others
+/// so that they can be lifted later. Returns the outcome for each room.
+pub(crate) async fn lift_lockdowns(
+    client: &Client,
+    lockdowns: &mut Lockdowns,
+    lifted: Vec<(RoomId, Lockdown)>,
+) -> Vec<(RoomId, Result<(), anyhow::Error>)> {
+    let mut results = Vec::with_capacity(lifted.len());
+    for (room_id, lockdown) in lifted {
+        let result = match client.get_joined_room(&room_id) {
+            Some(room) => lift_lockdown(&room, &lockdown).await,
+            None => Err(anyhow::anyhow!("not joined to the room")),
+        };
+        match &result {
+            Ok(()) => {
+                info!("Lifted lockdown of {}", room_id);
+                lockdowns.rooms.remove(&room_id);
+            }
+            Err(e) => warn!("Failed to lift lockdown of {}: {}", room_id, e),
+        }
+        results.push((room_id, result));
+    }
+    results
+}
+
+/// Format the outcomes of lifting lockdowns into a plain text and HTML summary.
+pub(crate) fn format_lockdown_results(
+    results: &[(RoomId, Result<(), anyhow::Error>)],
+    verb: &str,
+) -> (String, String) {
+    let mut plain = String::new();
+    let mut html = String::from("<ul>");
+    for (room_id, result) in results {
+        match result {
+            Ok(()) => {
+                plain.push_str(&format!("{} {}\n", verb, room_id));
+                html.push_str(&format!("<li>{} <code>{}</code></li>", verb, room_id));
+            }
+            Err(e) => {
+                plain.push_str(&format!("Could not lift lockdown of {}: {}\n", room_id, e));
+                html.push_str(&format!(
+                    "<li>Could not lift lockdown of <code>{}</code>: {}</li>",
+                    room_id,
+                    escape_html(&e.to_string())
+                ));
+            }
+        }
+    }
+    html.push_str("</ul>");
+    (plain.trim_end().to_owned(), html)
+}
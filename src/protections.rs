@@ -0,0 +1,528 @@
+// Clobber - a matrix moderation bot
+// Copyright (C) 2020 Emelie <em@nao.sh>
+// Licensed under the EUPL
+
+//! Automatic protections, reacting to what users do in protected rooms as it happens.
+
+use matrix_sdk::ruma::{
+    events::room::message::{
+        EmoteMessageEventContent, MessageEventContent, MessageFormat, MessageType,
+        NoticeMessageEventContent, Relation, TextMessageEventContent,
+    },
+    RoomId, UserId,
+};
+use regex::RegexSet;
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet, VecDeque},
+    convert::TryFrom,
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+#[allow(unused_imports)]
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    glob::{self, GlobSet},
+    parser,
+    state::{ContentRule, DomainLists, FloodLimit, JoinLimit, PatternKind},
+};
+
+/// Messages seen in every protected room, shared by all message handlers.
+static FLOOD: Mutex<RateTracker<(RoomId, UserId)>> = Mutex::new(RateTracker::new());
+
+/// Joins seen in every protected room, shared by all membership handlers.
+static JOINS: Mutex<RateTracker<RoomId>> = Mutex::new(RateTracker::new());
+
+/// When users joined protected rooms that restrict links from new members, shared by all
+/// membership and message handlers.
+static JOINED: Mutex<JoinTimes> = Mutex::new(JoinTimes::new());
+
+/// Domain lists compiled into glob sets, along with the hash of the lists they were compiled from.
+static DOMAINS: Mutex<Option<(u64, Arc<DomainFilter>)>> = Mutex::new(None);
+
+/// Content filters compiled for each protected room, along with the hash of the rules they were
+/// compiled from, reused for as long as its rules don't change.
+static FILTERS: Mutex<BTreeMap<RoomId, (u64, Arc<ContentFilter>)>> = Mutex::new(BTreeMap::new());
+
+/// Tracks when recent events happened within a sliding window, such as the messages of each user
+/// in each room.
+#[derive(Debug, Default)]
+pub struct RateTracker<K> {
+    /// Times of the events of each key within the window, oldest first.
+    events: BTreeMap<K, VecDeque<Instant>>,
+    /// Largest window events have been recorded with. Keys are only forgotten once they have
+    /// been quiet for longer, as different rooms may use different windows.
+    max_window: Duration,
+    /// When keys that have gone quiet were last forgotten.
+    pruned: Option<Instant>,
+}
+
+impl<K: Ord + Clone> RateTracker<K> {
+    /// How often keys that have gone quiet are forgotten.
+    const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+    /// Create an empty tracker.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            events: BTreeMap::new(),
+            max_window: Duration::from_secs(0),
+            pruned: None,
+        }
+    }
+
+    /// Number of keys with events within the window.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Whether no keys have events within the window.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Record an event for `key` at `at`, returning the number of its events within `window` if
+    /// that exceeds `limit`. Its events are forgotten once they exceed it, so that it is only
+    /// acted on once.
+    pub fn record(&mut self, key: K, at: Instant, limit: usize, window: Duration) -> Option<usize> {
+        self.max_window = self.max_window.max(window);
+        if self
+            .pruned
+            .is_none_or(|pruned| at.saturating_duration_since(pruned) >= Self::PRUNE_INTERVAL)
+        {
+            self.prune(at);
+        }
+        let times = self.events.entry(key.clone()).or_default();
+        times.push_back(at);
+        while times
+            .front()
+            .is_some_and(|first| at.saturating_duration_since(*first) >= window)
+        {
+            times.pop_front();
+        }
+        let count = times.len();
+        if count > limit {
+            self.events.remove(&key);
+            Some(count)
+        } else {
+            None
+        }
+    }
+
+    /// Forget the keys without events within the largest window as of `at`.
+    fn prune(&mut self, at: Instant) {
+        let max_window = self.max_window;
+        self.events.retain(|_, times| {
+            times
+                .back()
+                .is_some_and(|last| at.saturating_duration_since(*last) < max_window)
+        });
+        self.pruned = Some(at);
+    }
+}
+
+/// Record a message sent by `user` in `room` now, returning the number of messages they sent
+/// within the window if that exceeds `limit`.
+pub fn record_message(room: &RoomId, user: &UserId, limit: &FloodLimit) -> Option<usize> {
+    FLOOD
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+        .record(
+            (room.clone(), user.clone()),
+            Instant::now(),
+            limit.messages,
+            Duration::from_secs(limit.seconds),
+        )
+}
+
+/// Record a user joining `room` now, returning the number of users who joined within the window
+/// if that exceeds `limit`.
+pub fn record_join(room: &RoomId, limit: &JoinLimit) -> Option<usize> {
+    JOINS
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+        .record(
+            room.clone(),
+            Instant::now(),
+            limit.joins,
+            Duration::from_secs(limit.seconds),
+        )
+}
+
+/// Tracks when users joined rooms, for as long as they count as new members there.
+#[derive(Debug, Default)]
+pub struct JoinTimes {
+    /// When each user joined each room, and when they stop counting as a new member.
+    joined: BTreeMap<(RoomId, UserId), (Instant, Instant)>,
+    /// When members who no longer count as new were last forgotten.
+    pruned: Option<Instant>,
+}
+
+impl JoinTimes {
+    /// How often members who no longer count as new are forgotten.
+    const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+    /// Create an empty tracker.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            joined: BTreeMap::new(),
+            pruned: None,
+        }
+    }
+
+    /// Record `user` joining `room` at `at`, remembering it for `keep`.
+    pub fn record(&mut self, room: RoomId, user: UserId, at: Instant, keep: Duration) {
+        if self
+            .pruned
+            .is_none_or(|pruned| at.saturating_duration_since(pruned) >= Self::PRUNE_INTERVAL)
+        {
+            self.joined.retain(|_, (_, forget)| *forget > at);
+            self.pruned = Some(at);
+        }
+        self.joined.insert((room, user), (at, at + keep));
+    }
+
+    /// When `user` joined `room`, if it was recorded and is still remembered at `at`.
+    #[must_use]
+    pub fn joined(&self, room: &RoomId, user: &UserId, at: Instant) -> Option<Instant> {
+        self.joined
+            .get(&(room.clone(), user.clone()))
+            .filter(|(_, forget)| *forget > at)
+            .map(|(joined, _)| *joined)
+    }
+}
+
+/// Record `user` joining `room` now, remembering it for `keep`.
+pub fn record_member_join(room: &RoomId, user: &UserId, keep: Duration) {
+    JOINED
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+        .record(room.clone(), user.clone(), Instant::now(), keep);
+}
+
+/// When `user` joined `room`, if the bot saw them join recently enough to remember it.
+pub fn member_joined(room: &RoomId, user: &UserId) -> Option<Instant> {
+    JOINED
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+        .joined(room, user, Instant::now())
+}
+
+/// Plain text body and HTML `formatted_body` of text messages, notices and emotes, without reply
+/// fallbacks. Returns `None` for other kinds of messages.
+#[must_use]
+pub fn message_text(content: &MessageEventContent) -> Option<(&str, Option<&str>)> {
+    let (body, formatted) = match &content.msgtype {
+        MessageType::Text(TextMessageEventContent {
+            body, formatted, ..
+        })
+        | MessageType::Notice(NoticeMessageEventContent {
+            body, formatted, ..
+        })
+        | MessageType::Emote(EmoteMessageEventContent {
+            body, formatted, ..
+        }) => (body, formatted),
+        _ => return None,
+    };
+    let formatted_body = formatted
+        .as_ref()
+        .filter(|formatted| formatted.format == MessageFormat::Html)
+        .map(|formatted| formatted.body.as_str());
+    if let Some(Relation::Reply { .. }) = content.relates_to {
+        Some((
+            parser::strip_reply_fallback(body),
+            formatted_body.map(parser::strip_reply_fallback_html),
+        ))
+    } else {
+        Some((body, formatted_body))
+    }
+}
+
+/// Shortest display name, in characters, that counts as a mention when written out in a message.
+/// Shorter names are too likely to be ordinary words.
+pub const MIN_NAME_LENGTH: usize = 4;
+
+/// Users other than `sender` mentioned in a message, by their user ID written out in `body`, by a
+/// pill linking to them in `formatted_body`, or by the display name of one of `members` in `body`.
+///
+/// Display names only count if they are at least [`MIN_NAME_LENGTH`] characters long and no other
+/// member uses the same name.
+pub fn mentioned_users<'a>(
+    sender: &UserId,
+    body: &str,
+    formatted_body: Option<&str>,
+    members: impl IntoIterator<Item = (&'a UserId, Option<&'a str>)>,
+) -> BTreeSet<UserId> {
+    let mut mentioned = BTreeSet::new();
+    for word in body.split(|c: char| c.is_whitespace() || c == '<' || c == '>') {
+        let word = match word.find('@') {
+            Some(start) => &word[start..],
+            None => continue,
+        };
+        let word = word.trim_end_matches(|c: char| ",.;:!?)\"'".contains(c));
+        if let Ok(user) = UserId::try_from(word) {
+            mentioned.insert(user);
+        }
+    }
+    if let Some(formatted_body) = formatted_body {
+        mentioned.extend(pills(formatted_body));
+    }
+
+    // Names are looked up by their first word, so the body is only scanned once
+    let mut names: BTreeMap<Vec<String>, Option<&UserId>> = BTreeMap::new();
+    for (user, name) in members {
+        let name = match name {
+            Some(name) if name.trim().chars().count() >= MIN_NAME_LENGTH => name.to_lowercase(),
+            _ => continue,
+        };
+        let name: Vec<String> = words(&name).map(str::to_owned).collect();
+        if !name.is_empty() {
+            // Names shared by several members don't say which of them is meant
+            names
+                .entry(name)
+                .and_modify(|user| *user = None)
+                .or_insert(Some(user));
+        }
+    }
+    let mut by_first_word: BTreeMap<&str, Vec<(&[String], &UserId)>> = BTreeMap::new();
+    for (name, user) in &names {
+        if let Some(user) = user {
+            by_first_word
+                .entry(name[0].as_str())
+                .or_default()
+                .push((name, user));
+        }
+    }
+    let body = body.to_lowercase();
+    let body: Vec<&str> = words(&body).collect();
+    for (i, word) in body.iter().enumerate() {
+        for (name, user) in by_first_word.get(word).into_iter().flatten() {
+            if body[i..].len() >= name.len() && name.iter().zip(&body[i..]).all(|(a, b)| a == b) {
+                mentioned.insert((*user).clone());
+            }
+        }
+    }
+    mentioned.remove(sender);
+    mentioned
+}
+
+/// Words of `text`, separated by anything other than letters and digits.
+fn words(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+}
+
+/// Users linked to from `matrix.to` or `matrix:` links in an HTML body.
+fn pills(formatted_body: &str) -> impl Iterator<Item = UserId> + '_ {
+    // Links to users, along with the sigil their user IDs are written without
+    [("https://matrix.to/#/", ""), ("matrix:u/", "@")]
+        .iter()
+        .flat_map(move |&(prefix, sigil)| {
+            formatted_body
+                .match_indices(prefix)
+                .map(move |(start, _)| (&formatted_body[start + prefix.len()..], sigil))
+        })
+        .filter_map(|(link, sigil)| {
+            let end = link
+                .find(|c: char| c.is_whitespace() || "\"'?>".contains(c))
+                .unwrap_or(link.len());
+            UserId::try_from(format!("{}{}", sigil, percent_decode(&link[..end]))).ok()
+        })
+}
+
+/// Decode the percent-encoded characters that commonly appear in user IDs in links.
+fn percent_decode(link: &str) -> String {
+    link.replace("%40", "@")
+        .replace("%3A", ":")
+        .replace("%3a", ":")
+}
+
+/// Hosts of the web links in a message, lowercased and without ports, found in `body` and in the
+/// links of `formatted_body`. Links to `matrix.to`, as used by pills, are left out.
+pub fn link_hosts(body: &str, formatted_body: Option<&str>) -> BTreeSet<String> {
+    let mut hosts = BTreeSet::new();
+    for text in std::iter::once(body).chain(formatted_body) {
+        let text = text.to_lowercase();
+        for prefix in ["http://", "https://", "www."] {
+            for (start, _) in text.match_indices(prefix) {
+                if text[..start]
+                    .chars()
+                    .next_back()
+                    .is_some_and(char::is_alphanumeric)
+                {
+                    continue;
+                }
+                // Links starting with `www.` keep it as part of the host
+                let link = if prefix == "www." {
+                    &text[start..]
+                } else {
+                    &text[start + prefix.len()..]
+                };
+                let end = link
+                    .find(|c: char| c.is_whitespace() || "/?#\\\"'<>()[],".contains(c))
+                    .unwrap_or(link.len());
+                let authority = &link[..end];
+                let host = authority
+                    .rsplit_once('@')
+                    .map_or(authority, |(_, host)| host);
+                let host = glob::host(host).trim_end_matches('.');
+                if !host.is_empty() && host != "matrix.to" {
+                    hosts.insert(host.to_owned());
+                }
+            }
+        }
+    }
+    hosts
+}
+
+/// The domain lists compiled into glob sets, so that links are matched against them quickly.
+#[derive(Debug)]
+pub struct DomainFilter {
+    /// Domains no one may link to, lowercased.
+    denied: GlobSet<()>,
+    /// Domains new members may link to, lowercased.
+    allowed: GlobSet<()>,
+}
+
+impl DomainFilter {
+    /// Compile the domain `lists`.
+    #[must_use]
+    pub fn new(lists: &DomainLists) -> Self {
+        let compile = |patterns: &BTreeSet<String>| {
+            let mut set = GlobSet::new();
+            for pattern in patterns {
+                set.insert(&pattern.to_lowercase(), ());
+            }
+            set
+        };
+        Self {
+            denied: compile(&lists.denied),
+            allowed: compile(&lists.allowed),
+        }
+    }
+
+    /// Those of `hosts` on the list of denied domains.
+    #[must_use]
+    pub fn denied<'a>(&self, hosts: &'a BTreeSet<String>) -> Vec<&'a str> {
+        matching_hosts(&self.denied, hosts)
+    }
+
+    /// Those of `hosts` on the list of allowed domains.
+    #[must_use]
+    pub fn allowed<'a>(&self, hosts: &'a BTreeSet<String>) -> Vec<&'a str> {
+        matching_hosts(&self.allowed, hosts)
+    }
+}
+
+/// Those of `hosts` matching any pattern in `set`.
+fn matching_hosts<'a>(set: &GlobSet<()>, hosts: &'a BTreeSet<String>) -> Vec<&'a str> {
+    hosts
+        .iter()
+        .filter(|host| set.is_match(host))
+        .map(String::as_str)
+        .collect()
+}
+
+/// The domain filter compiled from `lists`, compiling it only if the lists changed since it was
+/// last compiled.
+pub fn domain_filter(lists: &DomainLists) -> Arc<DomainFilter> {
+    let hash = hash(lists);
+    let mut domains = DOMAINS
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    match domains.as_ref() {
+        Some((compiled, filter)) if *compiled == hash => Arc::clone(filter),
+        _ => {
+            debug!(
+                "Compiling {} denied and {} allowed domains",
+                lists.denied.len(),
+                lists.allowed.len()
+            );
+            let filter = Arc::new(DomainFilter::new(lists));
+            *domains = Some((hash, Arc::clone(&filter)));
+            filter
+        }
+    }
+}
+
+/// Hash of `value`, used to tell whether settings changed since they were compiled without
+/// keeping a copy of them around.
+fn hash(value: &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Content rules compiled into a single set of regular expressions, so that a message is matched
+/// against all of them in one pass.
+#[derive(Debug)]
+pub struct ContentFilter {
+    /// The rules, in the order of the patterns in `set`.
+    rules: Vec<ContentRule>,
+    /// The compiled patterns.
+    set: RegexSet,
+}
+
+impl ContentFilter {
+    /// Compile `rules` into a filter.
+    pub fn new(rules: Vec<ContentRule>) -> Result<Self, regex::Error> {
+        let set = RegexSet::new(rules.iter().map(rule_regex))?;
+        Ok(Self { rules, set })
+    }
+
+    /// The first rule matching `text`, if any does.
+    #[must_use]
+    pub fn matching(&self, text: &str) -> Option<&ContentRule> {
+        self.set
+            .matches(text)
+            .iter()
+            .next()
+            .map(|index| &self.rules[index])
+    }
+}
+
+/// Regular expression matching the pattern of `rule`.
+fn rule_regex(rule: &ContentRule) -> String {
+    match rule.kind {
+        PatternKind::Word => {
+            // `\b` only marks a boundary next to letters, digits and underscores
+            let is_word = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+            let boundary = |c| if is_word(c) { r"\b" } else { "" };
+            format!(
+                "(?i){}{}{}",
+                boundary(rule.pattern.chars().next()),
+                regex::escape(&rule.pattern),
+                boundary(rule.pattern.chars().next_back())
+            )
+        }
+        PatternKind::Regex => rule.pattern.clone(),
+    }
+}
+
+/// The content filter of `room` compiled from `rules`, compiling it only if the rules changed
+/// since it was last compiled.
+pub fn content_filter<'a>(
+    room: &RoomId,
+    rules: impl Iterator<Item = &'a ContentRule> + Clone,
+) -> Result<Arc<ContentFilter>, regex::Error> {
+    let mut hasher = DefaultHasher::new();
+    rules.clone().for_each(|rule| rule.hash(&mut hasher));
+    let hash = hasher.finish();
+    let mut filters = FILTERS
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    match filters.get(room) {
+        Some((compiled, filter)) if *compiled == hash => Ok(Arc::clone(filter)),
+        _ => {
+            let rules: Vec<ContentRule> = rules.cloned().collect();
+            debug!("Compiling {} content rules for {}", rules.len(), room);
+            let filter = Arc::new(ContentFilter::new(rules)?);
+            filters.insert(room.clone(), (hash, Arc::clone(&filter)));
+            Ok(filter)
+        }
+    }
+}
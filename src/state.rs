@@ -0,0 +1,685 @@
+// Clobber - a matrix moderation bot
+// Copyright (C) 2020 Emelie <em@nao.sh>
+// Licensed under the EUPL
+
+//! Bot state persisted in the account data of the bot's matrix account.
+//!
+//! Keeping state in account data rather than in the data directory means it survives restarts
+//! and is shared by every deployment logged in to the same account.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use matrix_sdk::{
+    ruma::{
+        api::{
+            client::{
+                error::ErrorKind,
+                r0::config::{get_global_account_data, set_global_account_data},
+            },
+            error::{FromHttpResponseError, ServerError},
+        },
+        events::{room::join_rules::JoinRule, EventType},
+        RoomId, UserId,
+    },
+    Client, HttpError,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+#[allow(unused_imports)]
+use tracing::{debug, error, info, warn};
+
+use crate::policy::Action;
+
+/// State stored as a custom global account data event.
+#[async_trait]
+pub trait AccountData: Default + DeserializeOwned + Serialize + Send + Sync {
+    /// Event type the state is stored under.
+    const EVENT_TYPE: &'static str;
+
+    /// Load state from the homeserver, or the default if it has never been saved.
+    async fn load(client: &Client) -> Result<Self> {
+        let user_id = client
+            .user_id()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Client is not logged in"))?;
+        let request = get_global_account_data::Request::new(&user_id, Self::EVENT_TYPE);
+        match client.send(request, None).await {
+            Ok(response) => Ok(serde_json::from_str(response.account_data.json().get())?),
+            Err(HttpError::ClientApi(FromHttpResponseError::Http(ServerError::Known(e))))
+                if e.kind == ErrorKind::NotFound =>
+            {
+                debug!("No {} account data found, using defaults", Self::EVENT_TYPE);
+                Ok(Self::default())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// State as last received through sync, without making a request to the homeserver. Falls
+    /// back to [`load`](Self::load) if nothing has been received yet.
+    ///
+    /// Changes made with [`save`](Self::save) only show up here after the next sync, so commands
+    /// modifying state should use `load` instead.
+    async fn cached(client: &Client) -> Result<Self> {
+        match client
+            .store()
+            .get_account_data_event(EventType::from(Self::EVENT_TYPE))
+            .await?
+        {
+            Some(raw) => Ok(serde_json::from_str::<Event<Self>>(raw.json().get())?.content),
+            None => Self::load(client).await,
+        }
+    }
+
+    /// Save state to the homeserver, replacing what was stored before.
+    async fn save(&self, client: &Client) -> Result<()> {
+        let user_id = client
+            .user_id()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Client is not logged in"))?;
+        let data = serde_json::value::to_raw_value(self)?;
+        let request = set_global_account_data::Request::new(&data, Self::EVENT_TYPE, &user_id);
+        client.send(request, None).await?;
+        Ok(())
+    }
+}
+
+/// Account data event as stored by the SDK, used to get at the content of custom events.
+#[derive(Deserialize)]
+struct Event<T> {
+    /// Content of the event.
+    content: T,
+}
+
+/// Rooms the bot moderates. Bans and ACL changes apply to every protected room.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ProtectedRooms {
+    /// IDs of the protected rooms.
+    #[serde(default)]
+    pub rooms: BTreeSet<RoomId>,
+}
+
+impl AccountData for ProtectedRooms {
+    const EVENT_TYPE: &'static str = "sh.nao.clobber.protected_rooms";
+}
+
+/// Rooms the bot monitors for users matching policy list rules without taking action, reporting
+/// matches to the management room instead.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct WatchedRooms {
+    /// IDs of the watched rooms.
+    #[serde(default)]
+    pub rooms: BTreeSet<RoomId>,
+}
+
+impl AccountData for WatchedRooms {
+    const EVENT_TYPE: &'static str = "sh.nao.clobber.watched_rooms";
+}
+
+/// Policy list rooms whose rules the bot follows.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PolicyLists {
+    /// IDs of the policy list rooms published by the bot, which rules can be added to and
+    /// removed from with commands. Earlier versions stored these as `rooms`.
+    #[serde(default, alias = "rooms")]
+    pub published: BTreeSet<RoomId>,
+    /// IDs of policy list rooms published by others that the bot is subscribed to.
+    #[serde(default)]
+    pub subscribed: BTreeSet<RoomId>,
+}
+
+impl PolicyLists {
+    /// IDs of all policy list rooms the bot follows, published or subscribed.
+    pub fn rooms(&self) -> impl Iterator<Item = &RoomId> {
+        self.published.iter().chain(&self.subscribed)
+    }
+}
+
+impl AccountData for PolicyLists {
+    const EVENT_TYPE: &'static str = "sh.nao.clobber.policy_lists";
+}
+
+/// Identifies a rule by the policy list it was published in and the state event it was published
+/// as. Revoking a rule only leaves its state key behind, which need not be its entity.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+pub struct RuleKey {
+    /// The policy list room the rule was published in.
+    pub list: RoomId,
+    /// Type of the state event the rule was published as.
+    pub event_type: String,
+    /// State key the rule was published under.
+    pub state_key: String,
+}
+
+/// A ban applied because of a policy list rule.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RuleBan {
+    /// The rule that caused the ban.
+    pub rule: RuleKey,
+    /// The room the user was banned from.
+    pub room: RoomId,
+    /// The banned user.
+    pub user: UserId,
+}
+
+/// A server ACL entry denied because of a policy list rule.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RuleAclEntry {
+    /// The rule that caused the entry to be denied.
+    pub rule: RuleKey,
+    /// The room whose server ACL denies the entry.
+    pub room: RoomId,
+    /// The denied ACL entry.
+    pub server: String,
+}
+
+/// Held while the rule enforcements are loaded, changed and saved again, so that concurrent event
+/// handlers don't overwrite each other's changes.
+static ENFORCEMENTS: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Bans and server ACL entries the bot applied because of policy list rules, so that they can be
+/// lifted again when the rule is revoked. Bans made by moderators are never tracked here.
+///
+/// Entries are dropped once the ban is lifted or the rule revoked, and a user or server is only
+/// tracked once per room.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RuleEnforcements {
+    /// Bans applied because of rules.
+    #[serde(default)]
+    pub bans: Vec<RuleBan>,
+    /// Server ACL entries denied because of rules.
+    #[serde(default)]
+    pub denied_servers: Vec<RuleAclEntry>,
+}
+
+impl RuleEnforcements {
+    /// Lock the rule enforcements until the guard is dropped. Changes must be loaded and saved
+    /// while holding it.
+    pub async fn lock() -> tokio::sync::MutexGuard<'static, ()> {
+        ENFORCEMENTS.lock().await
+    }
+
+    /// Load the rule enforcements, change them and save them again if `change` returns `true`,
+    /// holding the lock throughout.
+    pub async fn update(client: &Client, change: impl FnOnce(&mut Self) -> bool) -> Result<()> {
+        let _lock = Self::lock().await;
+        let mut enforcements = Self::load(client).await?;
+        if change(&mut enforcements) {
+            enforcements.save(client).await?;
+        }
+        Ok(())
+    }
+
+    /// Track `bans`, replacing any ban already tracked for the same user in the same room.
+    pub fn add_bans(&mut self, bans: Vec<RuleBan>) {
+        for ban in bans {
+            self.bans
+                .retain(|tracked| tracked.room != ban.room || tracked.user != ban.user);
+            self.bans.push(ban);
+        }
+    }
+
+    /// Track `entries`, replacing any entry already tracked for the same server in the same room.
+    pub fn add_denied_servers(&mut self, entries: Vec<RuleAclEntry>) {
+        for entry in entries {
+            self.denied_servers
+                .retain(|tracked| tracked.room != entry.room || tracked.server != entry.server);
+            self.denied_servers.push(entry);
+        }
+    }
+
+    /// Stop tracking the bans of `users` in `room`, returning whether any were tracked. Used
+    /// when a ban is lifted, and when a moderator bans a user by hand so that the ban is no
+    /// longer lifted along with the rule.
+    pub fn forget_bans(&mut self, room: &RoomId, users: &[UserId]) -> bool {
+        let before = self.bans.len();
+        self.bans
+            .retain(|ban| &ban.room != room || !users.contains(&ban.user));
+        self.bans.len() != before
+    }
+
+    /// The rules from the policy list `list` that anything is tracked for.
+    #[must_use]
+    pub fn rules_from(&self, list: &RoomId) -> Vec<RuleKey> {
+        let bans = self.bans.iter().map(|ban| &ban.rule);
+        let servers = self.denied_servers.iter().map(|entry| &entry.rule);
+        bans.chain(servers)
+            .filter(|rule| &rule.list == list)
+            .cloned()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// Stop tracking everything applied because of any of `rules`, returning it.
+    pub fn take(&mut self, rules: &[RuleKey]) -> (Vec<RuleBan>, Vec<RuleAclEntry>) {
+        let (taken, kept) = self
+            .bans
+            .drain(..)
+            .partition(|ban| rules.contains(&ban.rule));
+        self.bans = kept;
+        let (taken_servers, kept_servers) = self
+            .denied_servers
+            .drain(..)
+            .partition(|entry| rules.contains(&entry.rule));
+        self.denied_servers = kept_servers;
+        (taken, taken_servers)
+    }
+}
+
+impl AccountData for RuleEnforcements {
+    const EVENT_TYPE: &'static str = "sh.nao.clobber.rule_enforcements";
+}
+
+/// A protection setting, either turned off or on with the given configuration.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Setting<T> {
+    /// The protection is turned off.
+    Off,
+    /// The protection is turned on.
+    On(T),
+}
+
+/// Settings of the automatic protections for a single room, or the defaults for every protected
+/// room. Settings that are `None` aren't set, and fall back to the defaults.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RoomProtections {
+    /// Limit on how many messages a user may send in a short time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flood: Option<Setting<FloodLimit>>,
+    /// Limit on how many users a single message may mention.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mentions: Option<Setting<MentionLimit>>,
+    /// Users who may mention any number of users, such as announcement accounts.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub mention_allowed: BTreeSet<UserId>,
+    /// Limit on how many users may join a room in a short time before it is locked down.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub joins: Option<Setting<JoinLimit>>,
+    /// Filter on links to the domains on the domain lists.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub links: Option<Setting<LinkFilter>>,
+    /// Words and patterns messages may not contain. Rules of single rooms apply in addition to
+    /// the default rules.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub content: Vec<ContentRule>,
+}
+
+impl RoomProtections {
+    /// Whether none of the settings are set.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.flood.is_none()
+            && self.mentions.is_none()
+            && self.mention_allowed.is_empty()
+            && self.joins.is_none()
+            && self.links.is_none()
+            && self.content.is_empty()
+    }
+}
+
+/// Limit on how many messages a user may send in a room within a sliding window.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FloodLimit {
+    /// Number of messages allowed within the window.
+    pub messages: usize,
+    /// Length of the window in seconds.
+    pub seconds: u64,
+    /// Action to take on users sending more messages than allowed.
+    pub action: Action,
+}
+
+/// Limit on how many users a single message may mention.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MentionLimit {
+    /// Number of users a message may mention.
+    pub mentions: usize,
+    /// Action to take on users mentioning more users than allowed.
+    pub action: Action,
+}
+
+/// Limit on how many users may join a room within a sliding window, and how the room is locked
+/// down once more do.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct JoinLimit {
+    /// Number of joins allowed within the window.
+    pub joins: usize,
+    /// Length of the window in seconds.
+    pub seconds: u64,
+    /// How the room is locked down.
+    #[serde(flatten)]
+    pub lockdown: LockdownMode,
+}
+
+/// How a room is locked down.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LockdownMode {
+    /// Join rule to switch the room to, or `None` to leave it as it is.
+    pub join_rule: Option<LockdownJoinRule>,
+    /// Whether to stop users without a raised power level from sending messages.
+    pub restrict_messages: bool,
+    /// How long the lockdown lasts in seconds, or `None` until it is lifted by hand.
+    pub cooldown: Option<u64>,
+}
+
+impl Default for LockdownMode {
+    fn default() -> Self {
+        Self {
+            join_rule: Some(LockdownJoinRule::Invite),
+            restrict_messages: true,
+            cooldown: None,
+        }
+    }
+}
+
+/// Join rule a room is switched to while it is locked down.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockdownJoinRule {
+    /// Only invited users may join.
+    Invite,
+    /// Users have to knock and be let in.
+    Knock,
+}
+
+impl LockdownJoinRule {
+    /// The matrix join rule.
+    #[must_use]
+    pub const fn join_rule(self) -> JoinRule {
+        match self {
+            Self::Invite => JoinRule::Invite,
+            Self::Knock => JoinRule::Knock,
+        }
+    }
+}
+
+impl std::fmt::Display for LockdownJoinRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Invite => write!(f, "invite"),
+            Self::Knock => write!(f, "knock"),
+        }
+    }
+}
+
+impl std::str::FromStr for LockdownJoinRule {
+    type Err = String;
+
+    fn from_str(join_rule: &str) -> Result<Self, Self::Err> {
+        match join_rule.to_lowercase().as_str() {
+            "invite" => Ok(Self::Invite),
+            "knock" => Ok(Self::Knock),
+            _ => Err(String::from("expected invite or knock")),
+        }
+    }
+}
+
+/// Filter on messages linking to the domains on the [`DomainLists`].
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LinkFilter {
+    /// Action to take on users whose messages were redacted for their links.
+    pub action: Action,
+    /// How long users count as new members after joining, in seconds. New members may only link
+    /// to allowed domains. If `None`, everyone may link to domains that aren't denied.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_member_seconds: Option<u64>,
+}
+
+/// A word or pattern messages may not contain, along with what to do about messages that do.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct ContentRule {
+    /// How `pattern` is matched.
+    pub kind: PatternKind,
+    /// The word or regular expression.
+    pub pattern: String,
+    /// Action to take on users whose messages match, after the messages are redacted.
+    pub action: Action,
+    /// Reason given for the action.
+    #[serde(default)]
+    pub reason: String,
+}
+
+/// How the pattern of a [`ContentRule`] is matched.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PatternKind {
+    /// A whole word or phrase, matched case-insensitively.
+    Word,
+    /// A regular expression, matched anywhere in the message.
+    Regex,
+}
+
+impl std::fmt::Display for PatternKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Word => write!(f, "word"),
+            Self::Regex => write!(f, "regex"),
+        }
+    }
+}
+
+impl std::str::FromStr for PatternKind {
+    type Err = String;
+
+    fn from_str(kind: &str) -> Result<Self, Self::Err> {
+        match kind.to_lowercase().as_str() {
+            "word" => Ok(Self::Word),
+            "regex" => Ok(Self::Regex),
+            _ => Err(String::from("expected word or regex")),
+        }
+    }
+}
+
+/// Settings of the automatic protections of the protected rooms.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Protections {
+    /// Settings applying to every protected room that doesn't have its own.
+    #[serde(default)]
+    pub defaults: RoomProtections,
+    /// Settings of single rooms, overriding the defaults.
+    #[serde(default)]
+    pub rooms: BTreeMap<RoomId, RoomProtections>,
+}
+
+impl Protections {
+    /// The setting in effect in `room`, as picked out of room settings by `field`: the room's own
+    /// if it has one, otherwise the default. Returns `None` if the protection is turned off.
+    pub fn get<'a, T>(
+        &'a self,
+        room: &RoomId,
+        field: impl Fn(&'a RoomProtections) -> &'a Option<Setting<T>>,
+    ) -> Option<&'a T> {
+        let setting = self
+            .rooms
+            .get(room)
+            .and_then(|settings| field(settings).as_ref())
+            .or_else(|| field(&self.defaults).as_ref());
+        match setting {
+            Some(Setting::On(value)) => Some(value),
+            Some(Setting::Off) | None => None,
+        }
+    }
+
+    /// Whether `user` may mention any number of users in `room`, either because they are allowed
+    /// to in every protected room or in `room` in particular.
+    #[must_use]
+    pub fn mention_allowed(&self, room: &RoomId, user: &UserId) -> bool {
+        self.defaults.mention_allowed.contains(user)
+            || self
+                .rooms
+                .get(room)
+                .is_some_and(|settings| settings.mention_allowed.contains(user))
+    }
+
+    /// Content rules in effect in `room`: the default rules followed by the room's own.
+    pub fn content_rules<'a>(
+        &'a self,
+        room: &RoomId,
+    ) -> impl Iterator<Item = &'a ContentRule> + Clone + 'a {
+        self.defaults.content.iter().chain(
+            self.rooms
+                .get(room)
+                .into_iter()
+                .flat_map(|settings| &settings.content),
+        )
+    }
+
+    /// Settings of `room`, or the defaults if `room` is `None`, to be changed.
+    pub fn settings_mut(&mut self, room: Option<&RoomId>) -> &mut RoomProtections {
+        match room {
+            Some(room) => self.rooms.entry(room.clone()).or_default(),
+            None => &mut self.defaults,
+        }
+    }
+}
+
+impl AccountData for Protections {
+    const EVENT_TYPE: &'static str = "sh.nao.clobber.protections";
+}
+
+/// How a locked down room was before the lockdown, so that it can be restored.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Lockdown {
+    /// Join rule of the room before the lockdown, if the lockdown changed it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub join_rule: Option<JoinRule>,
+    /// Power level needed to send messages before the lockdown, if the lockdown changed it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub events_default: Option<i64>,
+    /// When the lockdown is lifted, in seconds since the unix epoch, or `None` if it lasts until
+    /// it is lifted by hand.
+    #[serde(default)]
+    pub expires: Option<u64>,
+}
+
+impl Lockdown {
+    /// Whether the lockdown didn't change anything, so there is nothing to restore.
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.join_rule.is_none() && self.events_default.is_none()
+    }
+}
+
+/// Held while rooms are locked down or their lockdowns lifted, so that concurrent event handlers
+/// and commands don't lock down the same room twice or overwrite each other's changes.
+static LOCKDOWNS: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Rooms that are locked down.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Lockdowns {
+    /// Locked down rooms, along with how they were before.
+    #[serde(default)]
+    pub rooms: BTreeMap<RoomId, Lockdown>,
+}
+
+impl Lockdowns {
+    /// Lock the lockdowns until the guard is dropped. Rooms must only be locked down or have
+    /// their lockdowns lifted while holding it.
+    pub async fn lock() -> tokio::sync::MutexGuard<'static, ()> {
+        LOCKDOWNS.lock().await
+    }
+
+    /// Record the lockdown of `room`, unless it changed nothing or the room is already locked
+    /// down, returning whether it was recorded. An earlier lockdown is never replaced, as it
+    /// holds how the room was before either of them.
+    pub fn add(&mut self, room: RoomId, lockdown: Lockdown) -> bool {
+        if lockdown.is_empty() || self.rooms.contains_key(&room) {
+            return false;
+        }
+        self.rooms.insert(room, lockdown);
+        true
+    }
+
+    /// The lockdowns that have expired by `now`, in seconds since the unix epoch.
+    #[must_use]
+    pub fn expired(&self, now: u64) -> Vec<(RoomId, Lockdown)> {
+        self.rooms
+            .iter()
+            .filter(|(_, lockdown)| lockdown.expires.is_some_and(|expires| expires <= now))
+            .map(|(room, lockdown)| (room.clone(), lockdown.clone()))
+            .collect()
+    }
+}
+
+impl AccountData for Lockdowns {
+    const EVENT_TYPE: &'static str = "sh.nao.clobber.lockdowns";
+}
+
+/// Held while the mutes are loaded, changed and saved again, so that concurrent event handlers
+/// don't overwrite each other's changes.
+static MUTES: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Power levels muted and quarantined users had before, so that unmuting them restores it.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Mutes {
+    /// Muted users by room, along with the power level they had before.
+    #[serde(default)]
+    pub rooms: BTreeMap<RoomId, BTreeMap<UserId, i64>>,
+}
+
+impl Mutes {
+    /// Lock the mutes until the guard is dropped. Changes must be loaded and saved while holding
+    /// it.
+    pub async fn lock() -> tokio::sync::MutexGuard<'static, ()> {
+        MUTES.lock().await
+    }
+
+    /// Load the mutes, change them and save them again if `change` returns `true`, holding the
+    /// lock throughout.
+    pub async fn update(client: &Client, change: impl FnOnce(&mut Self) -> bool) -> Result<()> {
+        let _lock = Self::lock().await;
+        let mut mutes = Self::load(client).await?;
+        if change(&mut mutes) {
+            mutes.save(client).await?;
+        }
+        Ok(())
+    }
+
+    /// Record that `user` had power level `level` in `room` before being muted, returning
+    /// whether it was recorded. An earlier record is never replaced, as it holds the level the
+    /// user had before either restriction.
+    pub fn add(&mut self, room: RoomId, user: UserId, level: i64) -> bool {
+        let users = self.rooms.entry(room).or_default();
+        if users.contains_key(&user) {
+            return false;
+        }
+        users.insert(user, level);
+        true
+    }
+
+    /// The power level `user` had in `room` before being muted, if it was recorded.
+    #[must_use]
+    pub fn get(&self, room: &RoomId, user: &UserId) -> Option<i64> {
+        self.rooms.get(room)?.get(user).copied()
+    }
+
+    /// Forget the power level `user` had in `room` before being muted, returning it.
+    pub fn remove(&mut self, room: &RoomId, user: &UserId) -> Option<i64> {
+        let users = self.rooms.get_mut(room)?;
+        let level = users.remove(user);
+        if users.is_empty() {
+            self.rooms.remove(room);
+        }
+        level
+    }
+}
+
+impl AccountData for Mutes {
+    const EVENT_TYPE: &'static str = "sh.nao.clobber.mutes";
+}
+
+/// Domains messages in protected rooms may or may not link to, as globs such as `*.example.org`.
+#[derive(Clone, Debug, Default, Hash, Deserialize, Serialize)]
+pub struct DomainLists {
+    /// Domains no one may link to.
+    #[serde(default)]
+    pub denied: BTreeSet<String>,
+    /// Domains new members may link to, if the link filter restricts them.
+    #[serde(default)]
+    pub allowed: BTreeSet<String>,
+}
+
+impl AccountData for DomainLists {
+    const EVENT_TYPE: &'static str = "sh.nao.clobber.domain_lists";
+}
@@ -0,0 +1,166 @@
+// Clobber - a matrix moderation bot
+// Copyright (C) 2020 Emelie <em@nao.sh>
+// Licensed under the EUPL
+
+//! Temporary bans and mutes, which are lifted again once they expire.
+//!
+//! Pending expiries are kept in the data directory, so they survive restarts of the bot.
+
+use anyhow::Result;
+use matrix_sdk::ruma::{RoomId, UserId};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+#[allow(unused_imports)]
+use tracing::{debug, error, info, warn};
+
+use crate::config::get_data_dir;
+
+/// Name of the file in the data directory timers are stored in.
+const TIMERS_FILE: &str = "timers.json";
+
+/// Serializes changes to the timers file between command handlers and the expiry task.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// What to do once a timer expires.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum TimerKind {
+    /// Lift a temporary ban.
+    Unban,
+    /// Lift a temporary mute.
+    Unmute,
+}
+
+impl std::fmt::Display for TimerKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unban => write!(f, "unban"),
+            Self::Unmute => write!(f, "unmute"),
+        }
+    }
+}
+
+/// A temporary ban or mute of a user in a room.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Timer {
+    /// What to do once the timer expires.
+    pub kind: TimerKind,
+    /// The room the user was banned from or muted in.
+    pub room: RoomId,
+    /// The banned or muted user.
+    pub user: UserId,
+    /// When the timer expires, in seconds since the unix epoch.
+    pub expires: u64,
+}
+
+impl Timer {
+    /// Create a timer expiring `duration` from now.
+    #[must_use]
+    pub fn new(kind: TimerKind, room: RoomId, user: UserId, duration: Duration) -> Self {
+        Self {
+            kind,
+            room,
+            user,
+            expires: now().saturating_add(duration.as_secs()),
+        }
+    }
+
+    /// Time left until the timer expires.
+    #[must_use]
+    pub fn remaining(&self) -> Duration {
+        Duration::from_secs(self.expires.saturating_sub(now()))
+    }
+}
+
+/// All pending timers.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Timers {
+    /// Pending timers, in no particular order.
+    #[serde(default)]
+    pub timers: Vec<Timer>,
+}
+
+impl Timers {
+    /// Load the pending timers, applying `change` to them and saving the result before
+    /// returning what it returned.
+    pub fn update<T>(change: impl FnOnce(&mut Self) -> T) -> Result<T> {
+        let _lock = LOCK
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        let mut timers = Self::load()?;
+        let result = change(&mut timers);
+        timers.save()?;
+        Ok(result)
+    }
+
+    /// Load the pending timers, sorted by when they expire.
+    pub fn pending() -> Result<Vec<Timer>> {
+        let _lock = LOCK
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        let mut timers = Self::load()?.timers;
+        timers.sort_by_key(|timer| timer.expires);
+        Ok(timers)
+    }
+
+    /// Add a timer, replacing any earlier timer of the same kind for the same user and room.
+    pub fn add(&mut self, timer: Timer) {
+        self.remove(timer.kind, &timer.room, &timer.user);
+        self.timers.push(timer);
+    }
+
+    /// Remove the timer of the given kind for `user` in `room`, returning whether there was one.
+    pub fn remove(&mut self, kind: TimerKind, room: &RoomId, user: &UserId) -> bool {
+        let before = self.timers.len();
+        self.timers
+            .retain(|timer| timer.kind != kind || &timer.room != room || &timer.user != user);
+        self.timers.len() != before
+    }
+
+    /// Remove `timer` once it has been lifted, returning whether it was still pending. A timer
+    /// that has been replaced in the meantime is left in place.
+    pub fn finish(&mut self, timer: &Timer) -> bool {
+        let before = self.timers.len();
+        self.timers.retain(|pending| pending != timer);
+        self.timers.len() != before
+    }
+
+    /// Move the expiry of `timer` to `expires`, in seconds since the unix epoch, so that it is
+    /// tried again later. Returns whether it was still pending.
+    pub fn postpone(&mut self, timer: &Timer, expires: u64) -> bool {
+        match self.timers.iter_mut().find(|pending| *pending == timer) {
+            Some(pending) => {
+                pending.expires = expires;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Read timers from the data directory, or none if they were never saved.
+    fn load() -> Result<Self> {
+        match fs::read(get_data_dir()?.join(TIMERS_FILE)) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Write timers to the data directory.
+    fn save(&self) -> Result<()> {
+        let data = serde_json::to_string_pretty(&self)?;
+        fs::write(get_data_dir()?.join(TIMERS_FILE), data)?;
+        Ok(())
+    }
+}
+
+/// Current time in seconds since the unix epoch.
+#[must_use]
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
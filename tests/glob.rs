@@ -0,0 +1,168 @@
+// Clobber - a matrix moderation bot
+// Copyright (C) 2020 Emelie Graven <em@nao.sh>
+// Licensed under the EUPL
+
+use std::convert::TryFrom;
+
+use clobber::{
+    bot::List,
+    glob::{host, Glob, GlobSet},
+    policy::{Action, Rule, RuleIndex},
+};
+use matrix_sdk::ruma::{RoomId, UserId};
+
+#[test]
+fn wildcards() {
+    let subdomains = Glob::new("*.example.org");
+    assert!(subdomains.is_match("matrix.example.org"));
+    assert!(subdomains.is_match("a.b.example.org"));
+    assert!(!subdomains.is_match("example.org"));
+    assert!(!subdomains.is_match("example.org.evil"));
+
+    let single = Glob::new("@spam?:example.org");
+    assert!(single.is_match("@spam1:example.org"));
+    assert!(single.is_match("@spamé:example.org"));
+    assert!(!single.is_match("@spam:example.org"));
+    assert!(!single.is_match("@spam12:example.org"));
+
+    let stars = Glob::new("@***bot**:*");
+    assert!(stars.is_match("@bot:example.org"));
+    assert!(stars.is_match("@spambot2:example.org"));
+    assert!(!stars.is_match("@spam:example.org"));
+
+    assert!(Glob::new("*").is_match(""));
+    assert!(Glob::new("example.org").is_literal());
+    assert!(!Glob::new("1.2.3.*").is_literal());
+}
+
+#[test]
+fn glob_sets() {
+    let mut set = GlobSet::new();
+    set.insert("*.example.org", "suffix");
+    set.insert("example.org", "exact");
+    set.insert("1.2.3.*", "prefix");
+    set.insert("*evil*", "other");
+    set.insert("*", "everything");
+    assert_eq!(set.len(), 5);
+
+    assert_eq!(set.matches("example.org"), [&"exact", &"everything"]);
+    assert_eq!(
+        set.matches("evil.example.org"),
+        [&"suffix", &"other", &"everything"]
+    );
+    assert_eq!(set.matches("1.2.3.4"), [&"prefix", &"everything"]);
+    assert!(GlobSet::<()>::new().matches("example.org").is_empty());
+}
+
+#[test]
+fn server_hosts() {
+    assert_eq!(host("example.org"), "example.org");
+    assert_eq!(host("example.org:8448"), "example.org");
+    assert_eq!(host("1.2.3.4:80"), "1.2.3.4");
+    assert_eq!(host("[::1]:8448"), "[::1]");
+    assert_eq!(host("[::1]"), "[::1]");
+    assert_eq!(host("[2001:db8::1]:8448"), "[2001:db8::1]");
+    // Unbracketed IPv6 addresses and globs have no port to strip
+    assert_eq!(host("2001:db8::1"), "2001:db8::1");
+    assert_eq!(host("2001:db8::*"), "2001:db8::*");
+    assert_eq!(host("[2001:db8::*"), "[2001:db8::*");
+}
+
+fn rule(kind: &str, entity: &str) -> Rule {
+    Rule {
+        list: RoomId::try_from("!list:example.org").unwrap(),
+        event_type: format!("m.policy.rule.{}", kind),
+        state_key: entity.to_owned(),
+        rule: List::new(kind, entity.to_owned(), Action::Ban, String::new()).unwrap(),
+    }
+}
+
+fn matching(rules: &RuleIndex, user: &str) -> Vec<String> {
+    rules
+        .matching(&UserId::try_from(user).unwrap())
+        .into_iter()
+        .map(|rule| rule.rule.entity().to_owned())
+        .collect()
+}
+
+#[test]
+fn rule_index() {
+    let rules = RuleIndex::new(vec![
+        rule("user", "@spam*:example.org"),
+        rule("server", "Evil.Example"),
+        rule("server", "*.evil.example"),
+        rule("server", "1.2.3.*"),
+        rule("room", "#spam:example.org"),
+    ]);
+    assert_eq!(
+        matching(&rules, "@spambot:example.org"),
+        ["@spam*:example.org"]
+    );
+    assert!(matching(&rules, "@alice:example.org").is_empty());
+
+    // Server rules are case-insensitive and apply to every port of a server
+    assert_eq!(matching(&rules, "@a:evil.example"), ["Evil.Example"]);
+    assert_eq!(matching(&rules, "@a:EVIL.example:8448"), ["Evil.Example"]);
+    assert_eq!(
+        matching(&rules, "@a:matrix.evil.example"),
+        ["*.evil.example"]
+    );
+    assert_eq!(matching(&rules, "@a:1.2.3.4:8448"), ["1.2.3.*"]);
+    assert!(matching(&rules, "@a:1.2.30.4").is_empty());
+}
+
+#[test]
+fn rule_index_updates() {
+    let mut rules = RuleIndex::new(vec![
+        rule("user", "@spam*:example.org"),
+        rule("server", "evil.example"),
+    ]);
+    let spam = rule("user", "@spam*:example.org");
+    let removed = rules.remove(&spam.key()).unwrap();
+    assert_eq!(removed.rule.entity(), "@spam*:example.org");
+    assert!(matching(&rules, "@spambot:example.org").is_empty());
+    assert_eq!(rules.len(), 1);
+
+    // Replacing a rule keeps a single copy of it
+    rules.insert(spam.clone());
+    rules.insert(spam);
+    assert_eq!(
+        matching(&rules, "@spambot:example.org"),
+        ["@spam*:example.org"]
+    );
+    assert_eq!(rules.len(), 2);
+    assert_eq!(rules.rules().count(), 2);
+
+    rules.remove_list(&RoomId::try_from("!list:example.org").unwrap());
+    assert!(rules.is_empty());
+    assert!(matching(&rules, "@a:evil.example").is_empty());
+}
+
+#[test]
+fn most_severe_rule() {
+    let list = RoomId::try_from("!list:example.org").unwrap();
+    let with_action = |kind: &str, entity: &str, action: Action| Rule {
+        list: list.clone(),
+        event_type: format!("m.policy.rule.{}", kind),
+        state_key: entity.to_owned(),
+        rule: List::new(kind, entity.to_owned(), action, String::new()).unwrap(),
+    };
+    let rules = RuleIndex::new(vec![
+        with_action("user", "@spam*:example.org", Action::Warn),
+        with_action("server", "example.org", Action::Kick),
+        with_action("user", "@spambot:example.org", Action::Ban),
+        with_action("user", "@spam?ot:example.org", Action::Ban),
+    ]);
+    let rule = |user| {
+        rules
+            .most_severe(&UserId::try_from(user).unwrap())
+            .map(|rule| rule.rule.entity().to_owned())
+    };
+    // Later, more severe rules win over earlier ones, and the first wins among equals
+    assert_eq!(
+        rule("@spambot:example.org").as_deref(),
+        Some("@spambot:example.org")
+    );
+    assert_eq!(rule("@spam:example.org").as_deref(), Some("example.org"));
+    assert!(rule("@spam:example.com").is_none());
+}
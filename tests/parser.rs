@@ -0,0 +1,155 @@
+// Clobber - a matrix moderation bot
+// Copyright (C) 2020 Emelie Graven <em@nao.sh>
+// Licensed under the EUPL
+
+use std::{convert::TryFrom, time::Duration};
+
+use clobber::{
+    bot::{find_command, find_subcommand},
+    parser::{
+        parse_duration, strip_mention, strip_prefix, strip_reply_fallback,
+        strip_reply_fallback_html, tokenize, Arguments, ParseError, UserTarget,
+    },
+};
+use matrix_sdk::ruma::UserId;
+
+fn tokens(input: &str) -> Vec<String> {
+    tokenize(input).unwrap()
+}
+
+#[test]
+fn tokenize_quotes_and_whitespace() {
+    assert_eq!(
+        tokens("  ban   @spam:example.org \"raiding and spam\"  "),
+        vec!["ban", "@spam:example.org", "raiding and spam"]
+    );
+    assert_eq!(
+        tokens(r#"a 'b "c"' "d \"e\"" f\ g"#),
+        vec!["a", "b \"c\"", "d \"e\"", "f g"]
+    );
+    assert_eq!(tokens("a \"\" b"), vec!["a", "", "b"]);
+    assert_eq!(
+        tokenize("ban \"unterminated"),
+        Err(ParseError::UnterminatedQuote)
+    );
+}
+
+#[test]
+fn prefixes() {
+    assert_eq!(strip_prefix("?clobber ban", "?clobber"), Some(" ban"));
+    assert_eq!(strip_prefix("?clobbering", "?clobber"), None);
+    assert_eq!(strip_prefix("!ban @a:b", "!"), Some("ban @a:b"));
+    assert_eq!(strip_prefix("¡ban", "¡"), Some("ban"));
+    assert_eq!(strip_prefix("hello", "!"), None);
+}
+
+#[test]
+fn flags_and_typed_arguments() {
+    let redact = find_command("redact").unwrap();
+    let arguments = Arguments::parse(
+        redact,
+        &tokens("@spam:example.org 10 --reason \"spam links\""),
+    )
+    .unwrap();
+    assert_eq!(arguments.flag_value("reason"), Some("spam links"));
+    assert_eq!(arguments.number::<usize>(1), Ok(Some(10)));
+    assert!(arguments.user(0).is_ok());
+
+    assert_eq!(
+        Arguments::parse(redact, &tokens("@spam:example.org --bogus")).unwrap_err(),
+        ParseError::UnknownFlag("bogus".to_owned())
+    );
+    assert_eq!(
+        Arguments::parse(redact, &tokens("@spam:example.org --reason")).unwrap_err(),
+        ParseError::MissingFlagValue("reason".to_owned())
+    );
+
+    let ban = find_command("ban").unwrap();
+    let arguments = Arguments::parse(ban, &tokens("*:evil.example -- --not-a-flag")).unwrap();
+    assert_eq!(
+        arguments.user_target(0),
+        Ok(UserTarget::Glob("*:evil.example".to_owned()))
+    );
+    assert_eq!(arguments.rest(1).as_deref(), Some("--not-a-flag"));
+    assert!(matches!(
+        Arguments::parse(ban, &tokens("not-a-user"))
+            .unwrap()
+            .user_target(0),
+        Err(ParseError::InvalidArgument { .. })
+    ));
+}
+
+#[test]
+fn subcommands() {
+    let list = find_command("list").unwrap();
+    assert_eq!(
+        list.usage(),
+        "list <create|add|remove|show|watch|unwatch|simulate>"
+    );
+    assert!(find_subcommand(list, "bogus").is_none());
+
+    let add = find_subcommand(list, "add").unwrap();
+    let arguments = Arguments::parse(
+        add,
+        &tokens("user *:evil.example ban --list #list:example.org \"spam\""),
+    )
+    .unwrap();
+    assert_eq!(arguments.flag_value("list"), Some("#list:example.org"));
+    assert_eq!(arguments.rest(3).as_deref(), Some("spam"));
+    assert_eq!(
+        Arguments::parse(add, &tokens("user")).unwrap().required(1),
+        Err(ParseError::MissingArgument("entity".to_owned()))
+    );
+    assert_eq!(find_subcommand(list, "revoke").unwrap().name, "remove");
+}
+
+#[test]
+fn durations() {
+    assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+    assert_eq!(parse_duration("2h"), Some(Duration::from_secs(2 * 60 * 60)));
+    assert_eq!(
+        parse_duration("7d"),
+        Some(Duration::from_secs(7 * 24 * 60 * 60))
+    );
+    assert_eq!(parse_duration("0m"), None);
+    assert_eq!(parse_duration("5y"), None);
+    assert_eq!(parse_duration("h"), None);
+}
+
+#[test]
+fn mentions_and_replies() {
+    let bot = UserId::try_from("@clobber:example.org").unwrap();
+    assert_eq!(
+        strip_mention("@clobber:example.org: ban @a:b", None, &bot),
+        Some(" ban @a:b")
+    );
+    assert_eq!(
+        strip_mention(
+            "Clobber &co: kick @a:b",
+            Some(
+                r#"<a href="https://matrix.to/#/@clobber:example.org">Clobber &amp;co</a>: kick @a:b"#
+            ),
+            &bot
+        ),
+        Some(" kick @a:b")
+    );
+    assert_eq!(
+        strip_mention(
+            "Someone: kick @a:b",
+            Some(r#"<a href="https://matrix.to/#/@someone:example.org">Someone</a>: kick @a:b"#),
+            &bot
+        ),
+        None
+    );
+    assert_eq!(strip_mention("@clobber:example.organ", None, &bot), None);
+
+    assert_eq!(
+        strip_reply_fallback("> <@spam:example.org> buy now\n> more\n\nban spam"),
+        "ban spam"
+    );
+    assert_eq!(strip_reply_fallback("ban spam"), "ban spam");
+    assert_eq!(
+        strip_reply_fallback_html("<mx-reply><blockquote>buy now</blockquote></mx-reply>ban spam"),
+        "ban spam"
+    );
+}
@@ -0,0 +1,248 @@
+// Clobber - a matrix moderation bot
+// Copyright (C) 2020 Emelie Graven <em@nao.sh>
+// Licensed under the EUPL
+
+use std::{collections::BTreeSet, convert::TryFrom};
+
+use clobber::{
+    bot::{List, RuleFormat},
+    policy::{
+        relaxed_server_acl, rule_change, server_acl, server_acl_deny, Action, Rule, RuleChange,
+    },
+};
+use matrix_sdk::ruma::{events::room::server_acl::ServerAclEventContent, RoomId};
+use serde_json::{json, value::to_raw_value};
+
+fn rule(event: serde_json::Value) -> Option<List> {
+    serde_json::from_value(event).ok()
+}
+
+#[test]
+fn standard_and_legacy_rules() {
+    let standard = rule(json!({
+        "type": "m.policy.rule.user",
+        "state_key": "@spam:example.org",
+        "content": {
+            "entity": "@spam:example.org",
+            "recommendation": "m.ban",
+            "reason": "spam"
+        }
+    }))
+    .unwrap();
+    assert!(matches!(
+        &standard,
+        List::User { entity, action: Action::Ban, reason }
+            if entity == "@spam:example.org" && reason == "spam"
+    ));
+
+    let legacy = rule(json!({
+        "type": "sh.nao.list.server",
+        "content": { "entity": "*.evil.example", "action": "Ban", "reason": "raids" }
+    }))
+    .unwrap();
+    assert!(matches!(&legacy, List::Server { entity, .. } if entity == "*.evil.example"));
+
+    let mjolnir = rule(json!({
+        "type": "org.matrix.mjolnir.rule.room",
+        "content": { "entity": "#spam:example.org", "recommendation": "org.matrix.mjolnir.ban" }
+    }))
+    .unwrap();
+    assert!(matches!(&mjolnir, List::Room { reason, .. } if reason.is_empty()));
+}
+
+#[test]
+fn revoked_and_unknown_rules() {
+    // Rules are revoked by replacing their content with an empty object
+    assert!(rule(json!({ "type": "m.policy.rule.user", "content": {} })).is_none());
+    assert!(rule(json!({
+        "type": "m.policy.rule.user",
+        "content": { "entity": "@a:b", "recommendation": "m.something_else" }
+    }))
+    .is_none());
+    assert!(rule(json!({
+        "type": "m.room.message",
+        "content": { "entity": "@a:b", "recommendation": "m.ban" }
+    }))
+    .is_none());
+}
+
+#[test]
+fn rule_formats() {
+    let rule = List::new(
+        "server",
+        "evil.example".to_owned(),
+        Action::Ban,
+        "raids".to_owned(),
+    )
+    .unwrap();
+    assert_eq!(
+        rule.event_type(RuleFormat::Standard),
+        "m.policy.rule.server"
+    );
+    assert_eq!(rule.event_type(RuleFormat::Legacy), "sh.nao.list.server");
+    assert_eq!(
+        rule.content(RuleFormat::Standard),
+        json!({ "entity": "evil.example", "recommendation": "m.ban", "reason": "raids" })
+    );
+    assert_eq!(
+        rule.content(RuleFormat::Legacy),
+        json!({ "entity": "evil.example", "action": "Ban", "reason": "raids" })
+    );
+
+    // Rules serialize as standard events, and read back the same
+    let event = serde_json::to_value(&rule).unwrap();
+    assert_eq!(event["type"], "m.policy.rule.server");
+    let read: List = serde_json::from_value(event).unwrap();
+    assert!(matches!(read, List::Server { entity, .. } if entity == "evil.example"));
+    assert!(List::new("bogus", String::new(), Action::Ban, String::new()).is_none());
+}
+
+#[test]
+fn rule_changes() {
+    let list = RoomId::try_from("!list:example.org").unwrap();
+    let added = to_raw_value(&json!({
+        "type": "m.policy.rule.user",
+        "state_key": "@spam:example.org",
+        "content": { "entity": "@spam:example.org", "recommendation": "m.ban" }
+    }))
+    .unwrap();
+    assert!(matches!(
+        rule_change(&list, &added),
+        Some(RuleChange::Added(rule)) if rule.list == list
+    ));
+
+    let revoked = to_raw_value(&json!({
+        "type": "sh.nao.list.server",
+        "state_key": "evil.example",
+        "content": {}
+    }))
+    .unwrap();
+    assert!(matches!(
+        rule_change(&list, &revoked),
+        Some(RuleChange::Revoked { kind: "server", entity, .. }) if entity == "evil.example"
+    ));
+
+    // Rules published under a state key other than their entity are revoked by that state key
+    let added = to_raw_value(&json!({
+        "type": "m.policy.rule.user",
+        "state_key": "rule:@spam:example.org",
+        "content": { "entity": "@spam:example.org", "recommendation": "m.ban" }
+    }))
+    .unwrap();
+    let revoked = to_raw_value(&json!({
+        "type": "m.policy.rule.user",
+        "state_key": "rule:@spam:example.org",
+        "content": {},
+        "unsigned": {
+            "prev_content": { "entity": "@spam:example.org", "recommendation": "m.ban" }
+        }
+    }))
+    .unwrap();
+    let added = rule_change(&list, &added).unwrap();
+    let revoked = rule_change(&list, &revoked).unwrap();
+    assert_eq!(added.key(), revoked.key());
+    assert_eq!(added.key().state_key, "rule:@spam:example.org");
+    assert!(matches!(
+        revoked,
+        RuleChange::Revoked { entity, .. } if entity == "@spam:example.org"
+    ));
+    // Standard and legacy events for the same entity are separate rules
+    let legacy = to_raw_value(&json!({
+        "type": "sh.nao.list.user",
+        "state_key": "rule:@spam:example.org",
+        "content": {}
+    }))
+    .unwrap();
+    assert_ne!(rule_change(&list, &legacy).unwrap().key(), added.key());
+
+    let message = to_raw_value(&json!({
+        "type": "m.room.message",
+        "content": { "msgtype": "m.text", "body": "hi" }
+    }))
+    .unwrap();
+    assert!(rule_change(&list, &message).is_none());
+}
+
+#[test]
+fn server_acls() {
+    let list = RoomId::try_from("!list:example.org").unwrap();
+    let rules: Vec<Rule> = [
+        ("server", "Evil.Example:8448"),
+        ("server", "*.spam.example"),
+        ("server", "*.org"),
+        ("user", "@spam:example.org"),
+    ]
+    .iter()
+    .map(|(kind, entity)| Rule {
+        list: list.clone(),
+        event_type: format!("m.policy.rule.{}", kind),
+        state_key: (*entity).to_owned(),
+        rule: List::new(kind, (*entity).to_owned(), Action::Ban, String::new()).unwrap(),
+    })
+    .collect();
+
+    // `*.org` would deny the bot's own server
+    let denied_by = server_acl_deny(&rules, "matrix.example.org:8448");
+    assert_eq!(
+        denied_by.keys().map(String::as_str).collect::<Vec<_>>(),
+        ["*.spam.example", "evil.example"]
+    );
+    assert_eq!(denied_by["evil.example"].rule.entity(), "Evil.Example:8448");
+    let deny: BTreeSet<String> = denied_by.keys().cloned().collect();
+
+    let created = server_acl(None, &deny).unwrap();
+    assert_eq!(created.content.allow, ["*"]);
+    assert_eq!(created.content.deny, ["*.spam.example", "evil.example"]);
+
+    // Existing entries are kept, and only missing ones are reported
+    let current = ServerAclEventContent::new(
+        false,
+        vec!["*".to_owned()],
+        vec!["manual.example".to_owned(), "evil.example".to_owned()],
+    );
+    let updated = server_acl(Some(current), &deny).unwrap();
+    assert_eq!(updated.denied, ["*.spam.example"]);
+    assert!(!updated.content.allow_ip_literals);
+    assert_eq!(
+        updated.content.deny,
+        ["manual.example", "evil.example", "*.spam.example"]
+    );
+    assert!(server_acl(Some(updated.content.clone()), &deny).is_none());
+
+    // Relaxing only removes the given entries
+    let lift: BTreeSet<String> = ["evil.example".to_owned(), "other.example".to_owned()]
+        .iter()
+        .cloned()
+        .collect();
+    let relaxed = relaxed_server_acl(updated.content, &lift).unwrap();
+    assert_eq!(relaxed.lifted, ["evil.example"]);
+    assert_eq!(relaxed.content.deny, ["manual.example", "*.spam.example"]);
+    assert!(relaxed_server_acl(relaxed.content, &lift).is_none());
+}
+
+#[test]
+fn actions() {
+    for action in Action::ALL.iter() {
+        assert_eq!(
+            Action::from_recommendation(action.recommendation()).as_ref(),
+            Some(action)
+        );
+        assert_eq!(action.to_string().parse::<Action>().as_ref(), Ok(action));
+    }
+    assert_eq!("Redact-And-Ban".parse::<Action>(), Ok(Action::RedactAndBan));
+    assert!("explode".parse::<Action>().is_err());
+
+    let standard = rule(json!({
+        "type": "m.policy.rule.user",
+        "content": { "entity": "@a:b", "recommendation": "sh.nao.clobber.quarantine" }
+    }))
+    .unwrap();
+    assert_eq!(standard.action(), &Action::Quarantine);
+    let legacy = rule(json!({
+        "type": "sh.nao.list.user",
+        "content": { "entity": "@a:b", "action": "RedactAndBan" }
+    }))
+    .unwrap();
+    assert_eq!(legacy.action(), &Action::RedactAndBan);
+    assert_eq!(legacy.content(RuleFormat::Legacy)["action"], "RedactAndBan");
+}
@@ -0,0 +1,248 @@
+// Clobber - a matrix moderation bot
+// Copyright (C) 2020 Emelie Graven <em@nao.sh>
+// Licensed under the EUPL
+
+use std::{
+    collections::BTreeSet,
+    convert::TryFrom,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use clobber::{
+    policy::Action,
+    protections::{
+        content_filter, domain_filter, link_hosts, mentioned_users, message_text, ContentFilter,
+        JoinTimes, RateTracker,
+    },
+    state::{ContentRule, DomainLists, PatternKind, Protections},
+};
+use matrix_sdk::ruma::{
+    events::room::message::{EmoteMessageEventContent, MessageEventContent, MessageType},
+    RoomId, UserId,
+};
+
+#[test]
+fn rate_tracker() {
+    let room = RoomId::try_from("!room:example.org").unwrap();
+    let other_room = RoomId::try_from("!other:example.org").unwrap();
+    let user = UserId::try_from("@a:example.org").unwrap();
+    let start = Instant::now();
+    let window = Duration::from_secs(10);
+    let mut tracker = RateTracker::new();
+    let mut record = |room: &RoomId, seconds| {
+        tracker.record(
+            (room.clone(), user.clone()),
+            start + Duration::from_secs(seconds),
+            3,
+            window,
+        )
+    };
+
+    for seconds in 0..3 {
+        assert_eq!(record(&room, seconds), None);
+    }
+    // Messages in other rooms are counted separately
+    assert_eq!(record(&other_room, 3), None);
+    assert_eq!(record(&room, 4), Some(4));
+    // Users are only acted on once, as their messages are forgotten after exceeding the limit
+    assert_eq!(record(&room, 5), None);
+
+    // Messages older than the window no longer count
+    for seconds in [20, 31, 32, 33] {
+        assert_eq!(record(&room, seconds), None);
+    }
+    assert_eq!(record(&room, 34), Some(4));
+
+    // Keys that went quiet are forgotten periodically, once outside the largest window
+    let mut tracker = RateTracker::new();
+    tracker.record("slow", start, 10, Duration::from_secs(600));
+    tracker.record("fast", start, 10, window);
+    tracker.record("other", start + Duration::from_secs(100), 10, window);
+    assert_eq!(tracker.len(), 3);
+    tracker.record("other", start + Duration::from_secs(700), 10, window);
+    assert_eq!(tracker.len(), 1);
+}
+
+fn users(users: &[&str]) -> Vec<UserId> {
+    users
+        .iter()
+        .map(|user| UserId::try_from(*user).unwrap())
+        .collect()
+}
+
+#[test]
+fn mentions() {
+    let members = users(&[
+        "@alice:example.org",
+        "@bob:example.org",
+        "@carol:example.org",
+        "@frank:example.org",
+        "@mallory:example.org",
+        "@grace:example.org",
+        "@heidi:example.org",
+    ]);
+    let names = [
+        Some("Alice"),
+        Some("Bob Smith"),
+        None,
+        Some("Bob"),
+        Some("Mallory"),
+        Some("Same Name"),
+        Some("same name"),
+    ];
+    let members = || members.iter().zip(names.iter().copied());
+    let sender = UserId::try_from("@mallory:example.org").unwrap();
+
+    let mentioned = mentioned_users(
+        &sender,
+        "Hey @dave:example.org, (@erin:example.org): alice and BOB-smith!",
+        None,
+        members(),
+    );
+    assert_eq!(
+        mentioned.into_iter().collect::<Vec<_>>(),
+        users(&[
+            "@alice:example.org",
+            "@bob:example.org",
+            "@dave:example.org",
+            "@erin:example.org"
+        ])
+    );
+
+    // Display names only count as whole words, and pills count once along with their text
+    let mentioned = mentioned_users(
+        &sender,
+        "Alicea: Carol",
+        Some(
+            "<a href=\"https://matrix.to/#/%40carol%3Aexample.org\">Carol</a> \
+             <a href='matrix:u/dave:example.org?action=chat'>Dave</a> \
+             <a href=\"https://matrix.to/#/#room:example.org\">room</a>",
+        ),
+        members(),
+    );
+    assert_eq!(
+        mentioned.into_iter().collect::<Vec<_>>(),
+        users(&["@carol:example.org", "@dave:example.org"])
+    );
+    assert!(mentioned_users(&sender, "Just an email@example", None, members()).is_empty());
+
+    // Short names, names shared by several members and the sender don't count
+    assert!(mentioned_users(
+        &sender,
+        "bob, same name, mallory and @mallory:example.org",
+        None,
+        members()
+    )
+    .is_empty());
+}
+
+#[test]
+fn message_texts() {
+    let text = MessageEventContent::text_html("plain", "<b>html</b>");
+    assert_eq!(message_text(&text), Some(("plain", Some("<b>html</b>"))));
+    let notice = MessageEventContent::notice_plain("notice");
+    assert_eq!(message_text(&notice), Some(("notice", None)));
+    let emote =
+        MessageEventContent::new(MessageType::Emote(EmoteMessageEventContent::plain("waves")));
+    assert_eq!(message_text(&emote), Some(("waves", None)));
+}
+
+fn set(items: &[&str]) -> BTreeSet<String> {
+    items.iter().map(|item| (*item).to_owned()).collect()
+}
+
+#[test]
+fn links() {
+    let hosts = link_hosts(
+        "See HTTPS://Evil.Example:8443/path?q=1, http://user@1.2.3.4/ and www.spam.example. \
+         Also https://matrix.to/#/@a:example.org and awww.nope",
+        Some("<a href=\"https://cdn.evil.example/x\">here</a>"),
+    );
+    assert_eq!(
+        hosts,
+        set(&[
+            "1.2.3.4",
+            "cdn.evil.example",
+            "evil.example",
+            "www.spam.example"
+        ])
+    );
+    assert!(link_hosts("no links, just example.org", None).is_empty());
+
+    let lists = DomainLists {
+        denied: set(&["*.Evil.Example", "1.2.3.*"]),
+        allowed: set(&["www.spam.example"]),
+    };
+    let filter = domain_filter(&lists);
+    assert_eq!(filter.denied(&hosts), ["1.2.3.4", "cdn.evil.example"]);
+    assert_eq!(filter.allowed(&hosts), ["www.spam.example"]);
+    // The filter is only compiled again once the lists change
+    assert!(Arc::ptr_eq(&filter, &domain_filter(&lists.clone())));
+    let lists = DomainLists {
+        denied: BTreeSet::new(),
+        ..lists
+    };
+    let filter = domain_filter(&lists);
+    assert!(filter.denied(&hosts).is_empty());
+}
+
+#[test]
+fn join_times() {
+    let room = RoomId::try_from("!room:example.org").unwrap();
+    let user = UserId::try_from("@a:example.org").unwrap();
+    let other = UserId::try_from("@b:example.org").unwrap();
+    let start = Instant::now();
+    let mut joins = JoinTimes::new();
+    joins.record(room.clone(), user.clone(), start, Duration::from_secs(60));
+    assert_eq!(joins.joined(&room, &user, start), Some(start));
+    assert!(joins.joined(&room, &other, start).is_none());
+    // Members are forgotten once they no longer count as new
+    assert!(joins
+        .joined(&room, &user, start + Duration::from_secs(60))
+        .is_none());
+}
+
+fn content_rule(kind: PatternKind, pattern: &str, action: Action) -> ContentRule {
+    ContentRule {
+        kind,
+        pattern: pattern.to_owned(),
+        action,
+        reason: String::new(),
+    }
+}
+
+#[test]
+fn content_filters() {
+    let room = RoomId::try_from("!room:example.org").unwrap();
+    let mut protections = Protections::default();
+    protections.settings_mut(None).content = vec![
+        content_rule(PatternKind::Word, "free n1tro", Action::Ban),
+        content_rule(PatternKind::Regex, r"^buy \d+ followers", Action::Kick),
+    ];
+    protections.settings_mut(Some(&room)).content =
+        vec![content_rule(PatternKind::Word, "c++", Action::Warn)];
+    let rules: Vec<ContentRule> = protections.content_rules(&room).cloned().collect();
+    assert_eq!(rules.len(), 3);
+
+    let filter = content_filter(&room, rules.iter()).unwrap();
+    let action = |text| filter.matching(text).map(|rule| rule.action.clone());
+    assert_eq!(action("Get FREE N1TRO now!"), Some(Action::Ban));
+    assert_eq!(action("freen1tro"), None);
+    assert_eq!(action("buy 100 followers"), Some(Action::Kick));
+    assert_eq!(action("don't buy 100 followers"), None);
+    // Words are matched literally rather than as regular expressions
+    assert_eq!(action("I like c++ a lot"), Some(Action::Warn));
+    assert_eq!(action("I like cc a lot"), None);
+
+    // Filters are only compiled again once the rules change
+    assert!(std::sync::Arc::ptr_eq(
+        &filter,
+        &content_filter(&room, protections.content_rules(&room)).unwrap()
+    ));
+    assert!(!std::sync::Arc::ptr_eq(
+        &filter,
+        &content_filter(&room, rules[1..].iter()).unwrap()
+    ));
+    assert!(ContentFilter::new(vec![content_rule(PatternKind::Regex, "(", Action::Ban)]).is_err());
+}
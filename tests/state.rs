@@ -0,0 +1,255 @@
+// Clobber - a matrix moderation bot
+// Copyright (C) 2020 Emelie Graven <em@nao.sh>
+// Licensed under the EUPL
+
+use std::convert::TryFrom;
+
+use clobber::{
+    policy::Action,
+    state::{
+        FloodLimit, JoinLimit, Lockdown, LockdownJoinRule, LockdownMode, Lockdowns, Mutes,
+        PolicyLists, Protections, RoomProtections, RuleAclEntry, RuleBan, RuleEnforcements,
+        RuleKey, Setting,
+    },
+};
+use matrix_sdk::ruma::{events::room::join_rules::JoinRule, RoomId, UserId};
+
+fn key(kind: &str, entity: &str) -> RuleKey {
+    RuleKey {
+        list: RoomId::try_from("!list:example.org").unwrap(),
+        event_type: format!("m.policy.rule.{}", kind),
+        state_key: entity.to_owned(),
+    }
+}
+
+fn ban(rule: &str, room: &str, user: &str) -> RuleBan {
+    RuleBan {
+        rule: key("user", rule),
+        room: RoomId::try_from(room).unwrap(),
+        user: UserId::try_from(user).unwrap(),
+    }
+}
+
+#[test]
+fn rule_enforcements() {
+    let mut enforcements = RuleEnforcements {
+        bans: vec![
+            ban("@spam*:example.org", "!a:example.org", "@spam1:example.org"),
+            ban("@spam*:example.org", "!b:example.org", "@spam1:example.org"),
+            ban("@evil:example.org", "!a:example.org", "@evil:example.org"),
+        ],
+        denied_servers: vec![RuleAclEntry {
+            rule: key("server", "evil.example"),
+            room: RoomId::try_from("!a:example.org").unwrap(),
+            server: "evil.example".to_owned(),
+        }],
+    };
+
+    // Bans made by hand replace the ones applied because of a rule
+    let room = RoomId::try_from("!b:example.org").unwrap();
+    let user = UserId::try_from("@spam1:example.org").unwrap();
+    assert!(enforcements.forget_bans(&room, std::slice::from_ref(&user)));
+    assert!(!enforcements.forget_bans(&room, &[user]));
+
+    // Users and servers are tracked once per room, under the latest rule
+    enforcements.add_bans(vec![ban(
+        "@spam?:example.org",
+        "!a:example.org",
+        "@spam1:example.org",
+    )]);
+    assert_eq!(enforcements.bans.len(), 2);
+    assert_eq!(enforcements.bans[1].rule, key("user", "@spam?:example.org"));
+    enforcements.add_bans(vec![ban(
+        "@spam*:example.org",
+        "!a:example.org",
+        "@spam1:example.org",
+    )]);
+    assert_eq!(enforcements.bans.len(), 2);
+
+    let (bans, servers) = enforcements.take(&[
+        key("user", "@spam*:example.org"),
+        key("server", "evil.example"),
+        // Rules for the same entity of another kind are separate rules
+        key("server", "@evil:example.org"),
+    ]);
+    assert_eq!(
+        bans,
+        [ban(
+            "@spam*:example.org",
+            "!a:example.org",
+            "@spam1:example.org"
+        )]
+    );
+    assert_eq!(servers.len(), 1);
+    assert_eq!(
+        enforcements.bans,
+        [ban(
+            "@evil:example.org",
+            "!a:example.org",
+            "@evil:example.org"
+        )]
+    );
+    assert!(enforcements.denied_servers.is_empty());
+}
+
+#[test]
+fn rule_enforcements_by_list() {
+    let other = RuleKey {
+        list: RoomId::try_from("!other:example.org").unwrap(),
+        ..key("user", "@evil:example.org")
+    };
+    let enforcements = RuleEnforcements {
+        bans: vec![
+            ban("@spam*:example.org", "!a:example.org", "@spam1:example.org"),
+            ban("@spam*:example.org", "!b:example.org", "@spam1:example.org"),
+            RuleBan {
+                rule: other.clone(),
+                ..ban("@evil:example.org", "!a:example.org", "@evil:example.org")
+            },
+        ],
+        denied_servers: vec![RuleAclEntry {
+            rule: key("server", "evil.example"),
+            room: RoomId::try_from("!a:example.org").unwrap(),
+            server: "evil.example".to_owned(),
+        }],
+    };
+
+    // Unwatching a list lifts what its rules caused, each rule listed once
+    let list = RoomId::try_from("!list:example.org").unwrap();
+    assert_eq!(
+        enforcements.rules_from(&list),
+        [
+            key("server", "evil.example"),
+            key("user", "@spam*:example.org")
+        ]
+    );
+    assert_eq!(enforcements.rules_from(&other.list), [other]);
+    let unknown = RoomId::try_from("!unknown:example.org").unwrap();
+    assert!(enforcements.rules_from(&unknown).is_empty());
+}
+
+#[test]
+fn protection_settings() {
+    let limit = FloodLimit {
+        messages: 10,
+        seconds: 5,
+        action: Action::Mute,
+    };
+    let loud = RoomId::try_from("!loud:example.org").unwrap();
+    let quiet = RoomId::try_from("!quiet:example.org").unwrap();
+    let other = RoomId::try_from("!other:example.org").unwrap();
+    let mut protections = Protections::default();
+    assert_eq!(protections.get(&other, |settings| &settings.flood), None);
+
+    protections.settings_mut(None).flood = Some(Setting::On(limit.clone()));
+    protections.settings_mut(Some(&quiet)).flood = Some(Setting::Off);
+    protections.settings_mut(Some(&loud)).flood = Some(Setting::On(FloodLimit {
+        messages: 50,
+        ..limit.clone()
+    }));
+    assert_eq!(
+        protections.get(&other, |settings| &settings.flood),
+        Some(&limit)
+    );
+    assert_eq!(protections.get(&quiet, |settings| &settings.flood), None);
+    assert_eq!(
+        protections
+            .get(&loud, |settings| &settings.flood)
+            .map(|limit| limit.messages),
+        Some(50)
+    );
+
+    // Rooms without settings of their own fall back to the defaults
+    protections.settings_mut(Some(&quiet)).flood = None;
+    assert!(protections.rooms[&quiet].is_empty());
+    assert_eq!(
+        protections.get(&quiet, |settings| &settings.flood),
+        Some(&limit)
+    );
+    assert!(RoomProtections::default().is_empty());
+}
+
+#[test]
+fn lockdowns() {
+    let lockdown = |expires| Lockdown {
+        join_rule: Some(JoinRule::Public),
+        events_default: None,
+        expires,
+    };
+    let mut lockdowns = Lockdowns::default();
+    for (room, expires) in [
+        ("!a:example.org", Some(100)),
+        ("!b:example.org", Some(200)),
+        ("!c:example.org", None),
+    ] {
+        lockdowns
+            .rooms
+            .insert(RoomId::try_from(room).unwrap(), lockdown(expires));
+    }
+    let expired = lockdowns.expired(150);
+    assert_eq!(expired.len(), 1);
+    assert_eq!(expired[0].0.as_str(), "!a:example.org");
+    assert_eq!(expired[0].1.join_rule, Some(JoinRule::Public));
+    // Lockdowns without an expiry last until they are lifted by hand
+    assert_eq!(lockdowns.expired(u64::MAX).len(), 2);
+    assert_eq!(lockdowns.rooms.len(), 3);
+
+    // Lockdowns are only recorded if they changed something, and never replace earlier ones
+    let room = RoomId::try_from("!a:example.org").unwrap();
+    assert!(!lockdowns.add(room.clone(), lockdown(None)));
+    assert_eq!(lockdowns.rooms[&room].expires, Some(100));
+    let other = RoomId::try_from("!d:example.org").unwrap();
+    let unchanged = Lockdown {
+        join_rule: None,
+        ..lockdown(None)
+    };
+    assert!(unchanged.is_empty());
+    assert!(!lockdowns.add(other.clone(), unchanged));
+    assert!(lockdowns.add(other, lockdown(None)));
+    assert_eq!(lockdowns.rooms.len(), 4);
+
+    let limit = JoinLimit {
+        joins: 5,
+        seconds: 10,
+        lockdown: LockdownMode {
+            join_rule: Some(LockdownJoinRule::Knock),
+            restrict_messages: false,
+            cooldown: Some(600),
+        },
+    };
+    let json = serde_json::to_value(&limit).unwrap();
+    assert_eq!(json["join_rule"], "knock");
+    assert_eq!(serde_json::from_value::<JoinLimit>(json).unwrap(), limit);
+    assert_eq!("Invite".parse(), Ok(LockdownJoinRule::Invite));
+    assert!("public".parse::<LockdownJoinRule>().is_err());
+}
+
+#[test]
+fn mutes() {
+    let room = RoomId::try_from("!a:example.org").unwrap();
+    let user = UserId::try_from("@alice:example.org").unwrap();
+    let mut mutes = Mutes::default();
+    assert!(mutes.add(room.clone(), user.clone(), 50));
+    // Quarantining a muted user keeps the level they had before the mute
+    assert!(!mutes.add(room.clone(), user.clone(), -1));
+    assert_eq!(mutes.get(&room, &user), Some(50));
+
+    let other = RoomId::try_from("!b:example.org").unwrap();
+    assert_eq!(mutes.get(&other, &user), None);
+    assert_eq!(mutes.remove(&other, &user), None);
+    assert_eq!(mutes.remove(&room, &user), Some(50));
+    assert_eq!(mutes.remove(&room, &user), None);
+    // Rooms are dropped along with their last muted user
+    assert!(mutes.rooms.is_empty());
+}
+
+#[test]
+fn policy_lists() {
+    // Lists stored before subscriptions were added are read as published lists
+    let lists: PolicyLists =
+        serde_json::from_value(serde_json::json!({ "rooms": ["!list:example.org"] })).unwrap();
+    let list = RoomId::try_from("!list:example.org").unwrap();
+    assert!(lists.published.contains(&list));
+    assert!(lists.subscribed.is_empty());
+    assert_eq!(lists.rooms().collect::<Vec<_>>(), [&list]);
+}
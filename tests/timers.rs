@@ -0,0 +1,51 @@
+// Clobber - a matrix moderation bot
+// Copyright (C) 2020 Emelie Graven <em@nao.sh>
+// Licensed under the EUPL
+
+use std::{convert::TryFrom, time::Duration};
+
+use clobber::timers::{now, Timer, TimerKind, Timers};
+use matrix_sdk::ruma::{RoomId, UserId};
+
+fn timer(kind: TimerKind, user: &str, duration: u64) -> Timer {
+    Timer::new(
+        kind,
+        RoomId::try_from("!room:example.org").unwrap(),
+        UserId::try_from(user).unwrap(),
+        Duration::from_secs(duration),
+    )
+}
+
+#[test]
+fn timers() {
+    let mut timers = Timers::default();
+    timers.add(timer(TimerKind::Unban, "@a:example.org", 60));
+    timers.add(timer(TimerKind::Unmute, "@a:example.org", 3600));
+    timers.add(timer(TimerKind::Unban, "@b:example.org", 7200));
+    // A new timer of the same kind replaces the earlier one
+    timers.add(timer(TimerKind::Unban, "@a:example.org", 120));
+    assert_eq!(timers.timers.len(), 3);
+    let remaining = timers.timers[2].remaining();
+    assert!(remaining <= Duration::from_secs(120) && remaining >= Duration::from_secs(110));
+
+    let room = RoomId::try_from("!room:example.org").unwrap();
+    let user = UserId::try_from("@b:example.org").unwrap();
+    assert!(!timers.remove(TimerKind::Unmute, &room, &user));
+    assert!(timers.remove(TimerKind::Unban, &room, &user));
+
+    // Timers are only removed once lifted, and not if they were replaced in the meantime
+    let expired = timers.timers[1].clone();
+    assert_eq!(expired.kind, TimerKind::Unban);
+    let later = now() + 600;
+    assert!(timers.postpone(&expired, later));
+    assert!(!timers.finish(&expired));
+    let postponed = timers.timers[1].clone();
+    assert_eq!(postponed.expires, later);
+    timers.add(timer(TimerKind::Unban, "@a:example.org", 60));
+    assert!(!timers.finish(&postponed));
+    assert_eq!(timers.timers.len(), 2);
+    let replaced = timers.timers[1].clone();
+    assert!(timers.finish(&replaced));
+    assert_eq!(timers.timers.len(), 1);
+    assert_eq!(timers.timers[0].kind, TimerKind::Unmute);
+}